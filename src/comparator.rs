@@ -0,0 +1,32 @@
+/*! Orderings for the keys of a tree. */
+use std::cmp::Ordering;
+
+/** An ordering for keys.
+
+The ordering decides where keys go on disk, so a tree has to be reopened with the same comparator it was built with.
+Closures of the form `Fn(&K, &K) -> Ordering` are comparators.
+*/
+pub trait Comparator<K> {
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+
+    /// Binary search sorted keys, like `slice::binary_search`.
+    fn search(&self, keys: &[K], key: &K) -> Result<usize, usize> {
+        keys.binary_search_by(|k| self.compare(k, key))
+    }
+}
+
+/// The ordering given by `Ord`, which is what trees use unless told otherwise.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NaturalOrder;
+
+impl<K: Ord> Comparator<K> for NaturalOrder {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl<K, F: Fn(&K, &K) -> Ordering> Comparator<K> for F {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        self(a, b)
+    }
+}
@@ -0,0 +1,144 @@
+/*! Errors shared by the backends and the tree. */
+use std::error;
+use std::fmt;
+use std::io;
+use bincode;
+
+#[derive(Debug)]
+pub enum EncodingError {
+    Unknown(String),
+    IoError(io::Error),
+}
+
+#[derive(Debug)]
+pub enum DecodingError {
+    Corrupt(String),
+    IoError(io::Error),
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodingError::Unknown(ref msg) => write!(f, "Unable to encode: {}", msg),
+            EncodingError::IoError(ref e) => write!(f, "I/O error while encoding: {}", e),
+        }
+    }
+}
+
+impl error::Error for EncodingError {
+    fn source(&self) -> Option<&(dyn error::Error+'static)> {
+        match *self {
+            EncodingError::IoError(ref e) => Some(e),
+            EncodingError::Unknown(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for DecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodingError::Corrupt(ref msg) => write!(f, "Corrupt data: {}", msg),
+            DecodingError::IoError(ref e) => write!(f, "I/O error while decoding: {}", e),
+        }
+    }
+}
+
+impl error::Error for DecodingError {
+    fn source(&self) -> Option<&(dyn error::Error+'static)> {
+        match *self {
+            DecodingError::IoError(ref e) => Some(e),
+            DecodingError::Corrupt(_) => None,
+        }
+    }
+}
+
+/// Any of the crate's errors, for operations which both read and write, or which take sorted input.
+#[derive(Debug)]
+pub enum Error {
+    Encoding(EncodingError),
+    Decoding(DecodingError),
+    Unsorted(UnsortedError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Encoding(ref e) => e.fmt(f),
+            Error::Decoding(ref e) => e.fmt(f),
+            Error::Unsorted(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error+'static)> {
+        match *self {
+            Error::Encoding(ref e) => e.source(),
+            Error::Decoding(ref e) => e.source(),
+            Error::Unsorted(ref e) => e.source(),
+        }
+    }
+}
+
+/// Input to `BPTree::build_sorted` or a `BPTreeBuilder` which wasn't in strictly ascending order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsortedError {
+    /// The position of the first key which wasn't greater than the one before it.
+    pub index: u64,
+}
+
+impl fmt::Display for UnsortedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key {} isn't greater than the one before it", self.index)
+    }
+}
+
+impl error::Error for UnsortedError {}
+
+impl From<EncodingError> for Error {
+    fn from(e: EncodingError) -> Error {
+        Error::Encoding(e)
+    }
+}
+
+impl From<DecodingError> for Error {
+    fn from(e: DecodingError) -> Error {
+        Error::Decoding(e)
+    }
+}
+
+impl From<UnsortedError> for Error {
+    fn from(e: UnsortedError) -> Error {
+        Error::Unsorted(e)
+    }
+}
+
+impl From<io::Error> for EncodingError {
+    fn from(e: io::Error) -> EncodingError {
+        EncodingError::IoError(e)
+    }
+}
+
+impl From<io::Error> for DecodingError {
+    fn from(e: io::Error) -> DecodingError {
+        DecodingError::IoError(e)
+    }
+}
+
+impl From<Box<bincode::ErrorKind>> for EncodingError {
+    fn from(e: Box<bincode::ErrorKind>) -> EncodingError {
+        match *e {
+            bincode::ErrorKind::IoError(y) => EncodingError::IoError(y),
+            _ => EncodingError::Unknown(e.to_string()),
+        }
+    }
+}
+
+impl From<Box<bincode::ErrorKind>> for DecodingError {
+    fn from(e: Box<bincode::ErrorKind>) -> DecodingError {
+        match *e {
+            bincode::ErrorKind::IoError(y) => DecodingError::IoError(y),
+            _ => DecodingError::Corrupt(e.to_string()),
+        }
+    }
+}
@@ -1,12 +1,35 @@
-#![allow(dead_code)]
+/*! A B+ tree which lives in a `StorageBackend`.
+
+Trees only ever load and store their nodes through the `StorageBackend` trait, which addresses records by `u64` keys rather than byte offsets, so there's one path for loading nodes whatever the storage is.
+Nothing in the crate reads from a `Read+Seek` directly.
+`FileBackend` is how a tree goes in a file, with the byte offsets of records as their keys, and `CachedBackend`, `CompressedBackend`, and `ChecksumBackend` wrap any other backend.
+Storage which already hands out numbered records only needs a `StorageBackend` implementation of its own.
+*/
+#![forbid(unsafe_code)]
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate bincode;
 extern crate take_mut;
+extern crate crc32fast;
+#[cfg(feature = "deflate")]
+extern crate flate2;
+#[cfg(test)]
+extern crate rand;
 
 
+pub mod comparator;
+pub mod error;
 pub mod offset_tree;
+pub mod persistent_map;
 pub mod storage_backend;
-pub use storage_backend::StorageBackend;
+pub use error::{EncodingError, DecodingError, Error, UnsortedError};
+pub use storage_backend::{StorageBackend, FileBackend, MemoryBackend, CachedBackend, CompressedBackend, Codec, ChecksumBackend};
+#[cfg(feature = "deflate")]
+pub use storage_backend::Deflate;
+#[cfg(feature = "async")]
+pub use storage_backend::{AsyncStorageBackend, LoadFuture};
+pub use persistent_map::PersistentMap;
+pub use offset_tree::{BPTree, OffsetTree, MultiOffsetTree, BPTreeBuilder, OffsetTreeBuilder, SplitPolicy, TreeStats, Metrics, Header, NodeType, NodeView};
+pub use comparator::{Comparator, NaturalOrder};
 
@@ -1,289 +1,3811 @@
 use std;
 use serde;
-use std::cell;
+use std::sync;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io;
+use std::ops::{Bound, RangeBounds};
 use storage_backend::StorageBackend;
+use error::{EncodingError, DecodingError, Error, UnsortedError};
+use comparator::{Comparator, NaturalOrder};
 
+mod builder;
+pub use self::builder::{BPTreeBuilder, OffsetTreeBuilder};
+#[cfg(feature = "async")]
+mod async_tree;
+#[cfg(feature = "async")]
+pub use self::async_tree::{GetAsync, InsertAsync};
+
+/// What a node is, as `BPTree::for_each_node` reports it.
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-enum NodeType {
+pub enum NodeType {
+    /// The root, when the tree is more than a single leaf.
     Root,
     Internal,
     Leaf
 }
 
-enum NodeRefInternal<K> {
-    Unloaded(u64),
-    Loaded(Box<Node<K>>),
+// A child, which is loaded from the backend the first time it's needed.
+//
+// Loading only ever goes from unloaded to loaded, which is what lets `get` load through a shared reference, from any number of threads at once.
+// Once the cell is filled, `offset` is meaningless: loaded nodes know where they're stored themselves.
+//
+// Loaded nodes can be shared with forks of the tree, and are copied on write: whichever tree changes a shared node first gets a copy of its own.
+#[derive(Clone)]
+struct NodeRef<K, V> {
+    offset: u64,
+    node: sync::OnceLock<sync::Arc<Node<K, V>>>,
+    // The number of keys under this node, which parents store for their children so that counting doesn't have to load them.
+    // Anything which changes a loaded node's keys has to update the count in its parent's reference to it.
+    count: u64,
 }
 
-struct NodeRef<K>(cell::UnsafeCell<NodeRefInternal<K>>);
-
 // An on-disk representation, for space saving.
+//
+// Leaves deliberately don't link to their siblings.
+// Nodes are never rewritten in place, so giving a leaf's successor a new offset would mean writing a new copy of the leaf, then of its predecessor, and so on down the chain.
+// It would also stop two roots from ever sharing a leaf.
+// The iterators keep a stack of the path from the root instead, which is amortized O(1) per step.
+//
+// Nodes are also always decoded whole, even when a lookup only compares against a few of the keys.
+// Skipping the rest would need the backends to hand out byte ranges of a record rather than decoded records, and the keys would have to be stored with their lengths so there'd be something to skip by.
+// Every loaded node is kept, too, so a lazily decoded one would only put off decoding the rest until something else needed it.
+// Trees with a few huge keys are better off storing the keys elsewhere and keeping a small key in the tree which refers to them.
 #[derive(Serialize, Deserialize)]
-struct DiskNode<K> {
+struct DiskNode<K, V> {
+    // Always FORMAT_VERSION when written by this crate.
+    version: u8,
     node_type: NodeType,
     keys: Vec<K>,
+    // Internal nodes have children and leaves have values; the other is always empty.
     children: Vec<u64>,
+    values: Vec<V>,
+    // A leaf's keys, if they're front-coded, in which case `keys` is empty.
+    front_coded: Vec<FrontCodedKey>,
+    // The number of keys under each child.
+    counts: Vec<u64>,
+}
+
+/// The version of the on-disk format, which is written first in every node and header.
+///
+/// Anything with another version is rejected as `DecodingError::Corrupt` rather than misread.
+pub const FORMAT_VERSION: u8 = 1;
+
+fn check_version(version: u8) -> Result<(), DecodingError> {
+    if version == FORMAT_VERSION {
+        Ok(())
+    } else {
+        Err(DecodingError::Corrupt(format!("unsupported version {}", version)))
+    }
+}
+
+// A key which serializes to a byte string, stored as the length of the prefix it shares with the key before it and the rest of its bytes.
+// Leaves have sorted keys, so long keys usually share a lot.
+type FrontCodedKey = (u16, Vec<u8>);
+
+// Bincode writes byte strings as their length followed by their bytes.
+const LENGTH_PREFIX: usize = 8;
+
+fn is_byte_string(bytes: &[u8]) -> bool {
+    bytes.len() >= LENGTH_PREFIX && bytes[..LENGTH_PREFIX] == ((bytes.len()-LENGTH_PREFIX) as u64).to_le_bytes()
+}
+
+// Front-code the keys if they all serialize to byte strings and that makes them smaller.
+//
+// Only the encoding is looked at, so this works for any key type: strings, byte vectors, and newtypes of either.
+fn front_code<K: serde::Serialize>(keys: &[K]) -> Result<Option<Vec<FrontCodedKey>>, EncodingError> {
+    let mut coded = Vec::with_capacity(keys.len());
+    let mut previous: Vec<u8> = vec![];
+    let (mut plain_size, mut coded_size) = (0, 0);
+    for k in keys {
+        let bytes = bincode::serialize(k, bincode::Infinite).map_err(EncodingError::from)?;
+        if !is_byte_string(&bytes) {
+            return Ok(None);
+        }
+        let string = &bytes[LENGTH_PREFIX..];
+        let shared = previous.iter().zip(string.iter()).take_while(|&(a, b)| a == b).count();
+        let shared = std::cmp::min(shared, u16::MAX as usize);
+        let suffix = string[shared..].to_vec();
+        plain_size += bytes.len();
+        coded_size += 2+LENGTH_PREFIX+suffix.len();
+        coded.push((shared as u16, suffix));
+        previous = string.to_vec();
+    }
+    Ok(if coded_size < plain_size { Some(coded) } else { None })
+}
+
+fn front_decode<K: serde::de::DeserializeOwned>(coded: Vec<FrontCodedKey>) -> Result<Vec<K>, DecodingError> {
+    let mut previous: Vec<u8> = vec![];
+    coded.into_iter().map(|(shared, suffix)| {
+        let shared = shared as usize;
+        if shared > previous.len() {
+            return Err(DecodingError::Corrupt(format!("Front-coded key shares {} bytes with a key of {}", shared, previous.len())));
+        }
+        previous.truncate(shared);
+        previous.extend_from_slice(&suffix);
+        let mut bytes = (previous.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&previous);
+        bincode::deserialize(&bytes).map_err(DecodingError::from)
+    }).collect()
 }
 
-struct Node<K> {
+// A split produces the dividing key and the new upper sibling.
+type Split<K, V> = (K, Box<Node<K, V>>);
+
+// An insertion produces the value which was previously at the key, if any, and possibly a split.
+type Inserted<K, V> = (Option<V>, Option<Split<K, V>>);
+
+#[derive(Clone)]
+struct Node<K, V> {
     node_type: NodeType,
     keys: Vec<K>,
-    children: Vec<NodeRef<K>>,
+    // Only internal nodes have children, and only leaves have values.
+    children: Vec<NodeRef<K, V>>,
+    values: Vec<V>,
+    // Set if this node or anything under it changed since it was last stored.
+    // Anything which changes a node must also mark every ancestor, which is what lets commit skip clean subtrees without looking at them.
     modified: bool,
+    // Where this node was last stored, if anywhere.
+    stored_at: Option<u64>,
+    // Offsets of nodes which were merged into this one or otherwise replaced by it.
+    // They're garbage once this node is next committed.
+    superseded: Vec<u64>,
 }
 
-impl<K: serde::de::DeserializeOwned> From<DiskNode<K>> for Node<K> {
-    fn from(obj: DiskNode<K>) -> Node<K> {
-        Node {
-            node_type: obj.node_type,
-            keys: obj.keys,
-            children: obj.children.into_iter().map(|x| (NodeRef::from_offset(x))).collect(),
+impl<K: serde::de::DeserializeOwned, V: serde::de::DeserializeOwned> DiskNode<K, V> {
+    fn load<B: StorageBackend>(backend: &Metered<'_, B>, offset: u64) -> Result<DiskNode<K, V>, DecodingError> {
+        backend.backend.load(offset)
+    }
+
+    fn load_uncached<B: StorageBackend>(backend: &Metered<'_, B>, offset: u64) -> Result<DiskNode<K, V>, DecodingError> {
+        backend.backend.load_uncached(offset)
+    }
+
+    // Anything shaped wrongly is rejected here, since the rest of the tree indexes nodes assuming it isn't.
+    fn into_node(self) -> Result<Node<K, V>, DecodingError> {
+        check_version(self.version)?;
+        let keys = if self.front_coded.is_empty() { self.keys } else { front_decode(self.front_coded)? };
+        let well_formed = match self.node_type {
+            NodeType::Leaf => self.children.is_empty() && keys.len() == self.values.len(),
+            NodeType::Root | NodeType::Internal => self.values.is_empty() && self.children.len() == keys.len()+1 && self.counts.len() == self.children.len(),
+        };
+        if !well_formed {
+            return Err(DecodingError::Corrupt(format!("Malformed {:?} node with {} keys, {} children, and {} values", self.node_type, keys.len(), self.children.len(), self.values.len())));
+        }
+        Ok(Node {
+            node_type: self.node_type,
+            keys,
+            children: self.children.into_iter().zip(self.counts).map(|(o, c)| NodeRef::from_offset(o, c)).collect(),
+            values: self.values,
             modified: false,
+            stored_at: None,
+            superseded: vec![],
+        })
+    }
+}
+
+// The same as DiskNode, but borrowing the keys and values so that writing doesn't need to clone them.
+#[derive(Serialize)]
+struct DiskNodeRef<'a, K: 'a, V: 'a> {
+    version: u8,
+    node_type: NodeType,
+    keys: &'a [K],
+    children: Vec<u64>,
+    values: &'a [V],
+    front_coded: Vec<FrontCodedKey>,
+    counts: Vec<u64>,
+}
+
+// The backend as a tree's internals see it, along with the tree's metrics, if it has any, for them to count in.
+struct Metered<'a, B: 'a> {
+    backend: &'a B,
+    metrics: Option<sync::Arc<Metrics>>,
+}
+
+impl<'a, B: StorageBackend> Metered<'a, B> {
+    fn new(backend: &'a B, metrics: Option<sync::Arc<Metrics>>) -> Metered<'a, B> {
+        Metered { backend, metrics }
+    }
+
+    fn count_loaded(&self) {
+        if let Some(ref m) = self.metrics {
+            count(&m.loaded);
+        }
+    }
+
+    fn count_resident(&self) {
+        if let Some(ref m) = self.metrics {
+            count(&m.resident);
         }
     }
 }
 
-impl<K: serde::de::DeserializeOwned> DiskNode<K> {
-    fn load<B: StorageBackend>(backend: &B, offset: u64) -> Result<DiskNode<K>, B::DecodingError> {
-        backend.load(offset)        
+fn not_loaded(offset: u64) -> DecodingError {
+    DecodingError::Corrupt(format!("The node at {} didn't load", offset))
+}
+
+fn load<K: serde::de::DeserializeOwned, V: serde::de::DeserializeOwned, B: StorageBackend>(backend: &Metered<'_, B>, offset: u64) -> Result<Node<K, V>, DecodingError> {
+    let node = from_disk(DiskNode::load(backend, offset)?, offset)?;
+    backend.count_loaded();
+    Ok(node)
+}
+
+fn from_disk<K: serde::de::DeserializeOwned, V: serde::de::DeserializeOwned>(disk_node: DiskNode<K, V>, offset: u64) -> Result<Node<K, V>, DecodingError> {
+    let mut node = disk_node.into_node()?;
+    node.stored_at = Some(offset);
+    Ok(node)
+}
+
+impl<K, V> NodeRef<K, V> {
+    fn from_boxed_node(node: Box<Node<K, V>>) -> NodeRef<K, V> {
+        NodeRef { offset: 0, count: node.total(), node: sync::OnceLock::from(sync::Arc::from(node)) }
     }
 }
 
-fn load<K: serde::de::DeserializeOwned, B: StorageBackend>(backend: &B, offset: u64) -> Result<Node<K>, B::DecodingError> {
-    Ok(DiskNode::<K>::load(backend, offset)?.into())
+// A node of our own to change, copied if it's shared.
+fn unshare<K: Clone, V: Clone>(node: sync::Arc<Node<K, V>>) -> Box<Node<K, V>> {
+    Box::new(sync::Arc::unwrap_or_clone(node))
 }
 
-impl<K: serde::de::DeserializeOwned> NodeRef<K> {
-    fn from_offset(offset: u64) -> NodeRef<K> {
-        NodeRef(cell::UnsafeCell::new(NodeRefInternal::Unloaded(offset)))
+impl<K: serde::de::DeserializeOwned, V: serde::de::DeserializeOwned> NodeRef<K, V> {
+    fn from_offset(offset: u64, count: u64) -> NodeRef<K, V> {
+        NodeRef { offset, node: sync::OnceLock::new(), count }
     }
 
-    fn from_boxed_node(node: Box<Node<K>>) -> NodeRef<K> {
-        NodeRef(cell::UnsafeCell::new(NodeRefInternal::Loaded(node)))
+    fn load<B: StorageBackend>(&self, backend: &Metered<'_, B>) -> Result<(), DecodingError> {
+        self.get(backend).map(|_| ())
     }
 
-    fn load<B: StorageBackend>(&self, backend: &B) -> Result<(), B::DecodingError> {
-        let internal = self.0.get();
-        unsafe {
-            if let &NodeRefInternal::Unloaded(offset) = &*internal {
-                *internal = NodeRefInternal::Loaded(Box::new(load(backend, offset)?));
-            }
+    fn get<B: StorageBackend>(&self, backend: &Metered<'_, B>) -> Result<&Node<K, V>, DecodingError> {
+        if let Some(n) = self.node.get() {
+            backend.count_resident();
+            return Ok(n);
         }
-        Ok(())
+        let n = load(backend, self.offset)?;
+        Ok(self.node.get_or_init(|| sync::Arc::new(n)))
+    }
+
+    /// Get the node if it's already in memory, without going to the backend.
+    fn loaded(&self) -> Option<&Node<K, V>> {
+        self.node.get().map(|n| &**n)
     }
+}
 
-    fn get<B: StorageBackend>(&self, backend: &B) -> Result<&Node<K>, B::DecodingError> {
+impl<K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone> NodeRef<K, V> {
+    fn get_mut<B: StorageBackend>(&mut self, backend: &Metered<'_, B>) -> Result<&mut Node<K, V>, DecodingError> {
         self.load(backend)?;
-        unsafe {
-            Ok(match *self.0.get() {
-                NodeRefInternal::Loaded(ref n) => n,
-                _ => panic!("Nodes should be loaded."),
-            })
+        let offset = self.offset;
+        self.node.get_mut().map(sync::Arc::make_mut).ok_or_else(|| not_loaded(offset))
+    }
+
+    /// Write this node and all of its modified descendants, returning the offset of this node.
+    ///
+    /// Children are written before their parents, so that the parents know where to point.
+    /// The previous locations of rewritten nodes go to the free list, if there is one.
+    fn commit<B: StorageBackend>(&mut self, backend: &mut B, mut free_list: Option<&mut FreeList>, metrics: Option<&Metrics>) -> Result<u64, EncodingError> where K: serde::Serialize, V: serde::Serialize {
+        match self.node.get_mut() {
+            None => Ok(self.offset),
+            Some(node) => {
+                if let (false, Some(o)) = (node.modified, node.stored_at) {
+                    return Ok(o);
+                }
+                // A fork sharing the node keeps its copy as it was, still modified, so committing the fork would write it again.
+                let node = sync::Arc::make_mut(node);
+                let children = node.children.iter_mut().map(|c| c.commit(backend, free_list.as_deref_mut(), metrics)).collect::<Result<Vec<_>, _>>()?;
+                let disk_ref = node.disk_ref(children)?;
+                let o = match free_list {
+                    Some(free_list) => {
+                        let o = free_list.store(backend, &disk_ref)?;
+                        free_list.pending.extend(node.stored_at);
+                        free_list.pending.append(&mut node.superseded);
+                        o
+                    },
+                    // Without a free list, superseded offsets are simply forgotten.
+                    None => {
+                        let o = backend.store(&disk_ref)?;
+                        node.superseded.clear();
+                        o
+                    },
+                };
+                if let Some(m) = metrics {
+                    count(&m.written);
+                }
+                node.stored_at = Some(o);
+                node.modified = false;
+                Ok(o)
+            }
         }
     }
 
-    fn get_mut<B: StorageBackend>(&mut self, backend: &B) -> Result<&mut Node<K>, B::DecodingError> {
+    fn into_box<B: StorageBackend>(self, backend: &Metered<'_, B>) -> Result<Box<Node<K, V>>, DecodingError> {
         self.load(backend)?;
-        unsafe {
-            Ok(match *self.0.get() {
-                NodeRefInternal::Loaded(ref mut n) => {
-                    n.modified = true;
-                    n
-                },
-                _ => panic!("Node not loaded."),
-            })
+        let offset = self.offset;
+        self.node.into_inner().map(unshare).ok_or_else(|| not_loaded(offset))
+    }
+}
+
+impl<K, V> Node<K, V> {
+    // A node which has never been stored.
+    fn new(node_type: NodeType, keys: Vec<K>, children: Vec<NodeRef<K, V>>, values: Vec<V>) -> Node<K, V> {
+        Node {
+            node_type,
+            keys,
+            children,
+            values,
+            modified: true,
+            stored_at: None,
+            superseded: vec![],
         }
     }
 
-    fn offset_or_panic(&self, msg: &'static str) -> u64 {
-        let internal = self.0.get();
-        unsafe {
-            match *internal {
-                NodeRefInternal::Unloaded(offset) => offset,
-                _ => panic!(msg),
-            }
+    // The number of children, counting the values of a leaf as its children.
+    fn width(&self) -> usize {
+        if self.node_type == NodeType::Leaf { self.values.len() } else { self.children.len() }
+    }
+
+    // The number of keys under this node.
+    fn total(&self) -> u64 {
+        if self.node_type == NodeType::Leaf { self.keys.len() as u64 } else { self.children.iter().map(|c| c.count).sum() }
+    }
+
+    // Bring the count for a loaded child up to date.
+    fn recount(&mut self, index: usize) {
+        if let Some(total) = self.children[index].node.get().map(|n| n.total()) {
+            self.children[index].count = total;
         }
     }
 
-    fn into_box<B: StorageBackend>(self, backend: &B) -> Result<Box<Node<K>>, B::DecodingError> {
-        self.load(backend)?;
-        let ret = unsafe {
-            match std::ptr::read(self.0.get()) {
-                NodeRefInternal::Loaded(n) => Ok(n),
-                _ => panic!("Somehow, this is an unloaded node."),
-            }
-        };
-        std::mem::forget(self);
-        ret
+    // Whether splitting leaves both halves with at least 2 children.
+    // Internal nodes need one more, because a key moves up to the parent.
+    fn splittable(&self) -> bool {
+        self.width() >= if self.node_type == NodeType::Leaf { 4 } else { 5 }
+    }
+
+    // The record for this node, given where its children are.
+    fn disk_ref(&self, children: Vec<u64>) -> Result<DiskNodeRef<'_, K, V>, EncodingError> where K: serde::Serialize {
+        let front_coded = if self.node_type == NodeType::Leaf { front_code(&self.keys)? } else { None };
+        let counts = self.children.iter().map(|c| c.count).collect();
+        Ok(match front_coded {
+            Some(front_coded) => DiskNodeRef { version: FORMAT_VERSION, node_type: self.node_type, keys: &[], children, values: &self.values, front_coded, counts },
+            None => DiskNodeRef { version: FORMAT_VERSION, node_type: self.node_type, keys: &self.keys, children, values: &self.values, front_coded: vec![], counts },
+        })
+    }
+
+    // The size of this node's record, were it stored now.
+    // Keys which can't be encoded will fail when the node is actually stored, so any size will do for them.
+    fn stored_size(&self) -> u64 where K: serde::Serialize, V: serde::Serialize {
+        self.disk_ref(vec![0; self.children.len()]).map(|r| bincode::serialized_size(&r)).unwrap_or(0)
     }
 }
 
-impl<K: serde::de::DeserializeOwned+Eq+Ord+Clone> Node<K> {
-    fn find_offset_for<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Option<u64>, B::DecodingError> {
+impl<K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone> Node<K, V> {
+    fn find<B: StorageBackend, C: Comparator<K>>(&self, backend: &Metered<'_, B>, cmp: &C, key: &K) -> Result<Option<V>, DecodingError> {
         if self.node_type == NodeType::Leaf {
-            match self.keys.binary_search(key) {
-                Ok(ind) => Ok(Some(self.children[ind].offset_or_panic("This is a leaf, but somehow has a loaded child."))),
+            match cmp.search(&self.keys, key) {
+                Ok(ind) => Ok(Some(self.values[ind].clone())),
                 Err(_) => Ok(None),
             }
         }
         else {
-            self.children[self.index_of(key)].get(backend)?.find_offset_for(backend, key)
+            self.children[self.index_of(cmp, key)].get(backend)?.find(backend, cmp, key)
+        }
+    }
+
+    // The key/value pair at the given index of a leaf.
+    fn leaf_entry(&self, index: usize) -> (K, V) {
+        (self.keys[index].clone(), self.values[index].clone())
+    }
+
+    fn first_entry<B: StorageBackend>(&self, backend: &Metered<'_, B>) -> Result<Option<(K, V)>, DecodingError> {
+        let mut node = self;
+        while node.node_type != NodeType::Leaf {
+            node = node.children[0].get(backend)?;
+        }
+        Ok(if node.keys.is_empty() { None } else { Some(node.leaf_entry(0)) })
+    }
+
+    fn last_entry<B: StorageBackend>(&self, backend: &Metered<'_, B>) -> Result<Option<(K, V)>, DecodingError> {
+        let mut node = self;
+        while node.node_type != NodeType::Leaf {
+            node = node.children.last().unwrap().get(backend)?;
+        }
+        Ok(if node.keys.is_empty() { None } else { Some(node.leaf_entry(node.keys.len()-1)) })
+    }
+
+    fn ceiling<B: StorageBackend, C: Comparator<K>>(&self, backend: &Metered<'_, B>, cmp: &C, key: &K) -> Result<Option<(K, V)>, DecodingError> {
+        if self.node_type == NodeType::Leaf {
+            let index = match cmp.search(&self.keys, key) {
+                Ok(i) | Err(i) => i,
+            };
+            return Ok(if index < self.keys.len() { Some(self.leaf_entry(index)) } else { None });
+        }
+        let index = self.index_of(cmp, key);
+        let found = self.children[index].get(backend)?.ceiling(backend, cmp, key)?;
+        // Separators can outlive the keys they were copied from, so the child we descend into might not actually contain anything big enough.
+        // If so, it's the first key of the next child.
+        match found {
+            None if index+1 < self.children.len() => self.children[index+1].get(backend)?.first_entry(backend),
+            _ => Ok(found),
+        }
+    }
+
+    // The smallest key which is within `lower`, without cloning it.
+    fn first_from<B: StorageBackend, C: Comparator<K>>(&self, backend: &Metered<'_, B>, cmp: &C, lower: Bound<&K>) -> Result<Option<&K>, DecodingError> {
+        if self.node_type == NodeType::Leaf {
+            return Ok(self.keys.get(leaf_start(cmp, &self.keys, lower)));
+        }
+        let index = match lower {
+            Bound::Unbounded => 0,
+            Bound::Included(k) | Bound::Excluded(k) => self.index_of(cmp, k),
+        };
+        // As for `ceiling`, the child we descend into might have nothing in range, and then it's the first key of the next child.
+        match self.children[index].get(backend)?.first_from(backend, cmp, lower)? {
+            None if index+1 < self.children.len() => self.children[index+1].get(backend)?.first_from(backend, cmp, Bound::Unbounded),
+            found => Ok(found),
         }
     }
 
-    fn index_of(&self, key: &K) -> usize{
+    fn floor<B: StorageBackend, C: Comparator<K>>(&self, backend: &Metered<'_, B>, cmp: &C, key: &K) -> Result<Option<(K, V)>, DecodingError> {
+        if self.node_type == NodeType::Leaf {
+            return Ok(match cmp.search(&self.keys, key) {
+                Ok(i) => Some(self.leaf_entry(i)),
+                Err(0) => None,
+                Err(i) => Some(self.leaf_entry(i-1)),
+            });
+        }
+        let index = self.index_of(cmp, key);
+        let found = self.children[index].get(backend)?.floor(backend, cmp, key)?;
+        // If everything in the child is bigger than the key, the answer is the maximum of the previous child.
+        match found {
+            None if index > 0 => self.children[index-1].get(backend)?.last_entry(backend),
+            _ => Ok(found),
+        }
+    }
+
+    fn index_of<C: Comparator<K>>(&self, cmp: &C, key: &K) -> usize{
         assert!(self.node_type != NodeType::Leaf);
-        let ind = self.keys.binary_search(key);
+        let ind = cmp.search(&self.keys, key);
         match ind {
             Ok(index) | Err(index) => index,
         }
     }
 
     /// Modify this node in place to split in half, returning the upper half and the dividing key.
-    fn split_in_place(&mut self) -> (K, Box<Node<K>>) {
+    fn split_in_place<C: Comparator<K>>(&mut self, cmp: &C) -> Split<K, V> {
+        self.modified = true;
         // Doing this based off keys is important.
         let half = self.keys.len()/2;
         let upper_keys = self.keys.drain(half..).collect::<Vec<_>>();
-        let upper_children = self.children.drain(half..).collect::<Vec<_>>();
-        assert!(self.children.len() > 1);
         assert!(self.keys.len() > 1);
-        assert!(upper_children.len() > 1);
         assert!(upper_keys.len() > 1);
         let ret_key;
         let ret_node;
         match self.node_type {
             NodeType::Leaf => {
+                let upper_values = self.values.drain(half..).collect::<Vec<_>>();
                 ret_key = self.keys.last().unwrap().clone();
-                ret_node = Node {
-                    keys: upper_keys,
-                    children: upper_children,
-                    node_type: NodeType::Leaf,
-                    modified: true,
-                };
+                ret_node = Node::new(NodeType::Leaf, upper_keys, vec![], upper_values);
             }
             NodeType::Root | NodeType::Internal => {
+                let upper_children = self.children.drain(half..).collect::<Vec<_>>();
                 // A split of the root makes us an internal, and something else will construct the new root.
                 self.node_type = NodeType::Internal;
                 // We have one extra key in ourself right now. This is greater than any value beneath us.
                 // In this implementation we go left for <=.
                 ret_key = self.keys.pop().unwrap();
-                ret_node = Node {
-                    node_type: NodeType::Internal,
-                    keys: upper_keys,
-                    children: upper_children,
-                    modified: true,
-                }
+                ret_node = Node::new(NodeType::Internal, upper_keys, upper_children, vec![]);
             }
         }
+        assert!(self.width() > 1);
+        assert!(ret_node.width() > 1);
         let d = if self.node_type == NodeType::Leaf { 0 } else { 1 };
-        assert_eq!(self.keys.len()+d, self.children.len());
-        assert_eq!(ret_node.keys.len()+d, ret_node.children.len());
-        assert!(self.keys.last().unwrap() <= &ret_key);
-        assert!(&ret_key < ret_node.keys.first().unwrap());
+        assert_eq!(self.keys.len()+d, self.width());
+        assert_eq!(ret_node.keys.len()+d, ret_node.width());
+        assert!(cmp.compare(self.keys.last().unwrap(), &ret_key) != Ordering::Greater);
+        assert!(cmp.compare(&ret_key, ret_node.keys.first().unwrap()) == Ordering::Less);
         (ret_key, Box::new(ret_node))
     }
 
-    fn insert_nonroot<B: StorageBackend>(&mut self, backend: &B, key: &K, value: u64, split_threshold: usize)
-        -> Result<Option<(K, Box<Node<K>>)>, B::DecodingError>
+    /// Absorb the sibling immediately to our right, which was separated from us by the given key in our parent.
+    ///
+    /// The parent is responsible for dropping the separator and the right sibling's slot.
+    fn merge_with<C: Comparator<K>>(&mut self, cmp: &C, mut right: Node<K, V>, separator: K) {
+        assert_eq!(self.node_type, right.node_type);
+        assert!(self.keys.last().map(|k| cmp.compare(k, &separator) != Ordering::Greater).unwrap_or(true));
+        assert!(right.keys.first().map(|k| cmp.compare(&separator, k) == Ordering::Less).unwrap_or(true));
+        if self.node_type != NodeType::Leaf {
+            // Internal nodes have one less key than children, so the separator comes down between the halves.
+            // Leaves already contain the separator as their maximum.
+            self.keys.push(separator);
+        }
+        self.keys.append(&mut right.keys);
+        self.children.append(&mut right.children);
+        self.values.append(&mut right.values);
+        self.superseded.extend(right.stored_at);
+        self.superseded.append(&mut right.superseded);
+        self.modified = true;
+    }
+
+    // `value` gets the key's current value, if any, and returns a replacement or `None` to leave it alone.
+    fn insert_into_leaf<C: Comparator<K>, F: FnOnce(Option<&V>) -> Option<V>>(&mut self, cmp: &C, key: &K, value: F) -> Option<V> {
+        assert_eq!(self.node_type, NodeType::Leaf);
+        match cmp.search(&self.keys, key) {
+            Ok(ind) => Some(match value(Some(&self.values[ind])) {
+                Some(new) => {
+                    self.modified = true;
+                    std::mem::replace(&mut self.values[ind], new)
+                },
+                None => self.values[ind].clone(),
+            }),
+            Err(ind) => {
+                let new = value(None).expect("There has to be a value for a new key.");
+                self.keys.insert(ind, key.clone());
+                self.values.insert(ind, new);
+                self.modified = true;
+                None
+            }
+        }
+    }
+
+    /// Insert into the subtree rooted at this node, returning the previous value and the upper half of this node if it split.
+    ///
+    /// If the root splits, its type becomes internal, and the caller makes the new root.
+    /// This doesn't recurse: nodes on the way down are detached from their parents onto a stack, then reattached on the way back up, absorbing splits as they go.
+    fn insert<B: StorageBackend, C: Comparator<K>, F: FnOnce(Option<&V>) -> Option<V>>(&mut self, backend: &Metered<'_, B>, cmp: &C, key: &K, value: F, policy: &SplitPolicy) -> Result<Inserted<K, V>, DecodingError>
+        where K: serde::Serialize, V: serde::Serialize
+    {
+        // Each detached node, with its index in its parent.
+        let mut path: Vec<(usize, Box<Node<K, V>>)> = vec![];
+        let mut descended = Ok(());
+        loop {
+            let current = deepest(self, &mut path);
+            if current.node_type == NodeType::Leaf {
+                break;
+            }
+            let target = current.index_of(cmp, key);
+            // A failed load has to stop the descent without returning, so that everything detached so far goes back.
+            if let Err(e) = current.children[target].load(backend) {
+                descended = Err(e);
+                break;
+            }
+            let child = current.children[target].node.take().map(unshare).expect("The child was just loaded.");
+            path.push((target, child));
+        }
+        let mut previous = None;
+        let mut split = None;
+        if descended.is_ok() {
+            let leaf = deepest(self, &mut path);
+            previous = leaf.insert_into_leaf(cmp, key, value);
+            if policy.overfull(leaf) {
+                split = Some(leaf.split_in_place(cmp));
+            }
+        }
+        while let Some((index, child)) = path.pop() {
+            let parent = deepest(self, &mut path);
+            // If the child was modified, so were we.
+            parent.modified |= child.modified;
+            parent.children[index] = NodeRef::from_boxed_node(child);
+            if let Some((k, n)) = split.take() {
+                // This makes the new key the child's new maximum.
+                parent.keys.insert(index, k);
+                // The new node is between the new key and the one after it; note the +1.
+                // This works because no node is permitted to have less than 2 children.
+                parent.children.insert(index+1, NodeRef::from_boxed_node(n));
+                if policy.overfull(parent) {
+                    split = Some(parent.split_in_place(cmp));
+                }
+            }
+        }
+        descended.map(|_| (previous, split))
+    }
+
+    /// Remove the key from the subtree rooted at this node, returning the value it had.
+    ///
+    /// Children which drop below the minimum occupancy are rebalanced before returning, but this node itself may be left underfull; that's the parent's problem.
+    fn remove<B: StorageBackend, C: Comparator<K>>(&mut self, backend: &Metered<'_, B>, cmp: &C, key: &K, policy: &SplitPolicy) -> Result<Option<V>, DecodingError>
+        where K: serde::Serialize, V: serde::Serialize
     {
-        assert!(self.node_type != NodeType::Root);
         if self.node_type == NodeType::Leaf {
-            match self.keys.binary_search(key) {
+            return Ok(match cmp.search(&self.keys, key) {
                 Ok(ind) => {
-                    self.children[ind] = NodeRef::from_offset(value);
+                    self.keys.remove(ind);
+                    self.modified = true;
+                    Some(self.values.remove(ind))
                 },
-                Err(ind) => {
-                    self.keys.insert(ind, key.clone());
-                    self.children.insert(ind, NodeRef::from_offset(value));
-                }
+                Err(_) => None,
+            });
+        }
+        let target = self.index_of(cmp, key);
+        let removed = self.children[target].get_mut(backend)?.remove(backend, cmp, key, policy)?;
+        if removed.is_some() {
+            self.modified = true;
+            self.children[target].count -= 1;
+        }
+        if removed.is_some() && policy.underfull(self.children[target].get(backend)?) {
+            self.rebalance_child(backend, cmp, target, policy)?;
+        }
+        Ok(removed)
+    }
+
+    /// Fix up an underfull child.
+    ///
+    /// If an adjacent sibling can spare a child we borrow one, checking the left sibling first.  Otherwise, the child is merged with a sibling.
+    /// Byte-budgeted trees merge first if the result fits, since borrowing one child barely helps a node which is short of bytes; they borrow only for a child which is short of children.
+    /// If they can do neither, the child stays as it is.
+    fn rebalance_child<B: StorageBackend, C: Comparator<K>>(&mut self, backend: &Metered<'_, B>, cmp: &C, index: usize, policy: &SplitPolicy) -> Result<(), DecodingError>
+        where K: serde::Serialize, V: serde::Serialize
+    {
+        assert!(self.node_type != NodeType::Leaf);
+        assert!(self.children.len() > 1);
+        self.modified = true;
+        // Prefer the left sibling, but the leftmost child has to use the right one.
+        let left_index = if index > 0 { index-1 } else { index };
+        // Load both up front, so that a decoding error can't leave us half-merged.
+        self.children[left_index].load(backend)?;
+        self.children[left_index+1].load(backend)?;
+        let min = policy.min_children(self.children[index].get(backend)?.node_type);
+        let fits = policy.fit_together(self.children[left_index].get(backend)?, self.children[left_index+1].get(backend)?);
+        let short = self.children[index].get(backend)?.width() < min;
+        let merge_first = fits && policy.prefers_merging();
+        if short && !merge_first {
+            if index > 0 && self.children[index-1].get(backend)?.width() > min {
+                self.borrow_from_left(backend, index)?;
+                return Ok(());
+            }
+            if index+1 < self.children.len() && self.children[index+1].get(backend)?.width() > min {
+                self.borrow_from_right(backend, index)?;
+                return Ok(());
             }
         }
-        else {
-            let target = self.index_of(key);
-            let needs_split = self.children[target].get_mut(backend)?.insert_nonroot(backend, key, value, split_threshold)?;
-            if let Some((k, n)) = needs_split {
-                // This makes the new key "our" new maximum.
-                self.keys.insert(target, k);
-                // The new node is between the new key and the one after it; note the +1.
-                // This works because no node is permitted to have less than 2 children.
-                self.children.insert(target+1, NodeRef::from_boxed_node(n));
+        // A node with too few children has to be merged, even if that leaves it over its byte budget.
+        if fits || short {
+            let separator = self.keys.remove(left_index);
+            let right = self.children.remove(left_index+1).into_box(backend)?;
+            self.children[left_index].get_mut(backend)?.merge_with(cmp, *right, separator);
+            self.recount(left_index);
+        }
+        Ok(())
+    }
+
+    /// Rebalance our first or last child against its one sibling, for `BPTree::split_off`, returning whether they merged.
+    ///
+    /// The first time, a child which can't merge borrows until it has one more than the minimum, so that a merge under it later can't leave it short.
+    /// That means each level merges at most once over all the passes, so nothing further from the edge than the sibling is ever touched.
+    /// After that, only a child which is short anyway gets merged.
+    fn rebalance_edge_child<B: StorageBackend, C: Comparator<K>>(&mut self, backend: &Metered<'_, B>, cmp: &C, rightmost: bool, policy: &SplitPolicy, first_pass: bool) -> Result<bool, DecodingError>
+        where K: serde::Serialize, V: serde::Serialize
+    {
+        assert!(self.children.len() > 1);
+        let index = if rightmost { self.children.len()-1 } else { 0 };
+        let min = policy.min_children(self.children[index].get(backend)?.node_type);
+        let sibling = if rightmost { index-1 } else { index+1 };
+        let left_index = std::cmp::min(index, sibling);
+        let fits = first_pass && policy.fit_together(self.children[left_index].get(backend)?, self.children[left_index+1].get(backend)?);
+        if first_pass && !fits {
+            while self.children[index].get(backend)?.width() <= min && self.children[sibling].get(backend)?.width() > min {
+                if rightmost {
+                    self.borrow_from_left(backend, index)?;
+                }
+                else {
+                    self.borrow_from_right(backend, index)?;
+                }
+                self.modified = true;
             }
         }
-        if self.children.len() > split_threshold {
-            Ok(Some(self.split_in_place()))
+        if !fits && self.children[index].get(backend)?.width() >= min {
+            return Ok(false);
         }
-        else { Ok(None) }   
+        let separator = self.keys.remove(left_index);
+        let right = self.children.remove(left_index+1).into_box(backend)?;
+        self.children[left_index].get_mut(backend)?.merge_with(cmp, *right, separator);
+        self.recount(left_index);
+        self.modified = true;
+        Ok(true)
     }
 
-    /// If the root splits, sets our type to internal and/or leaf depending, then returns the new sibling.
-    fn insert<B: StorageBackend>(&mut self, backend: &B, key: &K, value: u64, order: u64) -> Result<Option<(K, Box<Node<K>>)>, B::DecodingError> {
-        let split_threshold = (order/2+order%2) as usize;
-        // Leaf is a special, short-circuiting case:
-        if self.node_type == NodeType::Leaf {
-            return self.insert_nonroot(backend, key, value, split_threshold);
+    /// Rebalance the child at `index` until it isn't underfull, for `BPTree::append`, where it may be well short of the minimum.
+    fn rebalance_repeatedly<B: StorageBackend, C: Comparator<K>>(&mut self, backend: &Metered<'_, B>, cmp: &C, index: usize, policy: &SplitPolicy) -> Result<(), DecodingError>
+        where K: serde::Serialize, V: serde::Serialize
+    {
+        while policy.underfull(self.children[index].get(backend)?) {
+            let before = (self.children.len(), self.children[index].get(backend)?.width());
+            self.rebalance_child(backend, cmp, index, policy)?;
+            // Once merged, the child is as full as it'll get; a byte-budgeted child may also be unable to do either.
+            if self.children.len() != before.0 || self.children[index].get(backend)?.width() == before.1 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the last child of the left sibling of the child at `index` into it, rotating the separator through us.
+    fn borrow_from_left<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, index: usize) -> Result<(), DecodingError> {
+        let (before, after) = self.children.split_at_mut(index);
+        let left = before[index-1].get_mut(backend)?;
+        let node = after[0].get_mut(backend)?;
+        let separator = &mut self.keys[index-1];
+        left.modified = true;
+        node.modified = true;
+        if node.node_type == NodeType::Leaf {
+            node.values.insert(0, left.values.pop().unwrap());
+            node.keys.insert(0, left.keys.pop().unwrap());
+            *separator = left.keys.last().unwrap().clone();
+        }
+        else {
+            node.children.insert(0, left.children.pop().unwrap());
+            // The borrowed child is bounded above by the old separator, and the left sibling's last key bounds what remains.
+            let new_separator = left.keys.pop().unwrap();
+            node.keys.insert(0, std::mem::replace(separator, new_separator));
         }
-        let target = self.index_of(key);
-        let needs_split = self.children[target].get_mut(backend)?.insert_nonroot(backend, key, value, split_threshold)?;
-        if let Some((k, n)) = needs_split {
-            // Same as insert_nonroot.
-            self.keys.insert(target, k);
-            self.children.insert(target+1, NodeRef::from_boxed_node(n));
+        self.recount(index-1);
+        self.recount(index);
+        Ok(())
+    }
+
+    /// Move the first child of the right sibling of the child at `index` into it, rotating the separator through us.
+    fn borrow_from_right<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, index: usize) -> Result<(), DecodingError> {
+        let (before, after) = self.children.split_at_mut(index+1);
+        let node = before[index].get_mut(backend)?;
+        let right = after[0].get_mut(backend)?;
+        let separator = &mut self.keys[index];
+        node.modified = true;
+        right.modified = true;
+        if node.node_type == NodeType::Leaf {
+            node.values.push(right.values.remove(0));
+            node.keys.push(right.keys.remove(0));
+            *separator = node.keys.last().unwrap().clone();
         }
-        if self.children.len() > split_threshold {
-            Ok(Some(self.split_in_place()))
+        else {
+            node.children.push(right.children.remove(0));
+            let new_separator = right.keys.remove(0);
+            node.keys.push(std::mem::replace(separator, new_separator));
         }
-        else { Ok(None) }
+        self.recount(index);
+        self.recount(index+1);
+        Ok(())
     }
 }
 
-pub struct OffsetTree<K> {
-    root_reference: NodeRef<K>,
-    order: u64,
+// The bottom of a path of detached nodes, or the node they were detached from if there aren't any.
+fn deepest<'a, K, V>(top: &'a mut Node<K, V>, path: &'a mut [(usize, Box<Node<K, V>>)]) -> &'a mut Node<K, V> {
+    match path.last_mut() {
+        Some(&mut (_, ref mut n)) => n,
+        None => top,
+    }
 }
 
-impl<K: serde::de::DeserializeOwned+Eq+Ord+Clone> OffsetTree<K> {
-    pub fn empty(order: u64) -> OffsetTree<K> {
-        let initial_leaf = Box::new(Node {
-            modified: true,
-            node_type: NodeType::Leaf,
-            keys: vec![],
-            children: vec![],
-        });
-        OffsetTree {
-            root_reference: NodeRef::from_boxed_node(initial_leaf),
-            order,
+// The number of levels in the subtree under this node, loading the nodes down its rightmost or leftmost edge.
+fn edge_height<K: serde::de::DeserializeOwned, V: serde::de::DeserializeOwned, B: StorageBackend>(node: &Node<K, V>, backend: &Metered<'_, B>, rightmost: bool) -> Result<usize, DecodingError> {
+    let mut height = 1;
+    let mut node = node;
+    while node.node_type != NodeType::Leaf {
+        let next = if rightmost { node.children.last() } else { node.children.first() };
+        node = next.expect("Internal nodes have children.").get(backend)?;
+        height += 1;
+    }
+    Ok(height)
+}
+
+/// When nodes split, and when removals rebalance them.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SplitPolicy {
+    /// Split nodes with more than `ceil(order/2)` children, where `order` is the argument.
+    Order(u64),
+    /// Like `Order`, but with one order for the leaves and another for the root and the internal nodes.
+    ///
+    /// Leaves with long keys can be packed while the internal nodes above them stay small enough to keep in memory, or the other way around.
+    Orders { leaf: u64, internal: u64 },
+    /// Split nodes whose records would be more than this many bytes, as measured by `bincode::serialized_size`.
+    ///
+    /// Nodes with fewer than a quarter of the budget get rebalanced on removal.
+    /// Every node keeps at least 2 children, so a node holding a few very large keys may still go over.
+    Bytes(u64),
+}
+
+impl SplitPolicy {
+    // The order of nodes of this type, or `None` for a byte budget.
+    fn order(&self, node_type: NodeType) -> Option<u64> {
+        match *self {
+            SplitPolicy::Order(order) => Some(order),
+            SplitPolicy::Orders { leaf, .. } if node_type == NodeType::Leaf => Some(leaf),
+            SplitPolicy::Orders { internal, .. } => Some(internal),
+            SplitPolicy::Bytes(_) => None,
         }
     }
 
-    pub fn from_root_offset(offset: u64, order: u64) -> OffsetTree<K> {
-        OffsetTree {
-            root_reference: NodeRef::from_offset(offset),
-            order,
+    // The number of children nodes of this type split above, for policies with orders.
+    fn threshold(&self, node_type: NodeType) -> usize {
+        split_threshold(self.order(node_type).expect("Byte budgets don't count children."))
+    }
+
+    fn check(&self) {
+        match *self {
+            SplitPolicy::Order(order) => check_order(order),
+            SplitPolicy::Orders { leaf, internal } => {
+                check_order(leaf);
+                check_order(internal);
+            },
+            SplitPolicy::Bytes(_) => (),
         }
     }
 
-    pub fn contains<B: StorageBackend>(&mut self, backend: &B, key: &K) -> Result<bool, B::DecodingError> {
-        Ok(self.offset_for(backend, key)?.is_some())
+    fn overfull<K: serde::Serialize, V: serde::Serialize>(&self, node: &Node<K, V>) -> bool {
+        match *self {
+            SplitPolicy::Bytes(budget) => node.splittable() && node.stored_size() > budget,
+            SplitPolicy::Order(_) | SplitPolicy::Orders { .. } => node.width() > self.threshold(node.node_type),
+        }
     }
 
-    pub fn offset_for<B: StorageBackend>(&mut self, backend: &B, key: &K) -> Result<Option<u64>, B::DecodingError> {
-        self.root_reference.get(backend)?.find_offset_for(backend, key)
+    fn underfull<K: serde::Serialize, V: serde::Serialize>(&self, node: &Node<K, V>) -> bool {
+        match *self {
+            SplitPolicy::Order(_) | SplitPolicy::Orders { .. } => node.width() < self.min_children(node.node_type),
+            SplitPolicy::Bytes(budget) => node.width() < self.min_children(node.node_type) || node.stored_size() < budget/4,
+        }
     }
 
-    pub fn insert<B: StorageBackend>(&mut self, backend: &B, key: &K, value: u64) -> Result<(), B::DecodingError> {
-        let needs_split = self.root_reference.get_mut(backend)?.insert(backend, key, value, self.order)?;
-        if let Some((k, right)) = needs_split {
-            // This is a hack to get around moving out.
-            let r = std::mem::replace(&mut self.root_reference, NodeRef::from_offset(0));
-            let left = r.into_box(backend)?;
-            let new_node = Node {
-                node_type: NodeType::Root,
-                modified: true,
-                keys: vec![k],
-                children: vec![NodeRef::from_boxed_node(left), NodeRef::from_boxed_node(right)],
-            };
-            self.root_reference = NodeRef::from_boxed_node(Box::new(new_node));
-        }        
-        Ok(())
+    fn min_children(&self, node_type: NodeType) -> usize {
+        self.order(node_type).map(min_children).unwrap_or(2)
+    }
+
+    // Whether merging these two siblings would make a node which isn't overfull.
+    fn fit_together<K: serde::Serialize, V: serde::Serialize>(&self, left: &Node<K, V>, right: &Node<K, V>) -> bool {
+        match *self {
+            // An underfull node and a minimal one always fit together, but that's not right for the leftovers of a policy change.
+            SplitPolicy::Order(_) | SplitPolicy::Orders { .. } => left.width()+right.width() <= self.threshold(left.node_type),
+            SplitPolicy::Bytes(budget) => left.stored_size()+right.stored_size() <= budget,
+        }
+    }
+
+    fn prefers_merging(&self) -> bool {
+        match *self {
+            SplitPolicy::Order(_) | SplitPolicy::Orders { .. } => false,
+            SplitPolicy::Bytes(_) => true,
+        }
+    }
+}
+
+/// The smallest order a tree can have.
+///
+/// Nodes split when they have more than `ceil(order/2)` children, and an internal node needs 5 to split into two halves of at least 2 once the middle key moves up.
+pub const MIN_ORDER: u64 = 7;
+
+fn check_order(order: u64) {
+    assert!(order >= MIN_ORDER, "The order of a tree must be at least {}, not {}.", MIN_ORDER, order);
+}
+
+// Sizes for dividing `count` things into as few groups of at most `width` as possible, as evenly as possible.
+// With more than one group, every group is more than half full.
+fn even_chunks(count: usize, width: usize) -> Vec<usize> {
+    let groups = count.div_ceil(width);
+    (0..groups).map(|i| count/groups+if i < count%groups { 1 } else { 0 }).collect()
+}
+
+// Nodes split when they have more children than this.
+fn split_threshold(order: u64) -> usize {
+    (order/2+order%2) as usize
+}
+
+// Non-root nodes with fewer children than this get rebalanced on removal.
+// Splits never produce nodes smaller than this: a node splits with one more than the threshold, a leaf keeping the lower half of its keys and an internal node the lower half of its children less one, so whether the order is odd or even the smaller half has at least threshold/2.
+// The 2 only matters below MIN_ORDER.
+fn min_children(order: u64) -> usize {
+    std::cmp::max(2, split_threshold(order)/2)
+}
+
+/// The record written to a backend's header by `BPTree::commit_with_header`.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Header {
+    /// Always `FORMAT_VERSION` when written by this crate.
+    pub version: u8,
+    pub root: u64,
+    pub len: u64,
+    pub free_list: Option<FreeList>,
+    pub policy: SplitPolicy,
+}
+
+/// The shape of a tree, from `BPTree::stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TreeStats {
+    /// Levels of nodes, counting the leaves; a tree which is only a root leaf has height 1.
+    pub height: u64,
+    pub internal_nodes: u64,
+    pub leaves: u64,
+    pub keys: u64,
+    /// The average over all nodes of how full they are, from 0 to 1.
+    ///
+    /// Nodes are full when one more child would split them, or for a byte budget, when they're at it.
+    pub fill_factor: f64,
+}
+
+/** A read-only look at one node of a tree, from `BPTree::root_view`.
+
+Children are only loaded when `child` is called for them, so a verifier or visualizer can walk as little of the tree as it needs.
+Whatever it loads is kept, as for any other read.
+*/
+pub struct NodeView<'a, K: 'a, V: 'a, C: 'a = NaturalOrder> {
+    tree: &'a BPTree<K, V, C>,
+    node: &'a Node<K, V>,
+}
+
+// Where a node is stored as it is now, if it is.
+fn current_offset<K, V>(node: &Node<K, V>) -> Option<u64> {
+    if node.modified { None } else { node.stored_at }
+}
+
+impl<'a, K, V, C> NodeView<'a, K, V, C> {
+    pub fn node_type(&self) -> NodeType {
+        self.node.node_type
+    }
+
+    /// The keys of a leaf, or the separators of an internal node.
+    pub fn keys(&self) -> &'a [K] {
+        &self.node.keys
+    }
+
+    /// The values of a leaf, which for an `OffsetTree` are the offsets its keys map to.  Internal nodes have none.
+    pub fn values(&self) -> &'a [V] {
+        &self.node.values
+    }
+
+    /// The number of children of an internal node.  Leaves have none.
+    pub fn children(&self) -> usize {
+        self.node.children.len()
+    }
+
+    /// Where the node is stored, or `None` if it's new or has changed since it was last committed.
+    pub fn offset(&self) -> Option<u64> {
+        current_offset(self.node)
+    }
+
+    /// Where the child at `index` is stored, as `offset` would say, without loading it.  Panics if there's no such child.
+    pub fn child_offset(&self, index: usize) -> Option<u64> {
+        let child = &self.node.children[index];
+        match child.node.get() {
+            Some(n) => current_offset(n),
+            None => Some(child.offset),
+        }
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, C: Comparator<K>> NodeView<'a, K, V, C> {
+    /// The child at `index`, loading it if it isn't loaded already.  Panics if there's no such child.
+    pub fn child<B: StorageBackend>(&self, backend: &B, index: usize) -> Result<NodeView<'a, K, V, C>, DecodingError> {
+        let node = self.node.children[index].get(&self.tree.metered(backend))?;
+        Ok(NodeView { tree: self.tree, node })
+    }
+}
+
+/** Counts of how a tree got at its nodes, from `BPTree::metrics` once `BPTree::enable_metrics` has been called.
+
+Every time an operation needs a node, it's either resident, already in memory, or loaded from the backend.
+Iterators and cursors count theirs too, as do the nodes they load without keeping.
+Comparing the loaded count with the time an operation took says how much of it went on cold nodes.
+
+The counters are shared with forks, snapshots, and trees split off from this one, and can be read while other threads are using the tree.
+*/
+#[derive(Debug, Default)]
+pub struct Metrics {
+    loaded: AtomicU64,
+    resident: AtomicU64,
+    written: AtomicU64,
+}
+
+impl Metrics {
+    /// Nodes loaded from the backend.
+    pub fn loaded(&self) -> u64 {
+        self.loaded.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Nodes which were needed and already in memory.
+    pub fn resident(&self) -> u64 {
+        self.resident.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Nodes written by commits.
+    pub fn written(&self) -> u64 {
+        self.written.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.loaded.store(0, AtomicOrdering::Relaxed);
+        self.resident.store(0, AtomicOrdering::Relaxed);
+        self.written.store(0, AtomicOrdering::Relaxed);
+    }
+}
+
+fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, AtomicOrdering::Relaxed);
+}
+
+/** Offsets of nodes which are no longer part of the tree, so that later commits can reuse their space.
+
+Nodes superseded by a commit are still part of the tree the previous header points at, so they're only available for reuse once the next header has been written.
+Until then they're pending.
+
+Reuse is only safe if nothing else still points at the old nodes.
+Don't keep a free list for a tree if you also open other trees from its older roots.
+*/
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Eq, PartialEq)]
+pub struct FreeList {
+    available: Vec<u64>,
+    pending: Vec<u64>,
+}
+
+// How many dead records `FreeList::store` offers the backend before it gives up and stores a value anew.
+const REUSE_ATTEMPTS: usize = 8;
+
+impl FreeList {
+    pub fn new() -> FreeList {
+        Default::default()
+    }
+
+    /// Offsets which can be reused by the next commit.
+    pub fn available(&self) -> &[u64] {
+        &self.available
+    }
+
+    /// Offsets superseded since the last header was written.
+    pub fn pending(&self) -> &[u64] {
+        &self.pending
+    }
+
+    /// Mark an offset as garbage.  It becomes available for reuse once the next header is written.
+    ///
+    /// Trees release their own superseded nodes; this is for values which were stored alongside them.
+    pub fn release(&mut self, offset: u64) {
+        self.pending.push(offset);
+    }
+
+    /// Store a value, in the space of a dead record if the backend is willing.
+    pub fn store<B: StorageBackend, V: serde::Serialize>(&mut self, backend: &mut B, value: &V) -> Result<u64, EncodingError> {
+        for _ in 0..std::cmp::min(REUSE_ATTEMPTS, self.available.len()) {
+            let key = self.available.pop().expect("There are at least as many offsets as attempts.");
+            match backend.store_reusing(key, value)? {
+                Some(k) => return Ok(k),
+                // Too small for this value, but maybe not for others, which would never get to try it if it stayed on top.
+                None => self.available.insert(0, key),
+            }
+        }
+        backend.store_reusable(value)
+    }
+
+    // Called once a header which doesn't reference anything pending is durable.
+    fn promote(&mut self) {
+        self.available.append(&mut self.pending);
+    }
+}
+
+
+/** A B+ tree whose nodes live in a `StorageBackend`, loaded lazily and written back on commit.
+
+Leaves hold the values directly, so values should be small; larger ones are better stored separately, keeping only their keys in an `OffsetTree`.
+
+Lookups and iteration take `&self` and load nodes into the tree as they go, so a tree shared between threads can serve reads from all of them at once, given a backend which is `Sync`.
+Anything which changes the tree takes `&mut self`, as does committing.
+*/
+pub struct BPTree<K, V, C = NaturalOrder> {
+    root_reference: NodeRef<K, V>,
+    policy: SplitPolicy,
+    // Number of keys, which must be persisted by whoever persists the root offset.
+    len: u64,
+    free_list: Option<FreeList>,
+    comparator: C,
+    // The root offset and length as of the last commit, or as opened.
+    committed: Option<(u64, u64)>,
+    metrics: Option<sync::Arc<Metrics>>,
+}
+
+/// A tree from keys to offsets of values stored somewhere else, usually in the same backend.
+pub type OffsetTree<K, C = NaturalOrder> = BPTree<K, u64, C>;
+
+/// A summary of the tree, from what's already in memory: formatting never goes to the backend, so it doesn't show any keys.
+/// Use `to_dot` to see the whole structure.
+impl<K, V, C> std::fmt::Debug for BPTree<K, V, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let root = self.root_reference.node.get();
+        // A loaded root knows where it's stored itself, and nowhere if it's new.
+        let root_offset = match root {
+            Some(r) => r.stored_at,
+            None => Some(self.root_reference.offset),
+        };
+        f.debug_struct("BPTree")
+        .field("policy", &self.policy)
+        .field("len", &self.len)
+        .field("root_loaded", &root.is_some())
+        .field("root_offset", &root_offset)
+        .field("modified", &root.map(|r| r.modified).unwrap_or(false))
+        .finish()
+    }
+}
+
+// Constructors make trees ordered by `Ord`; use `with_comparator` to get any other order.
+impl<K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone> BPTree<K, V> {
+    /// Make an empty tree.
+    ///
+    /// Panics if `order` is less than `MIN_ORDER`.
+    pub fn empty(order: u64) -> BPTree<K, V> {
+        check_order(order);
+        let initial_leaf = Box::new(Node::new(NodeType::Leaf, vec![], vec![], vec![]));
+        BPTree {
+            root_reference: NodeRef::from_boxed_node(initial_leaf),
+            policy: SplitPolicy::Order(order),
+            len: 0,
+            free_list: None,
+            comparator: NaturalOrder,
+            committed: None,
+            metrics: None,
+        }
+    }
+
+    /// Reopen a tree from the offset of its root.
+    ///
+    /// `len` is the value `len` returned when the root was persisted; there's no way to recover it without a full scan.
+    /// Panics if `order` is less than `MIN_ORDER`.
+    pub fn from_root_offset(offset: u64, order: u64, len: u64) -> BPTree<K, V> {
+        check_order(order);
+        BPTree {
+            root_reference: NodeRef::from_offset(offset, len),
+            policy: SplitPolicy::Order(order),
+            len,
+            free_list: None,
+            comparator: NaturalOrder,
+            committed: Some((offset, len)),
+            metrics: None,
+        }
+    }
+
+    /// Open a tree whose root was stored in the backend under `root_key`.
+    ///
+    /// Unlike `from_root_offset`, this loads the root immediately, so that a bad key is reported here rather than by the first operation.
+    pub fn open<B: StorageBackend>(backend: &B, root_key: u64, order: u64, len: u64) -> Result<BPTree<K, V>, DecodingError> {
+        let tree = BPTree::from_root_offset(root_key, order, len);
+        tree.root_reference.load(&Metered::new(backend, None))?;
+        Ok(tree)
+    }
+
+    /// Reopen the tree recorded in the backend's header by `commit_with_header`, or `None` if there's no header.
+    ///
+    /// The header records the split policy the tree was committed with, and it's an error if that isn't the given order.
+    /// Use `reopen` to take whatever the header says.
+    pub fn from_header<B: StorageBackend>(backend: &B, order: u64) -> Result<Option<BPTree<K, V>>, DecodingError> {
+        check_order(order);
+        match BPTree::reopen(backend)? {
+            Some(ref t) if t.policy != SplitPolicy::Order(order) => {
+                Err(DecodingError::Corrupt(format!("Expected a tree of order {}, but the header says {:?}", order, t.policy)))
+            },
+            t => Ok(t),
+        }
+    }
+
+    /// Reopen the tree recorded in the backend's header, as `from_header` does, or if the backend has never had a header, start an empty tree and record it there.
+    ///
+    /// Only a missing header means starting over: one which can't be read, or is for a different order, is still an error, and the backend is left as it was.
+    pub fn open_or_create<B: StorageBackend>(backend: &mut B, order: u64) -> Result<BPTree<K, V>, Error> where K: serde::Serialize+Ord, V: serde::Serialize {
+        if let Some(tree) = BPTree::from_header(backend, order)? {
+            return Ok(tree);
+        }
+        let mut tree = BPTree::empty(order);
+        tree.commit_with_header(backend)?;
+        Ok(tree)
+    }
+
+    /// Reopen the tree recorded in the backend's header by `commit_with_header`, with the split policy it was committed with, or `None` if there's no header.
+    pub fn reopen<B: StorageBackend>(backend: &B) -> Result<Option<BPTree<K, V>>, DecodingError> {
+        // The version comes first, so it can be checked before trying to read the rest in a layout it might not have.
+        if let Some(version) = backend.load_header::<u8>()? {
+            check_version(version)?;
+        }
+        let h = match backend.load_header::<Header>()? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        if let Some(order) = [NodeType::Leaf, NodeType::Internal].iter().filter_map(|&t| h.policy.order(t)).find(|&o| o < MIN_ORDER) {
+            return Err(DecodingError::Corrupt(format!("The header says the tree has order {}, which is too small", order)));
+        }
+        let mut tree = BPTree::open(backend, h.root, MIN_ORDER, h.len)?;
+        tree.policy = h.policy;
+        tree.free_list = h.free_list;
+        Ok(Some(tree))
+    }
+
+    /// Build a tree from pairs whose keys are in strictly ascending order, without any of the descents or splits inserting them would take.
+    ///
+    /// Nodes are packed as full as they can be without splitting, and the levels are built from the leaves up.
+    /// Nothing is stored until the next commit; a `BPTreeBuilder` stores the nodes as it goes instead, for trees too big to build in memory.
+    pub fn build_sorted<I: IntoIterator<Item=(K, V)>>(iter: I, order: u64) -> Result<BPTree<K, V>, UnsortedError> where K: Ord {
+        let mut tree = BPTree::empty(order);
+        let mut keys = vec![];
+        let mut values = vec![];
+        for (k, v) in iter {
+            if keys.last().map(|l| *l >= k).unwrap_or(false) {
+                return Err(UnsortedError { index: keys.len() as u64 });
+            }
+            keys.push(k);
+            values.push(v);
+        }
+        tree.len = keys.len() as u64;
+        tree.root_reference = pack(keys, values, split_threshold(order), split_threshold(order));
+        Ok(tree)
+    }
+}
+
+// Build a tree from keys which are already in order, packing leaves with up to `leaf_width` keys and internal nodes with up to `internal_width` children from the leaves up, and return its root.
+fn pack<K: Clone, V>(keys: Vec<K>, values: Vec<V>, leaf_width: usize, internal_width: usize) -> NodeRef<K, V> {
+    // Each node, with the largest key under it.
+    let mut level = vec![];
+    let count = keys.len();
+    let mut keys = keys.into_iter();
+    let mut values = values.into_iter();
+    for size in even_chunks(count, leaf_width) {
+        let leaf_keys = keys.by_ref().take(size).collect::<Vec<_>>();
+        let max = leaf_keys.last().unwrap().clone();
+        level.push((max, Box::new(Node::new(NodeType::Leaf, leaf_keys, vec![], values.by_ref().take(size).collect()))));
+    }
+    while level.len() > 1 {
+        let count = level.len();
+        let mut below = level.into_iter();
+        level = vec![];
+        for size in even_chunks(count, internal_width) {
+            let mut separators = Vec::with_capacity(size);
+            let mut children = Vec::with_capacity(size);
+            for (k, n) in below.by_ref().take(size) {
+                separators.push(k);
+                children.push(NodeRef::from_boxed_node(n));
+            }
+            // The last child's maximum is the maximum of the new node rather than a separator.
+            let max = separators.pop().unwrap();
+            level.push((max, Box::new(Node::new(NodeType::Internal, separators, children, vec![]))));
+        }
+    }
+    let mut root = match level.pop() {
+        Some((_, root)) => root,
+        None => Box::new(Node::new(NodeType::Leaf, vec![], vec![], vec![])),
+    };
+    if root.node_type == NodeType::Internal {
+        root.node_type = NodeType::Root;
+    }
+    NodeRef::from_boxed_node(root)
+}
+
+impl<K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, C: Comparator<K>> BPTree<K, V, C> {
+    /// Order the tree's keys with the given comparator instead.
+    ///
+    /// This doesn't reorder anything, so it has to be the ordering the tree was built with unless the tree is empty.
+    pub fn with_comparator<C2: Comparator<K>>(self, comparator: C2) -> BPTree<K, V, C2> {
+        BPTree {
+            root_reference: self.root_reference,
+            policy: self.policy,
+            len: self.len,
+            free_list: self.free_list,
+            comparator,
+            committed: self.committed,
+            metrics: self.metrics,
+        }
+    }
+
+    /// Split nodes according to the given policy instead of the order.
+    ///
+    /// Unlike the comparator, this can change at any time: nodes which don't suit the new policy get fixed up as insertions and removals pass through them.
+    pub fn with_split_policy(mut self, policy: SplitPolicy) -> BPTree<K, V, C> {
+        policy.check();
+        self.policy = policy;
+        self
+    }
+
+    pub fn split_policy(&self) -> SplitPolicy {
+        self.policy
+    }
+
+    /// Start keeping a free list, so that commits can reuse the space of nodes they replace.
+    ///
+    /// Reuse needs a backend which implements `StorageBackend::store_reusing`.
+    pub fn enable_free_list(&mut self) {
+        if self.free_list.is_none() {
+            self.free_list = Some(FreeList::new());
+        }
+    }
+
+    pub fn free_list(&self) -> Option<&FreeList> {
+        self.free_list.as_ref()
+    }
+
+    pub fn free_list_mut(&mut self) -> Option<&mut FreeList> {
+        self.free_list.as_mut()
+    }
+
+    /// Start counting node loads and writes, from zero.
+    pub fn enable_metrics(&mut self) {
+        self.metrics = Some(sync::Arc::new(Metrics::default()));
+    }
+
+    /// The counts since `enable_metrics`, if it's been called.
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_deref()
+    }
+
+    // The backend, counting in our metrics.
+    fn metered<'a, B: StorageBackend>(&self, backend: &'a B) -> Metered<'a, B> {
+        Metered::new(backend, self.metrics.clone())
+    }
+
+    /// The number of keys in the tree.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the key is present, without cloning its value.
+    pub fn contains<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<bool, DecodingError> {
+        let backend = &self.metered(backend);
+        let mut node = self.root_reference.get(backend)?;
+        while node.node_type != NodeType::Leaf {
+            node = node.children[node.index_of(&self.comparator, key)].get(backend)?;
+        }
+        Ok(self.comparator.search(&node.keys, key).is_ok())
+    }
+
+    pub fn get<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Option<V>, DecodingError> {
+        let backend = &self.metered(backend);
+        self.root_reference.get(backend)?.find(backend, &self.comparator, key)
+    }
+
+    /// `get`, for keys which are each looked up once, without keeping the leaf it reads.
+    ///
+    /// Nothing it reads goes into a `CachedBackend`'s cache, where leaves read once would push out more useful nodes, since it loads with `StorageBackend::load_uncached`.
+    /// Internal nodes it loads are kept in the tree as `get` keeps them, but a leaf which isn't loaded already is dropped once it's been searched.
+    pub fn peek<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Option<V>, DecodingError> {
+        let backend = &self.metered(backend);
+        let search = |leaf: &Node<K, V>| self.comparator.search(&leaf.keys, key).ok().map(|i| leaf.values[i].clone());
+        let mut node_ref = &self.root_reference;
+        loop {
+            let node = match node_ref.loaded() {
+                Some(node) => {
+                    backend.count_resident();
+                    node
+                },
+                None => {
+                    let node: Node<K, V> = from_disk(DiskNode::load_uncached(backend, node_ref.offset)?, node_ref.offset)?;
+                    backend.count_loaded();
+                    if node.node_type == NodeType::Leaf {
+                        return Ok(search(&node));
+                    }
+                    &**node_ref.node.get_or_init(|| sync::Arc::new(node))
+                },
+            };
+            if node.node_type == NodeType::Leaf {
+                return Ok(search(node));
+            }
+            node_ref = &node.children[node.index_of(&self.comparator, key)];
+        }
+    }
+
+    /// Get the values for many keys at once, in the order of `keys`.
+    ///
+    /// Lookups share their descents: each one only climbs back up the path to the last key as far as the lowest node the new key falls under.
+    /// For keys in ascending order, that means every node on the way to the leaves they land in is looked at once for the whole batch, however many keys land in it.
+    /// Keys out of order still get the right values, but each one which goes backwards starts again from the root.
+    pub fn get_batch<B: StorageBackend>(&self, backend: &B, keys: &[K]) -> Result<Vec<Option<V>>, DecodingError> {
+        let backend = &self.metered(backend);
+        let cmp = &self.comparator;
+        // The path to the last key, with the index of each node in its parent.
+        let mut path = vec![(IterNode::resolve(backend, &self.root_reference)?, 0)];
+        let mut found = Vec::with_capacity(keys.len());
+        let mut previous = None;
+        for key in keys {
+            if previous.map(|p| cmp.compare(key, p) == Ordering::Less).unwrap_or(false) {
+                path.truncate(1);
+            }
+            previous = Some(key);
+            while path.len() > 1 && !covers(&path, cmp, key) {
+                path.pop();
+            }
+            loop {
+                let child = {
+                    let node = path.last().unwrap().0.node();
+                    if node.node_type == NodeType::Leaf {
+                        found.push(cmp.search(&node.keys, key).ok().map(|i| node.values[i].clone()));
+                        break;
+                    }
+                    let index = node.index_of(cmp, key);
+                    (path.last().unwrap().0.child(backend, index)?, index)
+                };
+                path.push(child);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Set the value for a key, returning the value it used to have if the key was already present.
+    ///
+    /// Setting a key to the value it already has doesn't count as a modification, so the next commit won't rewrite anything.
+    pub fn insert<B: StorageBackend>(&mut self, backend: &B, key: &K, value: V) -> Result<Option<V>, DecodingError> where K: serde::Serialize, V: serde::Serialize+PartialEq {
+        let backend = &self.metered(backend);
+        self.insert_with(backend, key, |old| if old == Some(&value) { None } else { Some(value) })
+    }
+
+    /// Insert every pair, returning how many of the keys weren't already present.
+    ///
+    /// This stops at the first error, keeping whatever was inserted before it.
+    pub fn extend<B: StorageBackend, I: IntoIterator<Item=(K, V)>>(&mut self, backend: &B, iter: I) -> Result<u64, DecodingError> where K: serde::Serialize, V: serde::Serialize+PartialEq {
+        let mut added = 0;
+        for (k, v) in iter {
+            if self.insert(backend, &k, v)?.is_none() {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Get the value for a key, inserting the one from `f` if the key isn't present.
+    ///
+    /// This only descends the tree once, and `f` is only called if the key is missing.
+    pub fn get_or_insert_with<B: StorageBackend, F: FnOnce() -> V>(&mut self, backend: &B, key: &K, f: F) -> Result<V, DecodingError> where K: serde::Serialize, V: serde::Serialize {
+        let backend = &self.metered(backend);
+        let mut result = None;
+        self.insert_with(backend, key, |old| match old {
+            Some(v) => {
+                result = Some(v.clone());
+                None
+            },
+            None => {
+                let v = f();
+                result = Some(v.clone());
+                Some(v)
+            },
+        })?;
+        Ok(result.unwrap())
+    }
+
+    fn insert_with<B: StorageBackend, F: FnOnce(Option<&V>) -> Option<V>>(&mut self, backend: &Metered<'_, B>, key: &K, value: F) -> Result<Option<V>, DecodingError> where K: serde::Serialize, V: serde::Serialize {
+        let (previous, needs_split) = self.root_reference.get_mut(backend)?.insert(backend, &self.comparator, key, value, &self.policy)?;
+        if previous.is_none() {
+            self.len += 1;
+        }
+        if let Some((k, right)) = needs_split {
+            // This is a hack to get around moving out.
+            let r = std::mem::replace(&mut self.root_reference, NodeRef::from_offset(0, 0));
+            let left = r.into_box(backend)?;
+            let new_node = Node::new(NodeType::Root, vec![k], vec![NodeRef::from_boxed_node(left), NodeRef::from_boxed_node(right)], vec![]);
+            self.root_reference = NodeRef::from_boxed_node(Box::new(new_node));
+        }
+        Ok(previous)
+    }
+
+    /// Remove a key from the tree, returning the value it used to have.
+    pub fn remove<B: StorageBackend>(&mut self, backend: &B, key: &K) -> Result<Option<V>, DecodingError> where K: serde::Serialize, V: serde::Serialize {
+        let backend = &self.metered(backend);
+        let removed = self.root_reference.get_mut(backend)?.remove(backend, &self.comparator, key, &self.policy)?;
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        self.collapse_root(backend)?;
+        Ok(removed)
+    }
+
+    // While the root is down to one child, that child becomes the new root.
+    fn collapse_root<B: StorageBackend>(&mut self, backend: &Metered<'_, B>) -> Result<(), DecodingError> {
+        loop {
+            let collapse = {
+                let root = self.root_reference.get(backend)?;
+                root.node_type != NodeType::Leaf && root.children.len() == 1
+            };
+            if !collapse {
+                return Ok(());
+            }
+            // Load it first, so that we don't replace the root with garbage on error.
+            self.root_reference.get(backend)?.children[0].load(backend)?;
+            let r = std::mem::replace(&mut self.root_reference, NodeRef::from_offset(0, 0));
+            let mut root = r.into_box(backend)?;
+            let mut child = root.children.pop().unwrap().into_box(backend)?;
+            if child.node_type == NodeType::Internal {
+                child.node_type = NodeType::Root;
+            }
+            child.superseded.extend(root.stored_at);
+            child.superseded.append(&mut root.superseded);
+            child.modified = true;
+            self.root_reference = NodeRef::from_boxed_node(child);
+        }
+    }
+
+    /// Load the top `levels` levels of the tree, the root being the first, so that lookups start from memory.
+    ///
+    /// Loaded nodes stay loaded for as long as the tree does, whatever the backend does with its own cache, so after this only the levels below ever go to the backend.
+    /// Nodes changed since the last commit are already in memory, and the levels stay loaded across commits.
+    pub fn pin_root<B: StorageBackend>(&self, backend: &B, levels: usize) -> Result<(), DecodingError> {
+        let backend = &self.metered(backend);
+        let mut level = vec![&self.root_reference];
+        for depth in 0..levels {
+            let mut below = vec![];
+            for node_ref in level {
+                let node = node_ref.get(backend)?;
+                if depth+1 < levels {
+                    below.extend(node.children.iter());
+                }
+            }
+            level = below;
+        }
+        Ok(())
+    }
+
+    /// Walk the whole tree, counting its nodes.
+    ///
+    /// Nodes which aren't in memory yet are loaded and kept, as for any other read.
+    pub fn stats<B: StorageBackend>(&self, backend: &B) -> Result<TreeStats, DecodingError> where K: serde::Serialize, V: serde::Serialize {
+        let backend = &self.metered(backend);
+        let mut stats = TreeStats { height: 0, internal_nodes: 0, leaves: 0, keys: 0, fill_factor: 0.0 };
+        let mut fill = 0.0;
+        let mut stack = vec![(&self.root_reference, 1)];
+        while let Some((node_ref, depth)) = stack.pop() {
+            let node = node_ref.get(backend)?;
+            stats.height = std::cmp::max(stats.height, depth);
+            fill += match self.policy {
+                SplitPolicy::Order(_) | SplitPolicy::Orders { .. } => node.width() as f64/self.policy.threshold(node.node_type) as f64,
+                SplitPolicy::Bytes(budget) => node.stored_size() as f64/budget as f64,
+            };
+            if node.node_type == NodeType::Leaf {
+                stats.leaves += 1;
+                stats.keys += node.keys.len() as u64;
+            }
+            else {
+                stats.internal_nodes += 1;
+                stack.extend(node.children.iter().map(|c| (c, depth+1)));
+            }
+        }
+        stats.fill_factor = fill/(stats.leaves+stats.internal_nodes) as f64;
+        Ok(stats)
+    }
+
+    /// Call `f` for every node, with its type, its keys, and how many children it has, counting a leaf's values as its children.
+    ///
+    /// Nodes are visited from the root down and from left to right, so each leaf comes after all of the nodes above it.
+    /// They're loaded and kept as for any other read, and nothing about them changes, so the next commit doesn't write any of them.
+    pub fn for_each_node<B: StorageBackend, F: FnMut(NodeType, &[K], usize)>(&self, backend: &B, mut f: F) -> Result<(), DecodingError> {
+        let backend = &self.metered(backend);
+        let mut stack = vec![&self.root_reference];
+        while let Some(node_ref) = stack.pop() {
+            let node = node_ref.get(backend)?;
+            f(node.node_type, &node.keys, node.width());
+            stack.extend(node.children.iter().rev());
+        }
+        Ok(())
+    }
+
+    /// A view of the root, loading it if it isn't loaded already, from which the rest of the tree can be walked one node at a time.
+    pub fn root_view<B: StorageBackend>(&self, backend: &B) -> Result<NodeView<'_, K, V, C>, DecodingError> {
+        let node = self.root_reference.get(&self.metered(backend))?;
+        Ok(NodeView { tree: self, node })
+    }
+
+    /// Check that the tree is well formed, loading all of it.
+    ///
+    /// The error describes the first problem found, and where: the path to the node is the index of each child on the way down from the root.
+    pub fn validate<B: StorageBackend>(&self, backend: &B) -> Result<(), DecodingError> {
+        let backend = &self.metered(backend);
+        fn corrupt(path: &[usize], problem: String) -> Result<(), DecodingError> {
+            let path = path.iter().map(|i| i.to_string()).collect::<Vec<_>>().join("/");
+            Err(DecodingError::Corrupt(format!("Node /{}: {}", path, problem)))
+        }
+        let cmp = &self.comparator;
+        let mut leaf_depth = None;
+        let mut keys = 0;
+        // Each node, with its path and the separators bounding it.
+        let mut stack = vec![(&self.root_reference, vec![], None, None)];
+        while let Some((node_ref, path, lower, upper)) = stack.pop() {
+            let node = node_ref.get(backend)?;
+            let is_root = path.is_empty();
+            let in_place = match node.node_type {
+                NodeType::Root => is_root,
+                NodeType::Internal => !is_root,
+                NodeType::Leaf => true,
+            };
+            if !in_place {
+                return corrupt(&path, format!("{:?} node in the wrong place", node.node_type));
+            }
+            if let Some(i) = node.keys.windows(2).position(|w| cmp.compare(&w[0], &w[1]) != Ordering::Less) {
+                return corrupt(&path, format!("keys {} and {} are out of order", i, i+1));
+            }
+            if let Some(l) = lower {
+                if let Some(i) = node.keys.iter().position(|k| cmp.compare(k, l) != Ordering::Greater) {
+                    return corrupt(&path, format!("key {} isn't above the separator before this node", i));
+                }
+            }
+            if let Some(u) = upper {
+                if let Some(i) = node.keys.iter().position(|k| cmp.compare(k, u) == Ordering::Greater) {
+                    return corrupt(&path, format!("key {} is above the separator after this node", i));
+                }
+            }
+            if !is_root && node.width() < 2 {
+                return corrupt(&path, format!("only {} children", node.width()));
+            }
+            // The root's count isn't kept; the tree's length is checked at the end instead.
+            if !is_root && node_ref.count != node.total() {
+                return corrupt(&path, format!("{} keys, but its parent counts {}", node.total(), node_ref.count));
+            }
+            if node.node_type == NodeType::Leaf {
+                if node.keys.len() != node.values.len() || !node.children.is_empty() {
+                    return corrupt(&path, format!("leaf with {} keys, {} values, and {} children", node.keys.len(), node.values.len(), node.children.len()));
+                }
+                let depth = *leaf_depth.get_or_insert(path.len());
+                if depth != path.len() {
+                    return corrupt(&path, format!("leaf at depth {}, but the first leaf is at {}", path.len(), depth));
+                }
+                keys += node.keys.len() as u64;
+                continue;
+            }
+            if node.keys.len()+1 != node.children.len() || !node.values.is_empty() {
+                return corrupt(&path, format!("internal node with {} keys, {} children, and {} values", node.keys.len(), node.children.len(), node.values.len()));
+            }
+            // Pushed backwards, so that children are checked left to right.
+            for i in (0..node.children.len()).rev() {
+                let mut child_path = path.clone();
+                child_path.push(i);
+                let l = if i == 0 { lower } else { Some(&node.keys[i-1]) };
+                let u = if i == node.keys.len() { upper } else { Some(&node.keys[i]) };
+                stack.push((&node.children[i], child_path, l, u));
+            }
+        }
+        if keys != self.len {
+            return Err(DecodingError::Corrupt(format!("The tree has {} keys, but its length is {}", keys, self.len)));
+        }
+        Ok(())
+    }
+
+    /// Write the tree's structure as a GraphViz DOT graph, with each node's keys and an edge to each of its children, for looking at when `validate` finds something wrong.
+    ///
+    /// Nodes are labelled with where they're stored, if anywhere, so that they can be matched up with the backend.
+    /// This loads all of the tree, and stops at the first node which won't load.
+    /// Failing to write is an `EncodingError`.
+    pub fn to_dot<B: StorageBackend>(&self, backend: &B, out: &mut dyn io::Write) -> Result<(), Error> where K: std::fmt::Debug {
+        let backend = &self.metered(backend);
+        fn escape(label: String) -> String {
+            label.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+        let written = |r: io::Result<()>| r.map_err(|e| Error::Encoding(EncodingError::IoError(e)));
+        written(writeln!(out, "digraph BPTree {{"))?;
+        written(writeln!(out, "    node [shape=box];"))?;
+        // Each node, with its number in the graph; children are numbered as they're pushed.
+        let mut stack = vec![(&self.root_reference, 0)];
+        let mut next_id = 1;
+        while let Some((node_ref, id)) = stack.pop() {
+            let node = node_ref.get(backend)?;
+            let stored = match node.stored_at {
+                Some(o) => format!("at {}", o),
+                None => "not stored".to_string(),
+            };
+            written(writeln!(out, "    n{} [label=\"{:?} {}\\n{}\"];", id, node.node_type, stored, escape(format!("{:?}", node.keys))))?;
+            let ids = next_id..next_id+node.children.len();
+            next_id = ids.end;
+            for child_id in ids.clone() {
+                written(writeln!(out, "    n{} -> n{};", id, child_id))?;
+            }
+            // Pushed backwards, so that nodes come out left to right.
+            stack.extend(node.children.iter().zip(ids).rev());
+        }
+        written(writeln!(out, "}}"))
+    }
+
+    /// Remove every key.
+    ///
+    /// With a free list, every stored node of the old tree is released to it, which means loading whatever of the tree isn't in memory yet to find them all.
+    /// On error, the tree is left as it was.
+    pub fn clear<B: StorageBackend>(&mut self, backend: &B) -> Result<(), DecodingError> {
+        let backend = &self.metered(backend);
+        if self.free_list.is_some() {
+            let mut garbage = vec![];
+            let mut loaded = vec![&self.root_reference];
+            let mut unloaded = vec![];
+            while let Some(r) = loaded.pop() {
+                match r.loaded() {
+                    Some(n) => {
+                        garbage.extend(n.stored_at);
+                        garbage.extend_from_slice(&n.superseded);
+                        loaded.extend(n.children.iter());
+                    },
+                    None => unloaded.push(r.offset),
+                }
+            }
+            // Nothing under an unloaded node is loaded, so these are only needed for their children's offsets.
+            while let Some(o) = unloaded.pop() {
+                let n: Node<K, V> = load(backend, o)?;
+                garbage.push(o);
+                unloaded.extend(n.children.iter().map(|c| c.offset));
+            }
+            if let Some(ref mut f) = self.free_list {
+                f.pending.append(&mut garbage);
+            }
+        }
+        self.root_reference = NodeRef::from_boxed_node(Box::new(Node::new(NodeType::Leaf, vec![], vec![], vec![])));
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Remove every pair for which `f` returns false, returning how many were removed.
+    ///
+    /// Rather than removing the keys one at a time, this reads the whole tree once and builds a new one from whatever survives, packed from the leaves up like `build_sorted`.
+    /// That's O(n) in the size of the tree however many keys go, so it's much faster than repeated `remove` when a lot of them do, and somewhat slower when only a few do.
+    /// Under a byte budget the survivors are inserted one at a time instead, since how many fit in a node depends on what they are, which makes it O(n log n).
+    ///
+    /// If nothing is removed, the tree isn't touched. Otherwise every node of the old tree is released to the free list, if there is one, as `clear` does.
+    /// On error the tree is left as it was, though `f` may have been called for some of the pairs.
+    pub fn retain<B: StorageBackend, F: FnMut(&K, &V) -> bool>(&mut self, backend: &B, mut f: F) -> Result<u64, DecodingError> where K: serde::Serialize, V: serde::Serialize {
+        let mut keys = vec![];
+        let mut values = vec![];
+        for pair in self.iter(backend) {
+            let (k, v) = pair?;
+            if f(&k, &v) {
+                keys.push(k);
+                values.push(v);
+            }
+        }
+        let removed = self.len-keys.len() as u64;
+        if removed == 0 {
+            return Ok(0);
+        }
+        self.clear(backend)?;
+        match self.policy {
+            SplitPolicy::Order(_) | SplitPolicy::Orders { .. } => {
+                self.len = keys.len() as u64;
+                self.root_reference = pack(keys, values, self.policy.threshold(NodeType::Leaf), self.policy.threshold(NodeType::Internal));
+            },
+            SplitPolicy::Bytes(_) => {
+                // Inserting into an empty leaf only loads what was just built, so this can't fail.
+                let backend = &self.metered(backend);
+                for (k, v) in keys.into_iter().zip(values) {
+                    self.insert_with(backend, &k, |_| Some(v))?;
+                }
+            },
+        }
+        Ok(removed)
+    }
+
+    /// Move every key at or above `key` into a new tree, which is returned.
+    ///
+    /// This cuts each node on the path down to `key` in two, so only that path is rewritten; everything to either side of it moves over as it is.
+    /// The cut leaves thin nodes along the new edges of both trees, which are then rebalanced against their neighbors from the top down.
+    /// The new tree has the same split policy and comparator, but no free list; anything freed goes to this tree's.
+    /// Everything the rebalancing can touch is loaded before anything moves, so on error this tree is left as it was.
+    pub fn split_off<B: StorageBackend>(&mut self, backend: &B, key: &K) -> Result<BPTree<K, V, C>, DecodingError> where K: serde::Serialize, V: serde::Serialize, C: Clone {
+        let backend = &self.metered(backend);
+        let cmp = &self.comparator;
+        // Load the path, and the nodes beside it at every level, which are all that rebalancing the edges can touch.
+        // After this, nothing below can fail.
+        {
+            let mut current = self.root_reference.get(backend)?;
+            let mut before: Option<&Node<K, V>> = None;
+            let mut after: Option<&Node<K, V>> = None;
+            while current.node_type != NodeType::Leaf {
+                let index = current.index_of(cmp, key);
+                let b = if index > 0 { Some(&current.children[index-1]) } else { before.and_then(|n| n.children.last()) };
+                let a = current.children.get(index+1).or_else(|| after.and_then(|n| n.children.first()));
+                before = match b { Some(r) => Some(r.get(backend)?), None => None };
+                after = match a { Some(r) => Some(r.get(backend)?), None => None };
+                current = current.children[index].get(backend)?;
+            }
+        }
+        // Each node on the path, with the index of the child it was cut at.
+        let mut path = vec![];
+        let mut current = std::mem::replace(&mut self.root_reference, NodeRef::from_offset(0, 0)).into_box(backend)?;
+        while current.node_type != NodeType::Leaf {
+            let index = current.index_of(cmp, key);
+            let child = std::mem::replace(&mut current.children[index], NodeRef::from_offset(0, 0)).into_box(backend)?;
+            path.push((current, index));
+            current = child;
+        }
+        // Offsets of path nodes which end up with nothing on the left.
+        let mut garbage = vec![];
+        // The leaf keeps its lower half in place, so it still frees its old record when next committed.
+        let split_at = match cmp.search(&current.keys, key) {
+            Ok(i) | Err(i) => i,
+        };
+        let right_keys = current.keys.split_off(split_at);
+        let right_values = current.values.split_off(split_at);
+        current.modified = true;
+        let mut right = if right_keys.is_empty() { None } else { Some(Box::new(Node::new(NodeType::Leaf, right_keys, vec![], right_values))) };
+        let mut left = if current.keys.is_empty() {
+            garbage.extend(current.stored_at);
+            garbage.append(&mut current.superseded);
+            None
+        }
+        else {
+            Some(current)
+        };
+        // Each half of a path node keeps the separators between its own children, except any next to a child which came out empty.
+        while let Some((mut node, index)) = path.pop() {
+            let mut right_children = node.children.split_off(index+1);
+            let mut right_keys = node.keys.split_off(index);
+            node.children.pop();
+            match left {
+                Some(l) => node.children.push(NodeRef::from_boxed_node(l)),
+                None => {
+                    node.keys.pop();
+                },
+            }
+            match right {
+                Some(r) => right_children.insert(0, NodeRef::from_boxed_node(r)),
+                None => {
+                    if !right_keys.is_empty() {
+                        right_keys.remove(0);
+                    }
+                },
+            }
+            right = if right_children.is_empty() { None } else { Some(Box::new(Node::new(NodeType::Internal, right_keys, right_children, vec![]))) };
+            node.modified = true;
+            left = if node.children.is_empty() {
+                garbage.extend(node.stored_at);
+                garbage.append(&mut node.superseded);
+                None
+            }
+            else {
+                Some(node)
+            };
+        }
+        let empty = || Box::new(Node::new(NodeType::Leaf, vec![], vec![], vec![]));
+        let mut left = left.unwrap_or_else(empty);
+        let mut right = right.unwrap_or_else(empty);
+        left.superseded.append(&mut garbage);
+        for root in [&mut left, &mut right].iter_mut() {
+            if root.node_type == NodeType::Internal {
+                root.node_type = NodeType::Root;
+            }
+        }
+        let left_len = left.total();
+        let mut other = BPTree {
+            root_reference: NodeRef::from_boxed_node(right),
+            policy: self.policy,
+            len: self.len-left_len,
+            free_list: None,
+            comparator: self.comparator.clone(),
+            committed: None,
+            metrics: self.metrics.clone(),
+        };
+        self.root_reference = NodeRef::from_boxed_node(left);
+        self.len = left_len;
+        self.repair_edge(backend, true)?;
+        other.repair_edge(backend, false)?;
+        Ok(other)
+    }
+
+    /// Move every key of `other` into this tree, where all of them are above all of ours.
+    ///
+    /// The shorter tree's root becomes a child of the taller tree's node at the same height on the facing edge, so only the nodes on that edge above it are rewritten.
+    /// Keys out of order are only caught in debug builds; in release builds they make a broken tree.
+    /// Nodes of `other` which get rewritten go to this tree's free list, if it has one, and `other`'s own free list is dropped.
+    /// On error, this tree is left as it was.
+    pub fn append<B: StorageBackend>(&mut self, backend: &B, other: BPTree<K, V, C>) -> Result<(), DecodingError> where K: serde::Serialize, V: serde::Serialize {
+        let backend = &self.metered(backend);
+        if other.len == 0 {
+            return Ok(());
+        }
+        let mut other_root = other.root_reference.into_box(backend)?;
+        if self.len == 0 {
+            self.root_reference.load(backend)?;
+            let old = std::mem::replace(&mut self.root_reference, NodeRef::from_offset(0, 0)).into_box(backend)?;
+            other_root.superseded.extend(old.stored_at);
+            other_root.superseded.extend_from_slice(&old.superseded);
+            other_root.modified = true;
+            self.root_reference = NodeRef::from_boxed_node(other_root);
+            self.len = other.len;
+            return Ok(());
+        }
+        let cmp = &self.comparator;
+        let separator = self.root_reference.get(backend)?.last_entry(backend)?.expect("The tree isn't empty.").0;
+        if cfg!(debug_assertions) {
+            let first = other_root.first_entry(backend)?.expect("The tree isn't empty.").0;
+            assert!(cmp.compare(&separator, &first) == Ordering::Less, "Appended a tree whose keys aren't all above this one's.");
+        }
+        // This loads both facing edges, which is everything below that might need to be loaded.
+        let height = edge_height(self.root_reference.get(backend)?, backend, true)?;
+        let other_height = edge_height(&other_root, backend, false)?;
+        let mut top = std::mem::replace(&mut self.root_reference, NodeRef::from_offset(0, 0)).into_box(backend)?;
+        let rightmost = height >= other_height;
+        let mut shorter = if rightmost { other_root } else { std::mem::replace(&mut top, other_root) };
+        let depth = if rightmost { height-other_height } else { other_height-height };
+        // Changing a node's type means rewriting it.
+        if shorter.node_type == NodeType::Root {
+            shorter.node_type = NodeType::Internal;
+            shorter.modified = true;
+        }
+        let policy = &self.policy;
+        if depth == 0 {
+            if top.node_type == NodeType::Root {
+                top.node_type = NodeType::Internal;
+                top.modified = true;
+            }
+            let (left, right) = if rightmost { (top, shorter) } else { (shorter, top) };
+            let mut root = Box::new(Node::new(NodeType::Root, vec![separator], vec![NodeRef::from_boxed_node(left), NodeRef::from_boxed_node(right)], vec![]));
+            // Both were roots, so either can be underfull.
+            root.rebalance_repeatedly(backend, cmp, 0, policy)?;
+            if root.children.len() > 1 {
+                root.rebalance_repeatedly(backend, cmp, 1, policy)?;
+            }
+            self.root_reference = NodeRef::from_boxed_node(root);
+        }
+        else {
+            // Each detached node down the edge to the parent of the shorter root, with its index in its parent.
+            let mut path: Vec<(usize, Box<Node<K, V>>)> = vec![];
+            for _ in 1..depth {
+                let current = deepest(&mut top, &mut path);
+                let index = if rightmost { current.children.len()-1 } else { 0 };
+                let child = std::mem::replace(&mut current.children[index], NodeRef::from_offset(0, 0)).into_box(backend)?;
+                path.push((index, child));
+            }
+            let parent = deepest(&mut top, &mut path);
+            let index = if rightmost {
+                parent.keys.push(separator);
+                parent.children.push(NodeRef::from_boxed_node(shorter));
+                parent.children.len()-1
+            }
+            else {
+                parent.keys.insert(0, separator);
+                parent.children.insert(0, NodeRef::from_boxed_node(shorter));
+                0
+            };
+            parent.modified = true;
+            parent.rebalance_repeatedly(backend, cmp, index, policy)?;
+            let mut split = if policy.overfull(parent) { Some(parent.split_in_place(cmp)) } else { None };
+            while let Some((index, child)) = path.pop() {
+                let parent = deepest(&mut top, &mut path);
+                parent.modified = true;
+                parent.children[index] = NodeRef::from_boxed_node(child);
+                if let Some((k, n)) = split.take() {
+                    parent.keys.insert(index, k);
+                    parent.children.insert(index+1, NodeRef::from_boxed_node(n));
+                    if policy.overfull(parent) {
+                        split = Some(parent.split_in_place(cmp));
+                    }
+                }
+            }
+            self.root_reference = NodeRef::from_boxed_node(match split {
+                Some((k, right)) => Box::new(Node::new(NodeType::Root, vec![k], vec![NodeRef::from_boxed_node(top), NodeRef::from_boxed_node(right)], vec![])),
+                None => top,
+            });
+        }
+        self.len += other.len;
+        self.collapse_root(backend)
+    }
+
+    // Rebalance the nodes down the rightmost or leftmost edge of the tree, after cutting a tree in two has left them with as little as one child.
+    // Everything off the edge is as it should be.
+    // Going from the top down means every node we rebalance under has at least 2 children, but a merge can then leave the node above it short, so this takes more passes until nothing merges.
+    fn repair_edge<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, rightmost: bool) -> Result<(), DecodingError> where K: serde::Serialize, V: serde::Serialize {
+        let mut first_pass = true;
+        loop {
+            self.collapse_root(backend)?;
+            let mut merged = false;
+            let mut node = self.root_reference.get_mut(backend)?;
+            while node.node_type != NodeType::Leaf {
+                merged |= node.rebalance_edge_child(backend, &self.comparator, rightmost, &self.policy, first_pass)?;
+                let index = if rightmost { node.children.len()-1 } else { 0 };
+                node = node.children[index].get_mut(backend)?;
+            }
+            if !merged {
+                return Ok(());
+            }
+            first_pass = false;
+        }
+    }
+
+    /// Remove every key in the range, returning the removed pairs in order.
+    ///
+    /// The range is cut out with two `split_off`s and the rest is put back together with `append`, so the cost is the height of the tree plus what's removed, and whole subtrees inside the range come out without being rebalanced one key at a time.
+    /// Every node of the removed part goes to the free list, if there is one.
+    /// On error, the tree is put back as it was.
+    pub fn drain_range<B: StorageBackend, R: RangeBounds<K>>(&mut self, backend: &B, bounds: R) -> Result<Vec<(K, V)>, DecodingError> where K: serde::Serialize, V: serde::Serialize, C: Clone {
+        // Find the first key in the range and the first one after it, so that both cuts are splits at keys which are present.
+        let first = match self.range(backend, (bounds.start_bound().cloned(), Bound::Unbounded)).next() {
+            Some(pair) => pair?.0,
+            None => return Ok(vec![]),
+        };
+        let after = match bounds.end_bound() {
+            Bound::Included(k) => self.range(backend, (Bound::Excluded(k.clone()), Bound::Unbounded)).next(),
+            Bound::Excluded(k) => self.range(backend, (Bound::Included(k.clone()), Bound::Unbounded)).next(),
+            Bound::Unbounded => None,
+        }.transpose()?.map(|(k, _)| k);
+        if let Some(ref a) = after {
+            if self.comparator.compare(&first, a) != Ordering::Less {
+                return Ok(vec![]);
+            }
+        }
+        let mut middle = self.split_off(backend, &first)?;
+        // Putting the pieces back only touches their facing edges, which the splits just loaded.
+        let right = match after {
+            Some(ref a) => match middle.split_off(backend, a) {
+                Ok(right) => Some(right),
+                Err(e) => {
+                    self.append(backend, middle)?;
+                    return Err(e);
+                },
+            },
+            None => None,
+        };
+        // Load all of the middle, so that reading its pairs and then releasing its nodes only goes to the backend once.
+        let mut unloaded = vec![&middle.root_reference];
+        let mut failed = None;
+        let metered = middle.metered(backend);
+        while let Some(r) = unloaded.pop() {
+            match r.get(&metered) {
+                Ok(n) => unloaded.extend(n.children.iter()),
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                },
+            }
+        }
+        if let Some(e) = failed {
+            if let Some(right) = right {
+                middle.append(backend, right)?;
+            }
+            self.append(backend, middle)?;
+            return Err(e);
+        }
+        let drained = middle.to_sorted_vec(backend)?;
+        if self.free_list.is_some() {
+            middle.free_list = Some(FreeList::new());
+            middle.clear(backend)?;
+            let mut garbage = middle.free_list.take().unwrap().pending;
+            if let Some(ref mut f) = self.free_list {
+                f.pending.append(&mut garbage);
+            }
+        }
+        if let Some(right) = right {
+            self.append(backend, right)?;
+        }
+        Ok(drained)
+    }
+
+    /// Write every modified node to the backend, returning the offset of the new root.
+    ///
+    /// Subtrees which haven't been modified since they were last loaded or committed keep their offsets and aren't written again.
+    /// The rest are written depth first, each node's children left to right and then the node itself, and nothing else decides the order.
+    /// So two trees of the same nodes, committed into backends in the same state, store the same records in the same order, and files written that way are byte for byte the same.
+    /// The tree can be reopened later by passing the returned offset and `len` to `from_root_offset`.
+    pub fn commit<B: StorageBackend>(&mut self, backend: &mut B) -> Result<u64, EncodingError> where K: serde::Serialize, V: serde::Serialize {
+        let root = self.root_reference.commit(backend, self.free_list.as_mut(), self.metrics.as_deref())?;
+        self.committed = Some((root, self.len));
+        Ok(root)
+    }
+
+    /// A tree of what was in this one as of the last commit, or `None` if it's never been committed or opened.
+    ///
+    /// Committed nodes are never rewritten, so the snapshot shares them with this tree, and doesn't see anything done to this tree since.
+    /// That stops being true if this tree has a free list: once the next header is written, nodes the snapshot still uses can be reused.
+    /// Snapshots don't have free lists of their own.
+    pub fn snapshot(&self) -> Option<BPTree<K, V, C>> where C: Clone {
+        self.committed.map(|(root, len)| BPTree {
+            root_reference: NodeRef::from_offset(root, len),
+            policy: self.policy,
+            len,
+            free_list: None,
+            comparator: self.comparator.clone(),
+            committed: self.committed,
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    /// A tree of what's in this one now, committed or not, sharing all of its nodes with this one.
+    ///
+    /// Nothing is copied up front: each tree copies a shared node the first time it changes it, so neither sees what's done to the other afterward.
+    /// Like snapshots, forks don't have free lists, and stop being safe once this tree's free list can reuse a node they might still load.
+    /// Nodes which hadn't been committed when the fork was made are written again if both trees are committed.
+    pub fn fork(&self) -> BPTree<K, V, C> where C: Clone {
+        BPTree {
+            root_reference: self.root_reference.clone(),
+            policy: self.policy,
+            len: self.len,
+            free_list: None,
+            comparator: self.comparator.clone(),
+            committed: self.committed,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Commit, then record the new root in the backend's header.
+    ///
+    /// The nodes are flushed before the header is written, and the header is the last thing written.
+    /// If we crash partway through, the header still points at the previous complete tree.
+    pub fn commit_with_header<B: StorageBackend>(&mut self, backend: &mut B) -> Result<Header, EncodingError> where K: serde::Serialize, V: serde::Serialize {
+        let root = self.commit(backend)?;
+        backend.flush()?;
+        // Once this header is durable, nothing points at the pending nodes.
+        let free_list = self.free_list.clone().map(|mut f| {
+            f.promote();
+            f
+        });
+        let header = Header {
+            version: FORMAT_VERSION,
+            root,
+            len: self.len,
+            free_list,
+            policy: self.policy,
+        };
+        backend.store_header(&header)?;
+        backend.flush()?;
+        self.free_list = header.free_list.clone();
+        Ok(header)
+    }
+
+    /// Write the whole tree, including anything not yet committed, into a different backend, returning the offset of the new root.
+    ///
+    /// The nodes keep their shape, but are stored a level at a time from the leaves up, so that each level is contiguous and in key order.
+    /// This loads all of the tree.
+    /// Afterward, this tree reads from `target` rather than `backend`, as if it had been opened there; if it has a free list, the list starts over empty, since the old one is for `backend`.
+    pub fn compact<B: StorageBackend, T: StorageBackend>(&mut self, backend: &B, target: &mut T) -> Result<u64, Error> where K: serde::Serialize, V: serde::Serialize {
+        let backend = &self.metered(backend);
+        let mut levels = vec![vec![self.root_reference.get(backend)?]];
+        loop {
+            let mut below = vec![];
+            for node in levels.last().unwrap() {
+                for c in node.children.iter() {
+                    below.push(c.get(backend)?);
+                }
+            }
+            if below.is_empty() {
+                break;
+            }
+            levels.push(below);
+        }
+        let mut offsets: Vec<u64> = vec![];
+        for level in levels.iter().rev() {
+            let mut children = offsets.into_iter();
+            offsets = Vec::with_capacity(level.len());
+            for node in level {
+                let node_children = children.by_ref().take(node.children.len()).collect();
+                offsets.push(target.store(&node.disk_ref(node_children)?)?);
+            }
+        }
+        target.flush()?;
+        let root = offsets[0];
+        self.root_reference = NodeRef::from_offset(root, self.len);
+        if self.free_list.is_some() {
+            self.free_list = Some(FreeList::new());
+        }
+        self.committed = Some((root, self.len));
+        Ok(root)
+    }
+
+    /// Get the smallest key and its value, or `None` if the tree is empty.
+    pub fn first<B: StorageBackend>(&self, backend: &B) -> Result<Option<(K, V)>, DecodingError> {
+        let backend = &self.metered(backend);
+        self.root_reference.get(backend)?.first_entry(backend)
+    }
+
+    /// Get the largest key and its value, or `None` if the tree is empty.
+    pub fn last<B: StorageBackend>(&self, backend: &B) -> Result<Option<(K, V)>, DecodingError> {
+        let backend = &self.metered(backend);
+        self.root_reference.get(backend)?.last_entry(backend)
+    }
+
+    /// Get the smallest key which is greater than or equal to the probe, and its value.
+    pub fn ceiling<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Option<(K, V)>, DecodingError> {
+        let backend = &self.metered(backend);
+        self.root_reference.get(backend)?.ceiling(backend, &self.comparator, key)
+    }
+
+    /// Get the largest key which is less than or equal to the probe, and its value.
+    pub fn floor<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Option<(K, V)>, DecodingError> {
+        let backend = &self.metered(backend);
+        self.root_reference.get(backend)?.floor(backend, &self.comparator, key)
+    }
+
+    /// Whether there are no keys in a range.
+    ///
+    /// This only looks for the first key from the start of the range on.
+    /// That means going down the path to where the range would start, and if nothing left there is in range, down the left edge of whatever comes next.
+    pub fn range_is_empty<B: StorageBackend, R: RangeBounds<K>>(&self, backend: &B, bounds: R) -> Result<bool, DecodingError> {
+        let backend = &self.metered(backend);
+        let first = self.root_reference.get(backend)?.first_from(backend, &self.comparator, bounds.start_bound())?;
+        Ok(first.map(|k| past_upper(&self.comparator, bounds.end_bound(), k)).unwrap_or(true))
+    }
+
+    /// Count the keys in a range.
+    ///
+    /// Internal nodes know how many keys are under each of their children, so this only goes down the paths to the two ends of the range.
+    pub fn count_range<B: StorageBackend, R: RangeBounds<K>>(&self, backend: &B, bounds: R) -> Result<u64, DecodingError> {
+        let backend = &self.metered(backend);
+        let to_upper = match bounds.end_bound() {
+            Bound::Unbounded => self.len,
+            Bound::Included(k) => self.count_before(backend, k, true)?,
+            Bound::Excluded(k) => self.count_before(backend, k, false)?,
+        };
+        let to_lower = match bounds.start_bound() {
+            Bound::Unbounded => 0,
+            Bound::Included(k) => self.count_before(backend, k, false)?,
+            Bound::Excluded(k) => self.count_before(backend, k, true)?,
+        };
+        Ok(to_upper.saturating_sub(to_lower))
+    }
+
+    /// The number of keys less than the given one, whether or not it's present.
+    pub fn rank<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<u64, DecodingError> {
+        let backend = &self.metered(backend);
+        self.count_before(backend, key, false)
+    }
+
+    /// The `n`th smallest key and its value, counting from 0, or `None` if there aren't that many keys.
+    pub fn select<B: StorageBackend>(&self, backend: &B, n: u64) -> Result<Option<(K, V)>, DecodingError> {
+        let backend = &self.metered(backend);
+        if n >= self.len {
+            return Ok(None);
+        }
+        let mut node = self.root_reference.get(backend)?;
+        let mut n = n;
+        while node.node_type != NodeType::Leaf {
+            let mut index = 0;
+            while index+1 < node.children.len() && n >= node.children[index].count {
+                n -= node.children[index].count;
+                index += 1;
+            }
+            node = node.children[index].get(backend)?;
+        }
+        Ok(node.keys.get(n as usize).map(|_| node.leaf_entry(n as usize)))
+    }
+
+    // The number of keys less than the given one, or no greater than it if `inclusive`.
+    fn count_before<B: StorageBackend>(&self, backend: &Metered<'_, B>, key: &K, inclusive: bool) -> Result<u64, DecodingError> {
+        let mut node = self.root_reference.get(backend)?;
+        let mut count = 0;
+        while node.node_type != NodeType::Leaf {
+            let index = node.index_of(&self.comparator, key);
+            count += node.children[..index].iter().map(|c| c.count).sum::<u64>();
+            node = node.children[index].get(backend)?;
+        }
+        Ok(count+match self.comparator.search(&node.keys, key) {
+            Ok(i) if inclusive => i as u64+1,
+            Ok(i) | Err(i) => i as u64,
+        })
+    }
+
+    /// Iterate over all keys and their values in ascending order.
+    ///
+    /// Nodes which aren't already in memory are loaded as the iterator reaches them and dropped once it moves past them, so at most one path from root to leaf is resident for the iterator's benefit.
+    pub fn iter<'a, B: StorageBackend>(&'a self, backend: &'a B) -> Iter<'a, K, V, B, C> {
+        self.range(backend, ..)
+    }
+
+    /// Collect all keys and their values in ascending order, as `iter` would, into a vector sized for the whole tree.
+    ///
+    /// The result is suitable for passing straight back to `build_sorted`.
+    pub fn to_sorted_vec<B: StorageBackend>(&self, backend: &B) -> Result<Vec<(K, V)>, DecodingError> {
+        let mut result = Vec::with_capacity(self.len as usize);
+        for pair in self.iter(backend) {
+            result.push(pair?);
+        }
+        Ok(result)
+    }
+
+    /// Iterate over all keys and their values in descending order.
+    ///
+    /// Like `iter`, this only keeps one path from root to leaf resident.
+    pub fn iter_rev<'a, B: StorageBackend>(&'a self, backend: &'a B) -> RevIter<'a, K, V, B> {
+        RevIter {
+            backend: self.metered(backend),
+            stack: vec![],
+            root: Some(&self.root_reference),
+        }
+    }
+
+    /// Consume the tree, iterating over all keys and their values in ascending order.
+    ///
+    /// Unlike `iter`, this moves the keys and values out of the nodes rather than cloning them.
+    /// Any changes which haven't been committed are lost.
+    pub fn into_iter<B: StorageBackend>(self, backend: &B) -> IntoIter<'_, K, V, B> {
+        IntoIter {
+            backend: self.metered(backend),
+            stack: vec![vec![self.root_reference].into_iter()],
+            leaf: vec![].into_iter().zip(vec![]),
+        }
+    }
+
+    /// Get a cursor at the smallest key which is at least `key`, or off the end if there isn't one.
+    pub fn cursor_at<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Cursor<'_, K, V, C>, DecodingError> {
+        let backend = &self.metered(backend);
+        let mut cursor = Cursor {
+            root: &self.root_reference,
+            stack: vec![],
+            comparator: &self.comparator,
+            metrics: self.metrics.clone(),
+        };
+        cursor.seek(backend, key)?;
+        Ok(cursor)
+    }
+
+    /// Get a cursor which can change values, at the smallest key which is at least `key`, or off the end if there isn't one.
+    pub fn cursor_mut_at<B: StorageBackend>(&mut self, backend: &B, key: &K) -> Result<CursorMut<'_, K, V, C>, DecodingError> {
+        let backend = &self.metered(backend);
+        let mut cursor = CursorMut {
+            tree: self,
+            path: vec![],
+            index: 0,
+        };
+        // On error, dropping the cursor puts the tree back together.
+        cursor.seek(backend, key)?;
+        Ok(cursor)
+    }
+
+    /// Find where a key is or would go, for deciding what to do with it without descending again.
+    ///
+    /// The entry holds the path to the key's leaf until it's dropped, so inserting into a vacant entry splits nodes on the way back up without going down a second time.
+    pub fn entry<B: StorageBackend>(&mut self, backend: &B, key: &K) -> Result<Entry<'_, K, V, C>, DecodingError> {
+        let backend = &self.metered(backend);
+        let mut cursor = CursorMut {
+            tree: self,
+            path: vec![],
+            index: 0,
+        };
+        // On error, dropping the cursor puts the tree back together.
+        Ok(if cursor.descend(backend, key)? {
+            Entry::Occupied(OccupiedEntry { cursor })
+        } else {
+            Entry::Vacant(VacantEntry { cursor, key: key.clone() })
+        })
+    }
+
+    /// Iterate over the keys in the given range and their values, in ascending order.
+    ///
+    /// The iterator descends directly to the first leaf which might contain the start of the range.
+    pub fn range<'a, B: StorageBackend, R: RangeBounds<K>>(&'a self, backend: &'a B, bounds: R) -> Iter<'a, K, V, B, C> {
+        Iter {
+            backend: self.metered(backend),
+            comparator: &self.comparator,
+            stack: vec![],
+            root: Some(&self.root_reference),
+            lower: bounds.start_bound().cloned(),
+            upper: bounds.end_bound().cloned(),
+            prefetch: false,
+        }
+    }
+
+    /// Fold `f` over the keys in the given range and their values, in ascending order, without cloning any of them.
+    ///
+    /// Like `range`, this descends directly to the first leaf which might contain the start of the range, and nodes which aren't in memory are loaded just for the fold rather than kept.
+    /// An error loading a node is returned in place of the accumulator, which is dropped; anything needed from a fold which fails partway has to be kept by `f` itself.
+    pub fn fold_range<B: StorageBackend, R: RangeBounds<K>, A, F: FnMut(A, &K, &V) -> A>(&self, backend: &B, bounds: R, init: A, mut f: F) -> Result<A, DecodingError> {
+        let backend = &self.metered(backend);
+        let root = IterNode::resolve(backend, &self.root_reference)?;
+        fold_node(backend, &self.comparator, &root, bounds.start_bound(), bounds.end_bound(), init, &mut f).map(|(acc, _)| acc)
+    }
+}
+
+// Fold over the pairs under a node from the lower bound on, returning the accumulator and whether the fold got past the upper bound.
+// Only the leftmost path needs the lower bound, so the rest of the nodes get an unbounded one.
+fn fold_node<K, V, B, C, A, F>(backend: &Metered<'_, B>, cmp: &C, node: &IterNode<'_, K, V>, lower: Bound<&K>, upper: Bound<&K>, mut acc: A, f: &mut F) -> Result<(A, bool), DecodingError>
+    where K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, B: StorageBackend, C: Comparator<K>, F: FnMut(A, &K, &V) -> A
+{
+    let n = node.node();
+    if n.node_type == NodeType::Leaf {
+        for i in leaf_start(cmp, &n.keys, lower)..n.keys.len() {
+            if past_upper(cmp, upper, &n.keys[i]) {
+                return Ok((acc, true));
+            }
+            acc = f(acc, &n.keys[i], &n.values[i]);
+        }
+        return Ok((acc, false));
+    }
+    let start = match lower {
+        Bound::Unbounded => 0,
+        Bound::Included(k) | Bound::Excluded(k) => n.index_of(cmp, k),
+    };
+    for i in start..n.children.len() {
+        let child = node.child(backend, i)?;
+        let (a, done) = fold_node(backend, cmp, &child, if i == start { lower } else { Bound::Unbounded }, upper, acc, f)?;
+        acc = a;
+        if done {
+            return Ok((acc, true));
+        }
+    }
+    Ok((acc, false))
+}
+
+// The index of the first key in a leaf which is in range of the lower bound.
+fn leaf_start<K, C: Comparator<K>>(cmp: &C, keys: &[K], lower: Bound<&K>) -> usize {
+    match lower {
+        Bound::Unbounded => 0,
+        Bound::Included(k) => match cmp.search(keys, k) {
+            Ok(i) | Err(i) => i,
+        },
+        Bound::Excluded(k) => match cmp.search(keys, k) {
+            Ok(i) => i+1,
+            Err(i) => i,
+        },
+    }
+}
+
+fn past_upper<K, C: Comparator<K>>(cmp: &C, upper: Bound<&K>, key: &K) -> bool {
+    match upper {
+        Bound::Unbounded => false,
+        Bound::Included(k) => cmp.compare(key, k) == Ordering::Greater,
+        Bound::Excluded(k) => cmp.compare(key, k) != Ordering::Less,
+    }
+}
+
+impl<K: serde::de::DeserializeOwned+Clone, C: Comparator<K>> OffsetTree<K, C> {
+    /// The offset stored for `key`, if there is one.
+    ///
+    /// Loading nodes along the way doesn't need the tree to be mutable, so a shared reference will do.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate append_tree;
+    /// use append_tree::{MemoryBackend, OffsetTree};
+    ///
+    /// # fn main() {
+    /// let backend = MemoryBackend::new();
+    /// let mut tree = OffsetTree::empty(7);
+    /// for k in 0..100u32 {
+    ///     tree.insert(&backend, &k, k as u64*10).unwrap();
+    /// }
+    /// let shared: &OffsetTree<u32> = &tree;
+    /// let k = 42;
+    /// assert_eq!(shared.offset_for(&backend, &k).unwrap(), Some(420));
+    /// assert_eq!(shared.offset_for(&backend, &1000).unwrap(), None);
+    /// # }
+    /// ```
+    pub fn offset_for<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Option<u64>, DecodingError> {
+        self.get(backend, key)
+    }
+
+    /// `offset_for` through `peek`, which doesn't keep the leaf it reads or let it into a backend's cache.
+    pub fn peek_offset_for<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Option<u64>, DecodingError> {
+        self.peek(backend, key)
+    }
+
+    /// Remove every key whose offset matches `pred`, whatever the key is, returning how many were removed.
+    ///
+    /// This is for purging keys which point into space that's been reclaimed, as after compacting wherever the values are stored.
+    /// It's `retain` underneath, so it rebuilds the tree in one pass, and on error the tree is left as it was.
+    pub fn remove_by_offset<B: StorageBackend, F: Fn(u64) -> bool>(&mut self, backend: &B, pred: F) -> Result<u64, DecodingError> where K: serde::Serialize {
+        self.retain(backend, |_, &offset| !pred(offset))
+    }
+}
+
+/** A tree which can hold any number of offsets for each key.
+
+Each key's offsets are kept together, in insertion order, so scans see all of them at once no matter how many there are.
+They're stored in the key's leaf, so a key with a great many offsets makes for a large leaf.
+`len` counts keys, not offsets.
+*/
+pub type MultiOffsetTree<K> = BPTree<K, Vec<u64>>;
+
+impl<K: serde::de::DeserializeOwned+Clone, C: Comparator<K>> BPTree<K, Vec<u64>, C> {
+    /// Add an offset for a key, after any it already has.
+    pub fn insert_multi<B: StorageBackend>(&mut self, backend: &B, key: &K, offset: u64) -> Result<(), DecodingError> where K: serde::Serialize {
+        let backend = &self.metered(backend);
+        self.insert_with(backend, key, |old| {
+            let mut offsets = old.cloned().unwrap_or_default();
+            offsets.push(offset);
+            Some(offsets)
+        })?;
+        Ok(())
+    }
+
+    /// All of the offsets for a key, in the order they were inserted.
+    pub fn offsets_for<B: StorageBackend>(&self, backend: &B, key: &K) -> Result<Vec<u64>, DecodingError> {
+        Ok(self.get(backend, key)?.unwrap_or_default())
+    }
+}
+
+// Nodes on the iterator's stack are either borrowed from the tree or were loaded just for the iterator.
+enum IterNode<'a, K: 'a, V: 'a> {
+    Borrowed(&'a Node<K, V>),
+    Owned(Box<Node<K, V>>),
+}
+
+impl<'a, K, V> IterNode<'a, K, V> {
+    fn node(&self) -> &Node<K, V> {
+        match *self {
+            IterNode::Borrowed(n) => n,
+            IterNode::Owned(ref n) => n,
+        }
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned, V: serde::de::DeserializeOwned> IterNode<'a, K, V> {
+    fn resolve<B: StorageBackend>(backend: &Metered<'_, B>, node_ref: &'a NodeRef<K, V>) -> Result<IterNode<'a, K, V>, DecodingError> {
+        Ok(match node_ref.loaded() {
+            Some(n) => {
+                backend.count_resident();
+                IterNode::Borrowed(n)
+            },
+            None => IterNode::Owned(Box::new(load(backend, node_ref.offset)?)),
+        })
+    }
+
+    fn child<B: StorageBackend>(&self, backend: &Metered<'_, B>, index: usize) -> Result<IterNode<'a, K, V>, DecodingError> {
+        match *self {
+            IterNode::Borrowed(b) => IterNode::resolve(backend, &b.children[index]),
+            // A node loaded just for us came from the backend, so its children can't be loaded and their offsets are right.
+            IterNode::Owned(ref o) => Ok(IterNode::Owned(Box::new(load(backend, o.children[index].offset)?))),
+        }
+    }
+}
+
+// Whether a key no smaller than the last one looked up along `path` is under the node at the end of it.
+// That's when it's no greater than the nearest separator to the right of the path, if there is one.
+fn covers<K, V, C: Comparator<K>>(path: &[(IterNode<'_, K, V>, usize)], cmp: &C, key: &K) -> bool {
+    for i in (1..path.len()).rev() {
+        let (parent, index) = (path[i-1].0.node(), path[i].1);
+        if index < parent.keys.len() {
+            return cmp.compare(key, &parent.keys[index]) != Ordering::Greater;
+        }
+    }
+    true
+}
+
+// A node on the path of an `Iter`, the index of the next child to visit, and the children after that which have been prefetched.
+type IterLevel<'a, K, V> = (IterNode<'a, K, V>, usize, VecDeque<IterNode<'a, K, V>>);
+
+/// An iterator over a range of the keys and values of a `BPTree`, in ascending order.
+///
+/// After returning an error, the iterator is exhausted.
+pub struct Iter<'a, K: 'a, V: 'a, B: 'a, C: 'a = NaturalOrder> {
+    backend: Metered<'a, B>,
+    comparator: &'a C,
+    stack: Vec<IterLevel<'a, K, V>>,
+    // The root, until we first descend into it.
+    root: Option<&'a NodeRef<K, V>>,
+    lower: Bound<K>,
+    upper: Bound<K>,
+    prefetch: bool,
+}
+
+// What to do after looking at the top of the stack.
+enum IterStep<'a, K: 'a, V: 'a> {
+    Pop,
+    Yield(K, V),
+    DescendRef(&'a NodeRef<K, V>),
+    DescendOffset(u64),
+    DescendPrefetched(IterNode<'a, K, V>),
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, B: StorageBackend, C: Comparator<K>> Iter<'a, K, V, B, C> {
+    /// Whether to load all of the children of each internal node which might be in the range as soon as the iterator reaches the node, rather than each one as it's reached.
+    ///
+    /// This is off by default.
+    /// The iterator keeps the prefetched nodes until it gets to them, so it holds up to a node's worth of children at each level; in exchange, the backend sees each node's children requested together, which is what a caching backend wants to warm itself.
+    pub fn prefetch(mut self, prefetch: bool) -> Iter<'a, K, V, B, C> {
+        self.prefetch = prefetch;
+        self
+    }
+
+    // Load the children of an internal node from `from` through the last which might have keys in range, if we're prefetching.
+    fn fill(&self, node: &IterNode<'a, K, V>, from: usize) -> Result<VecDeque<IterNode<'a, K, V>>, DecodingError> {
+        let n = node.node();
+        if !self.prefetch || n.node_type == NodeType::Leaf {
+            return Ok(VecDeque::new());
+        }
+        // Children after a separator past the upper bound only have keys past it.
+        let end = n.keys.iter().position(|k| self.past_upper(k)).map(|p| p+1).unwrap_or(n.children.len());
+        (from..std::cmp::max(from, end)).map(|i| node.child(&self.backend, i)).collect()
+    }
+
+    // Build the stack for the path to the lower bound.
+    fn seek(&mut self, root: &'a NodeRef<K, V>) -> Result<(), DecodingError> {
+        let mut node = IterNode::resolve(&self.backend, root)?;
+        loop {
+            let (index, child) = {
+                let n = node.node();
+                if n.node_type == NodeType::Leaf {
+                    let index = leaf_start(self.comparator, &n.keys, self.lower.as_ref());
+                    self.stack.push((node, index, VecDeque::new()));
+                    return Ok(());
+                }
+                let index = match self.lower {
+                    Bound::Unbounded => 0,
+                    Bound::Included(ref k) | Bound::Excluded(ref k) => n.index_of(self.comparator, k),
+                };
+                (index, node.child(&self.backend, index)?)
+            };
+            let prefetched = self.fill(&node, index+1)?;
+            self.stack.push((node, index+1, prefetched));
+            node = child;
+        }
+    }
+
+    fn past_upper(&self, key: &K) -> bool {
+        past_upper(self.comparator, self.upper.as_ref(), key)
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, B: StorageBackend, C: Comparator<K>> Iterator for Iter<'a, K, V, B, C> {
+    type Item = Result<(K, V), DecodingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            if let Err(e) = self.seek(root) {
+                self.stack.clear();
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let step = match self.stack.last_mut() {
+                None => return None,
+                Some(&mut (ref node, ref mut index, ref mut prefetched)) => {
+                    let i = *index;
+                    *index += 1;
+                    let n = node.node();
+                    if i >= n.width() {
+                        IterStep::Pop
+                    }
+                    else if n.node_type == NodeType::Leaf {
+                        IterStep::Yield(n.keys[i].clone(), n.values[i].clone())
+                    }
+                    else if let Some(c) = prefetched.pop_front() {
+                        IterStep::DescendPrefetched(c)
+                    }
+                    else {
+                        match *node {
+                            IterNode::Borrowed(b) => IterStep::DescendRef(&b.children[i]),
+                            // A node loaded just for us came from the backend, so its children can't be loaded and their offsets are right.
+                            IterNode::Owned(ref o) => IterStep::DescendOffset(o.children[i].offset),
+                        }
+                    }
+                }
+            };
+            let child = match step {
+                IterStep::Pop => {
+                    self.stack.pop();
+                    continue;
+                },
+                IterStep::Yield(k, v) => {
+                    if self.past_upper(&k) {
+                        self.stack.clear();
+                        return None;
+                    }
+                    return Some(Ok((k, v)));
+                },
+                IterStep::DescendRef(r) => IterNode::resolve(&self.backend, r),
+                IterStep::DescendOffset(o) => load(&self.backend, o).map(|n| IterNode::Owned(Box::new(n))),
+                IterStep::DescendPrefetched(c) => Ok(c),
+            };
+            match child.and_then(|c| self.fill(&c, 0).map(|p| (c, p))) {
+                Ok((c, p)) => self.stack.push((c, 0, p)),
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+
+/// An iterator over the keys and values of a `BPTree`, in descending order.
+///
+/// After returning an error, the iterator is exhausted.
+pub struct RevIter<'a, K: 'a, V: 'a, B: 'a> {
+    backend: Metered<'a, B>,
+    // Each entry is a node and the number of its children which haven't been visited yet.
+    stack: Vec<(IterNode<'a, K, V>, usize)>,
+    root: Option<&'a NodeRef<K, V>>,
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, B: StorageBackend> RevIter<'a, K, V, B> {
+    fn push(&mut self, node: Result<IterNode<'a, K, V>, DecodingError>) -> Result<(), DecodingError> {
+        let n = node?;
+        let len = n.node().width();
+        self.stack.push((n, len));
+        Ok(())
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, B: StorageBackend> Iterator for RevIter<'a, K, V, B> {
+    type Item = Result<(K, V), DecodingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            let r = IterNode::resolve(&self.backend, root);
+            if let Err(e) = self.push(r) {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let step = match self.stack.last_mut() {
+                None => return None,
+                Some(&mut (ref node, ref mut remaining)) => {
+                    let n = node.node();
+                    if *remaining == 0 {
+                        IterStep::Pop
+                    }
+                    else {
+                        *remaining -= 1;
+                        let i = *remaining;
+                        if n.node_type == NodeType::Leaf {
+                            IterStep::Yield(n.keys[i].clone(), n.values[i].clone())
+                        }
+                        else {
+                            match *node {
+                                IterNode::Borrowed(b) => IterStep::DescendRef(&b.children[i]),
+                                IterNode::Owned(ref o) => IterStep::DescendOffset(o.children[i].offset),
+                            }
+                        }
+                    }
+                }
+            };
+            let child = match step {
+                IterStep::Pop => {
+                    self.stack.pop();
+                    continue;
+                },
+                IterStep::Yield(k, v) => return Some(Ok((k, v))),
+                IterStep::DescendRef(r) => IterNode::resolve(&self.backend, r),
+                IterStep::DescendOffset(o) => load(&self.backend, o).map(|n| IterNode::Owned(Box::new(n))),
+                IterStep::DescendPrefetched(c) => Ok(c),
+            };
+            if let Err(e) = self.push(child) {
+                self.stack.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// An iterator which consumes a `BPTree`, from `BPTree::into_iter`.
+///
+/// Nodes are dropped as soon as the iterator is done with them, as is everything left when the iterator is.
+/// After returning an error, the iterator is exhausted.
+pub struct IntoIter<'a, K, V, B: 'a> {
+    backend: Metered<'a, B>,
+    // The children not yet visited of each internal node on the path from the root.
+    stack: Vec<std::vec::IntoIter<NodeRef<K, V>>>,
+    // What's left of the current leaf.
+    leaf: std::iter::Zip<std::vec::IntoIter<K>, std::vec::IntoIter<V>>,
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, B: StorageBackend> Iterator for IntoIter<'a, K, V, B> {
+    type Item = Result<(K, V), DecodingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.leaf.next() {
+                return Some(Ok(entry));
+            }
+            let child = match self.stack.last_mut() {
+                None => return None,
+                Some(children) => children.next(),
+            };
+            let node = match child {
+                None => {
+                    self.stack.pop();
+                    continue;
+                },
+                Some(c) => c.into_box(&self.backend),
+            };
+            match node {
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
+                },
+                Ok(n) => {
+                    let n = *n;
+                    if n.node_type == NodeType::Leaf {
+                        self.leaf = n.keys.into_iter().zip(n.values);
+                    }
+                    else {
+                        self.stack.push(n.children.into_iter());
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// A position among the keys of a `BPTree`, from `BPTree::cursor_at`.
+///
+/// The cursor is either at an entry or off the end, which is both past the last entry and before the first, so moving forward from off the end goes to the first entry and moving back goes to the last.
+/// Like the iterators, it keeps the path from the root to its entry, loading nodes which aren't in memory as it reaches them and dropping them once it moves off them.
+/// It only needs the backend while moving.
+/// After an error, the cursor is off the end.
+pub struct Cursor<'a, K: 'a, V: 'a, C: 'a = NaturalOrder> {
+    root: &'a NodeRef<K, V>,
+    // Each node on the path to the current entry, and the index of the child we went down or, in the leaf, of the entry.
+    // Empty when off the end.
+    stack: Vec<(IterNode<'a, K, V>, usize)>,
+    comparator: &'a C,
+    metrics: Option<sync::Arc<Metrics>>,
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, C: Comparator<K>> Cursor<'a, K, V, C> {
+    // Position at the ceiling of the key.
+    fn seek<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, key: &K) -> Result<(), DecodingError> {
+        let mut node = IterNode::resolve(backend, self.root)?;
+        while node.node().node_type != NodeType::Leaf {
+            let index = node.node().index_of(self.comparator, key);
+            let child = node.child(backend, index)?;
+            self.stack.push((node, index));
+            node = child;
+        }
+        let width = node.node().width();
+        let index = match self.comparator.search(&node.node().keys, key) {
+            Ok(i) | Err(i) => i,
+        };
+        if width == 0 {
+            // Only an empty root can be an empty leaf.
+            if !self.stack.is_empty() {
+                self.stack.clear();
+                return Err(DecodingError::Corrupt(format!("Empty {:?} node below the root", node.node().node_type)));
+            }
+        }
+        else if index == width {
+            // Everything in this leaf is below the key, so the ceiling is the first entry of the next one.
+            self.stack.push((node, width-1));
+            self.step(backend, true)?;
+        }
+        else {
+            self.stack.push((node, index));
+        }
+        Ok(())
+    }
+
+    // From the node on top of the stack, go down the first or last children to a leaf.
+    fn descend<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, last: bool) -> Result<(), DecodingError> {
+        loop {
+            let child = {
+                let &(ref node, index) = self.stack.last().expect("Descending from an empty path.");
+                if node.node().node_type == NodeType::Leaf {
+                    return Ok(());
+                }
+                node.child(backend, index)?
+            };
+            let width = child.node().width();
+            // Only the root is ever empty, so an empty child means the file is damaged rather than that there's nothing here.
+            if width == 0 {
+                return Err(DecodingError::Corrupt(format!("Empty {:?} node below the root", child.node().node_type)));
+            }
+            let index = if last { width-1 } else { 0 };
+            self.stack.push((child, index));
+        }
+    }
+
+    // Move one entry forward or back, returning whether the cursor is still at one.
+    fn step<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, forward: bool) -> Result<bool, DecodingError> {
+        if self.stack.is_empty() {
+            let root = IterNode::resolve(backend, self.root)?;
+            let width = root.node().width();
+            if width == 0 {
+                return Ok(false);
+            }
+            self.stack.push((root, if forward { 0 } else { width-1 }));
+        }
+        else {
+            // Go up until there's somewhere to go sideways.
+            loop {
+                match self.stack.last_mut() {
+                    None => return Ok(false),
+                    Some(&mut (ref node, ref mut index)) => {
+                        if forward && *index+1 < node.node().width() {
+                            *index += 1;
+                            break;
+                        }
+                        if !forward && *index > 0 {
+                            *index -= 1;
+                            break;
+                        }
+                    },
+                }
+                self.stack.pop();
+            }
+        }
+        self.descend(backend, !forward)?;
+        Ok(true)
+    }
+
+    /// Move to the next entry, returning whether there is one.
+    pub fn next<B: StorageBackend>(&mut self, backend: &B) -> Result<bool, DecodingError> {
+        let backend = &Metered::new(backend, self.metrics.clone());
+        let moved = self.step(backend, true);
+        if moved.is_err() {
+            self.stack.clear();
+        }
+        moved
+    }
+
+    /// Move to the previous entry, returning whether there is one.
+    pub fn prev<B: StorageBackend>(&mut self, backend: &B) -> Result<bool, DecodingError> {
+        let backend = &Metered::new(backend, self.metrics.clone());
+        let moved = self.step(backend, false);
+        if moved.is_err() {
+            self.stack.clear();
+        }
+        moved
+    }
+
+    /// The key of the current entry, or `None` if the cursor is off the end.
+    pub fn key(&self) -> Option<&K> {
+        self.stack.last().map(|&(ref node, index)| &node.node().keys[index])
+    }
+
+    /// The value of the current entry, or `None` if the cursor is off the end.
+    pub fn value(&self) -> Option<&V> {
+        self.stack.last().map(|&(ref node, index)| &node.node().values[index])
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, C: Comparator<K>> Cursor<'a, K, u64, C> {
+    /// The offset of the current entry, or `None` if the cursor is off the end.
+    pub fn offset(&self) -> Option<u64> {
+        self.value().cloned()
+    }
+}
+
+/// A cursor which can change values in place, from `BPTree::cursor_mut_at`.
+///
+/// It moves like `Cursor`, but holds the tree for as long as it lives.
+/// The nodes on the path to the current entry are detached from the tree while the cursor is on them, as for an insertion, and go back when it moves off them or is dropped, so changing a value doesn't search for the entry again.
+/// After an error, the cursor is off the end.
+pub struct CursorMut<'a, K: 'a, V: 'a, C: 'a = NaturalOrder> {
+    tree: &'a mut BPTree<K, V, C>,
+    // The root, then each node down to the current leaf with its index in its parent.
+    // Empty when off the end.
+    path: Vec<(usize, Box<Node<K, V>>)>,
+    // The current entry in the leaf.
+    index: usize,
+}
+
+impl<'a, K, V, C> CursorMut<'a, K, V, C> {
+    // Put the bottom node back in its parent, or the root back in the tree.
+    fn pop(&mut self) -> Option<usize> {
+        let (index, child) = self.path.pop()?;
+        match self.path.last_mut() {
+            Some(&mut (_, ref mut parent)) => {
+                parent.modified |= child.modified;
+                parent.children[index] = NodeRef::from_boxed_node(child);
+            },
+            None => self.tree.root_reference = NodeRef::from_boxed_node(child),
+        }
+        Some(index)
+    }
+
+    fn reattach(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, C: Comparator<K>> CursorMut<'a, K, V, C> {
+    fn push_child<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, index: usize) -> Result<(), DecodingError> {
+        let child = match self.path.last_mut() {
+            Some(&mut (_, ref mut parent)) => {
+                parent.children[index].load(backend)?;
+                parent.children[index].node.take().map(unshare).expect("The child was just loaded.")
+            },
+            None => {
+                // Load it first, so that we don't replace the root with garbage on error.
+                self.tree.root_reference.load(backend)?;
+                std::mem::replace(&mut self.tree.root_reference, NodeRef::from_offset(0, 0)).into_box(backend)?
+            },
+        };
+        self.path.push((index, child));
+        Ok(())
+    }
+
+    fn bottom(&self) -> &Node<K, V> {
+        &self.path.last().expect("The cursor is at an entry.").1
+    }
+
+    // Detach the path down to the leaf the key belongs in, and point at where it is or would go.
+    fn descend<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, key: &K) -> Result<bool, DecodingError> {
+        self.push_child(backend, 0)?;
+        while self.bottom().node_type != NodeType::Leaf {
+            let index = self.bottom().index_of(&self.tree.comparator, key);
+            self.push_child(backend, index)?;
+        }
+        let found = self.tree.comparator.search(&self.bottom().keys, key);
+        self.index = match found {
+            Ok(i) | Err(i) => i,
+        };
+        Ok(found.is_ok())
+    }
+
+    // Position at the ceiling of the key.
+    fn seek<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, key: &K) -> Result<(), DecodingError> {
+        self.descend(backend, key)?;
+        let width = self.bottom().width();
+        if width == 0 {
+            // As for `Cursor::seek`, the root is the only node which can be empty.
+            if self.path.len() > 1 {
+                return Err(DecodingError::Corrupt(format!("Empty {:?} node below the root", self.bottom().node_type)));
+            }
+            self.reattach();
+        }
+        else if self.index == width {
+            // Everything in this leaf is below the key, so the ceiling is the first entry of the next one.
+            self.index = width-1;
+            self.step(backend, true)?;
+        }
+        Ok(())
+    }
+
+    // Move one entry forward or back, returning whether the cursor is still at one.
+    fn step<B: StorageBackend>(&mut self, backend: &Metered<'_, B>, forward: bool) -> Result<bool, DecodingError> {
+        if self.path.is_empty() {
+            self.push_child(backend, 0)?;
+            if self.bottom().width() == 0 {
+                self.reattach();
+                return Ok(false);
+            }
+        }
+        else {
+            let width = self.bottom().width();
+            if forward && self.index+1 < width {
+                self.index += 1;
+                return Ok(true);
+            }
+            if !forward && self.index > 0 {
+                self.index -= 1;
+                return Ok(true);
+            }
+            // Go up until there's somewhere to go sideways.
+            let next = loop {
+                let index = self.pop().expect("The cursor is at an entry.");
+                if self.path.is_empty() {
+                    return Ok(false);
+                }
+                if forward && index+1 < self.bottom().width() {
+                    break index+1;
+                }
+                if !forward && index > 0 {
+                    break index-1;
+                }
+            };
+            self.push_child(backend, next)?;
+        }
+        loop {
+            let width = self.bottom().width();
+            // As for `Cursor`, only the root is ever empty.
+            if width == 0 {
+                return Err(DecodingError::Corrupt(format!("Empty {:?} node below the root", self.bottom().node_type)));
+            }
+            let index = if forward { 0 } else { width-1 };
+            if self.bottom().node_type == NodeType::Leaf {
+                self.index = index;
+                return Ok(true);
+            }
+            self.push_child(backend, index)?;
+        }
+    }
+
+    /// Move to the next entry, returning whether there is one.
+    pub fn next<B: StorageBackend>(&mut self, backend: &B) -> Result<bool, DecodingError> {
+        let backend = &self.tree.metered(backend);
+        let moved = self.step(backend, true);
+        if moved.is_err() {
+            self.reattach();
+        }
+        moved
+    }
+
+    /// Move to the previous entry, returning whether there is one.
+    pub fn prev<B: StorageBackend>(&mut self, backend: &B) -> Result<bool, DecodingError> {
+        let backend = &self.tree.metered(backend);
+        let moved = self.step(backend, false);
+        if moved.is_err() {
+            self.reattach();
+        }
+        moved
+    }
+
+    /// The key of the current entry, or `None` if the cursor is off the end.
+    pub fn key(&self) -> Option<&K> {
+        self.path.last().map(|(_, node)| &node.keys[self.index])
+    }
+
+    /// The value of the current entry, or `None` if the cursor is off the end.
+    pub fn value(&self) -> Option<&V> {
+        self.path.last().map(|(_, node)| &node.values[self.index])
+    }
+
+    // The current value, marking the leaf modified.
+    fn value_mut(&mut self) -> &mut V {
+        let index = self.index;
+        let leaf = &mut self.path.last_mut().expect("The cursor is at an entry.").1;
+        leaf.modified = true;
+        &mut leaf.values[index]
+    }
+
+    // Put a key which isn't in the leaf at the current index, then reattach the path, splitting whatever is overfull on the way up as `insert` does.
+    fn insert_here(&mut self, key: K, value: V) where K: serde::Serialize, V: serde::Serialize {
+        let tree = &mut *self.tree;
+        let mut split = {
+            let leaf = &mut self.path.last_mut().expect("The cursor is in a leaf.").1;
+            leaf.keys.insert(self.index, key);
+            leaf.values.insert(self.index, value);
+            leaf.modified = true;
+            if tree.policy.overfull(leaf) { Some(leaf.split_in_place(&tree.comparator)) } else { None }
+        };
+        tree.len += 1;
+        while let Some((index, child)) = self.path.pop() {
+            match self.path.last_mut() {
+                Some(&mut (_, ref mut parent)) => {
+                    parent.modified |= child.modified;
+                    parent.children[index] = NodeRef::from_boxed_node(child);
+                    if let Some((k, n)) = split.take() {
+                        parent.keys.insert(index, k);
+                        parent.children.insert(index+1, NodeRef::from_boxed_node(n));
+                        if tree.policy.overfull(parent) {
+                            split = Some(parent.split_in_place(&tree.comparator));
+                        }
+                    }
+                },
+                None => {
+                    tree.root_reference = match split.take() {
+                        Some((k, n)) => NodeRef::from_boxed_node(Box::new(Node::new(NodeType::Root, vec![k], vec![NodeRef::from_boxed_node(child), NodeRef::from_boxed_node(n)], vec![]))),
+                        None => NodeRef::from_boxed_node(child),
+                    };
+                },
+            }
+        }
+    }
+
+    /// Replace the value of the current entry, returning the old one.
+    ///
+    /// Off the end, there's no entry to change; this returns `None` and drops the value, leaving the tree as it was.
+    /// Unless the value is the one already there, the leaf is marked modified and goes out with the next commit.
+    pub fn set_value(&mut self, value: V) -> Option<V> where V: PartialEq {
+        let index = self.index;
+        self.path.last_mut().map(|&mut (_, ref mut node)| {
+            if node.values[index] != value {
+                node.modified = true;
+            }
+            std::mem::replace(&mut node.values[index], value)
+        })
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, C: Comparator<K>> CursorMut<'a, K, u64, C> {
+    /// The offset of the current entry, or `None` if the cursor is off the end.
+    pub fn offset(&self) -> Option<u64> {
+        self.value().cloned()
+    }
+
+    /// Replace the offset of the current entry, returning the old one, or `None` without changing anything if the cursor is off the end.
+    pub fn set_offset(&mut self, offset: u64) -> Option<u64> {
+        self.set_value(offset)
+    }
+}
+
+impl<'a, K, V, C> Drop for CursorMut<'a, K, V, C> {
+    fn drop(&mut self) {
+        self.reattach();
+    }
+}
+
+/// A key's place in a tree, from `BPTree::entry`.
+pub enum Entry<'a, K: 'a, V: 'a, C: 'a = NaturalOrder> {
+    Occupied(OccupiedEntry<'a, K, V, C>),
+    Vacant(VacantEntry<'a, K, V, C>),
+}
+
+/// An entry for a key which is in the tree.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, C: 'a = NaturalOrder> {
+    // At the key.
+    cursor: CursorMut<'a, K, V, C>,
+}
+
+/// An entry for a key which isn't in the tree.
+pub struct VacantEntry<'a, K: 'a, V: 'a, C: 'a = NaturalOrder> {
+    // In the leaf the key belongs in, at the index it would have.
+    cursor: CursorMut<'a, K, V, C>,
+    key: K,
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, C: Comparator<K>> Entry<'a, K, V, C> {
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref o) => o.key(),
+            Entry::Vacant(ref v) => v.key(),
+        }
+    }
+
+    /// The value at the key, after inserting `value` if the key was vacant.
+    pub fn or_insert(self, value: V) -> V where K: serde::Serialize, V: serde::Serialize {
+        self.or_insert_with(|| value)
+    }
+
+    /// The value at the key, after inserting the one from `f` if the key was vacant.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> V where K: serde::Serialize, V: serde::Serialize {
+        match self {
+            Entry::Occupied(o) => o.get().clone(),
+            Entry::Vacant(v) => {
+                let value = f();
+                v.insert(value.clone());
+                value
+            },
+        }
+    }
+
+    /// Change the value in place if the key is occupied.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Entry<'a, K, V, C> {
+        match self {
+            Entry::Occupied(mut o) => {
+                f(o.get_mut());
+                Entry::Occupied(o)
+            },
+            e => e,
+        }
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, C: Comparator<K>> OccupiedEntry<'a, K, V, C> {
+    pub fn key(&self) -> &K {
+        self.cursor.key().expect("The entry is at its key.")
+    }
+
+    pub fn get(&self) -> &V {
+        self.cursor.value().expect("The entry is at its key.")
+    }
+
+    /// The value, for changing in place. The leaf is marked modified whether or not it changes.
+    pub fn get_mut(&mut self) -> &mut V {
+        self.cursor.value_mut()
+    }
+
+    /// Replace the value, returning the old one.
+    ///
+    /// As with `BPTree::insert`, replacing the value with an equal one doesn't count as a modification.
+    pub fn insert(&mut self, value: V) -> V where V: PartialEq {
+        self.cursor.set_value(value).expect("The entry is at its key.")
+    }
+}
+
+impl<'a, K: serde::de::DeserializeOwned+Clone, V: serde::de::DeserializeOwned+Clone, C: Comparator<K>> VacantEntry<'a, K, V, C> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Insert the key with the given value.
+    pub fn insert(self, value: V) where K: serde::Serialize, V: serde::Serialize {
+        let VacantEntry { mut cursor, key } = self;
+        cursor.insert_here(key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trees which are never committed never touch their backend.
+    struct NullBackend;
+
+    impl StorageBackend for NullBackend {
+        fn load<V: serde::de::DeserializeOwned>(&self, _key: u64) -> Result<V, DecodingError> {
+            unreachable!("In-memory trees shouldn't load anything.")
+        }
+
+        fn store<V: serde::Serialize>(&mut self, _value: &V) -> Result<u64, EncodingError> {
+            unreachable!("In-memory trees shouldn't store anything.")
+        }
+
+        fn store_header<V: serde::Serialize>(&mut self, _value: &V) -> Result<(), EncodingError> {
+            unreachable!("In-memory trees shouldn't store anything.")
+        }
+
+        fn load_header<V: serde::de::DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+            unreachable!("In-memory trees shouldn't load anything.")
+        }
+    }
+
+    // For reaching into nodes directly.
+    const NULL: Metered<'static, NullBackend> = Metered { backend: &NullBackend, metrics: None };
+
+    fn height(tree: &OffsetTree<u64>) -> usize {
+        let mut node = tree.root_reference.get(&NULL).unwrap();
+        let mut height = 1;
+        while node.node_type != NodeType::Leaf {
+            node = node.children[0].get(&NULL).unwrap();
+            height += 1;
+        }
+        height
+    }
+
+    // Returns the depth of the leaves under this node.
+    fn check_node(node: &Node<u64, u64>, policy: &SplitPolicy, is_root: bool, lower: Option<u64>, upper: Option<u64>) -> usize {
+        assert!(node.keys.windows(2).all(|w| w[0] < w[1]), "Keys out of order.");
+        if let Some(l) = lower {
+            assert!(node.keys.iter().all(|k| *k > l), "Key below the parent's separator.");
+        }
+        if let Some(u) = upper {
+            assert!(node.keys.iter().all(|k| *k <= u), "Key above the parent's separator.");
+        }
+        if is_root {
+            assert!(node.node_type != NodeType::Internal);
+        }
+        else {
+            assert!(node.node_type != NodeType::Root);
+            assert!(node.width() >= policy.min_children(node.node_type), "Underfull node.");
+        }
+        assert!(!policy.overfull(node), "Overfull node.");
+        if node.node_type == NodeType::Leaf {
+            assert_eq!(node.keys.len(), node.values.len());
+            assert!(node.children.is_empty());
+            return 1;
+        }
+        assert_eq!(node.keys.len()+1, node.children.len());
+        assert!(node.values.is_empty());
+        let depths = node.children.iter().enumerate().map(|(i, c)| {
+            let l = if i == 0 { lower } else { Some(node.keys[i-1]) };
+            let u = if i == node.keys.len() { upper } else { Some(node.keys[i]) };
+            check_node(c.get(&NULL).unwrap(), policy, false, l, u)
+        }).collect::<Vec<_>>();
+        assert!(depths.iter().all(|d| *d == depths[0]), "Leaves at different depths.");
+        depths[0]+1
+    }
+
+    fn check(tree: &OffsetTree<u64>) {
+        check_node(tree.root_reference.get(&NULL).unwrap(), &tree.policy, true, None, None);
+        tree.validate(&NullBackend).unwrap();
+    }
+
+    fn build(order: u64, count: u64) -> OffsetTree<u64> {
+        let mut tree = OffsetTree::empty(order);
+        for i in 0..count {
+            tree.insert(&NullBackend, &i, i).unwrap();
+        }
+        check(&tree);
+        tree
+    }
+
+    #[test]
+    fn test_merges_to_root() {
+        let count = 2000;
+        let mut tree = build(7, count);
+        let mut last_height = height(&tree);
+        assert!(last_height > 3);
+        // Removing from the left keeps merging the leftmost nodes, all the way up.
+        for i in 0..count {
+            assert_eq!(tree.remove(&NullBackend, &i).unwrap(), Some(i));
+            check(&tree);
+            let h = height(&tree);
+            assert!(h <= last_height);
+            last_height = h;
+        }
+        assert_eq!(last_height, 1);
+        assert!(tree.root_reference.get(&NULL).unwrap().keys.is_empty());
+    }
+
+    #[test]
+    fn test_merges_from_right() {
+        let count = 2000;
+        let mut tree = build(8, count);
+        for i in (0..count).rev() {
+            assert_eq!(tree.remove(&NullBackend, &i).unwrap(), Some(i));
+            check(&tree);
+        }
+        assert_eq!(height(&tree), 1);
+    }
+
+    fn leaf_keys(tree: &OffsetTree<u64>) -> Vec<Vec<u64>> {
+        let root = tree.root_reference.get(&NULL).unwrap();
+        root.children.iter().map(|c| c.get(&NULL).unwrap().keys.clone()).collect()
+    }
+
+    #[test]
+    fn test_borrow_from_right() {
+        let mut tree = build(7, 8);
+        assert_eq!(leaf_keys(&tree), vec![vec![0, 1], vec![2, 3], vec![4, 5, 6, 7]]);
+        // The left sibling is minimal, so this has to come from the right.
+        tree.remove(&NullBackend, &2).unwrap();
+        check(&tree);
+        assert_eq!(leaf_keys(&tree), vec![vec![0, 1], vec![3, 4], vec![5, 6, 7]]);
+        assert_eq!(tree.root_reference.get(&NULL).unwrap().keys, vec![1, 4]);
+    }
+
+    #[test]
+    fn test_borrow_from_left() {
+        let mut tree = build(7, 8);
+        // Neither side can spare anything for the leftmost leaf, so it merges.
+        tree.remove(&NullBackend, &0).unwrap();
+        assert_eq!(leaf_keys(&tree), vec![vec![1, 2, 3], vec![4, 5, 6, 7]]);
+        tree.remove(&NullBackend, &4).unwrap();
+        tree.remove(&NullBackend, &5).unwrap();
+        tree.remove(&NullBackend, &6).unwrap();
+        check(&tree);
+        assert_eq!(leaf_keys(&tree), vec![vec![1, 2], vec![3, 7]]);
+        assert_eq!(tree.root_reference.get(&NULL).unwrap().keys, vec![2]);
+    }
+
+    #[test]
+    fn test_split_occupancy() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([75, 75, 75, 75]);
+        for order in MIN_ORDER..=16 {
+            let mut shuffled = (0..500).collect::<Vec<u64>>();
+            rng.shuffle(&mut shuffled);
+            for keys in [(0..500).collect::<Vec<u64>>(), (0..500).rev().collect(), shuffled] {
+                let mut tree = OffsetTree::empty(order);
+                for k in keys {
+                    tree.insert(&NullBackend, &k, k).unwrap();
+                    check(&tree);
+                }
+                assert!(height(&tree) > 2, "Order {} didn't split internal nodes.", order);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_removal() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([3, 3, 3, 3]);
+        for &order in &[7, 8, 9, 12] {
+            let count = 3000;
+            let mut tree = build(order, count);
+            let mut keys = (0..count).collect::<Vec<_>>();
+            rng.shuffle(&mut keys);
+            for k in keys.iter() {
+                assert_eq!(tree.remove(&NullBackend, k).unwrap(), Some(*k));
+                check(&tree);
+            }
+            assert_eq!(height(&tree), 1);
+        }
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([4, 4, 4, 4]);
+        let count = 3000;
+        let mut keys = (0..count).collect::<Vec<_>>();
+        rng.shuffle(&mut keys);
+        let mut tree = OffsetTree::empty(7).with_split_policy(SplitPolicy::Bytes(300));
+        for k in keys.iter() {
+            tree.insert(&NullBackend, k, *k).unwrap();
+        }
+        check(&tree);
+        // A leaf's entries take 16 bytes each, so the budget makes leaves much wider than order 7 would.
+        let stats = tree.stats(&NullBackend).unwrap();
+        assert!(stats.keys/stats.leaves > 8);
+        rng.shuffle(&mut keys);
+        for k in keys.iter() {
+            assert_eq!(tree.remove(&NullBackend, k).unwrap(), Some(*k));
+            check(&tree);
+        }
+        assert_eq!(height(&tree), 1);
+    }
+
+    #[test]
+    fn test_change_split_policy() {
+        let mut tree = build(7, 500).with_split_policy(SplitPolicy::Bytes(300));
+        for k in 500..1000 {
+            tree.insert(&NullBackend, &k, k).unwrap();
+        }
+        for k in 0..1000 {
+            assert_eq!(tree.remove(&NullBackend, &k).unwrap(), Some(k));
+        }
+        let mut tree = tree.with_split_policy(SplitPolicy::Order(7));
+        for k in 0..1000 {
+            tree.insert(&NullBackend, &k, k).unwrap();
+        }
+        check(&tree);
+    }
+
+    #[test]
+    fn test_separate_orders() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([84, 84, 84, 84]);
+        for &(leaf, internal) in &[(32, 7), (7, 16)] {
+            let policy = SplitPolicy::Orders { leaf, internal };
+            let mut tree = OffsetTree::empty(MIN_ORDER).with_split_policy(policy);
+            let mut keys = (0..3000).collect::<Vec<u64>>();
+            rng.shuffle(&mut keys);
+            for k in keys.iter() {
+                tree.insert(&NullBackend, k, *k).unwrap();
+            }
+            check(&tree);
+            // Each kind of node fills up to its own threshold, and no further.
+            let mut widest = (0, 0);
+            let mut stack = vec![tree.root_reference.get(&NULL).unwrap()];
+            while let Some(node) = stack.pop() {
+                if node.node_type == NodeType::Leaf {
+                    widest.0 = std::cmp::max(widest.0, node.width());
+                }
+                else {
+                    widest.1 = std::cmp::max(widest.1, node.width());
+                    stack.extend(node.children.iter().map(|c| c.get(&NULL).unwrap()));
+                }
+            }
+            assert_eq!(widest, (split_threshold(leaf), split_threshold(internal)));
+            for k in keys.iter().take(2900) {
+                assert_eq!(tree.remove(&NullBackend, k).unwrap(), Some(*k));
+            }
+            check(&tree);
+            // Packing a tree uses both widths as well.
+            let odd = keys[2900..].iter().filter(|k| *k%2 == 1).count() as u64;
+            assert_eq!(tree.retain(&NullBackend, |k, _| k%2 == 0).unwrap(), odd);
+            check(&tree);
+        }
+    }
+
+    #[test]
+    fn test_front_coding() {
+        let strings = vec!["".to_string(), "apple".to_string(), "application".to_string(), "applications".to_string(), "banana".to_string(), "ü".to_string()];
+        let coded = front_code(&strings).unwrap().unwrap();
+        assert_eq!(coded[2], (4, b"ication".to_vec()));
+        assert_eq!(front_decode::<String>(coded).unwrap(), strings);
+        let prefix = vec![7u8; 70000];
+        let bytes = vec![prefix.clone(), [prefix.clone(), vec![1]].concat(), [prefix, vec![2, 3]].concat()];
+        let coded = front_code(&bytes).unwrap().unwrap();
+        assert_eq!(coded[1].0, u16::MAX);
+        assert_eq!(front_decode::<Vec<u8>>(coded).unwrap(), bytes);
+        // Nothing to gain.
+        assert!(front_code(&["a", "b", "c"]).unwrap().is_none());
+        // Not byte strings.
+        assert!(front_code(&[1u64, 2, 3]).unwrap().is_none());
+        assert!(front_code(&[("aaaaaaaaaaaa".to_string(), 1u8), ("aaaaaaaaaaab".to_string(), 2u8)]).unwrap().is_none());
+        assert!(front_decode::<String>(vec![(0, b"a".to_vec()), (2, b"b".to_vec())]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_nodes() {
+        use storage_backend::MemoryBackend;
+        let mut backend = MemoryBackend::new();
+        let disk_node = |node_type, keys: Vec<u64>, children: Vec<u64>, values: Vec<u64>| DiskNode { version: FORMAT_VERSION, node_type, keys, counts: vec![1; children.len()], children, values, front_coded: vec![] };
+        let short_leaf = backend.store(&disk_node(NodeType::Leaf, vec![1, 2], vec![], vec![1])).unwrap();
+        let leaf_with_children = backend.store(&disk_node(NodeType::Leaf, vec![1], vec![short_leaf], vec![1])).unwrap();
+        let childless = backend.store(&disk_node(NodeType::Root, vec![], vec![], vec![])).unwrap();
+        let future = backend.store(&DiskNode { version: FORMAT_VERSION+1, ..disk_node(NodeType::Leaf, vec![1], vec![], vec![1]) }).unwrap();
+        match OffsetTree::<u64>::open(&backend, future, 7, 1) {
+            Err(DecodingError::Corrupt(ref m)) if *m == format!("unsupported version {}", FORMAT_VERSION+1) => {},
+            _ => panic!("Opened a node from a future version."),
+        }
+        for &o in &[short_leaf, leaf_with_children, childless] {
+            match OffsetTree::<u64>::open(&backend, o, 7, 1) {
+                Err(DecodingError::Corrupt(_)) => {},
+                _ => panic!("Opened a malformed node."),
+            }
+        }
+        // Internal nodes which are too short are only found when something goes down to them.
+        let good_leaf = backend.store(&disk_node(NodeType::Leaf, vec![1, 2], vec![], vec![1, 2])).unwrap();
+        let short_internal = backend.store(&disk_node(NodeType::Internal, vec![5, 10], vec![good_leaf, good_leaf], vec![])).unwrap();
+        let root = backend.store(&disk_node(NodeType::Root, vec![2], vec![good_leaf, short_internal], vec![])).unwrap();
+        let mut tree = OffsetTree::<u64>::open(&backend, root, 7, 4).unwrap();
+        assert_eq!(tree.offset_for(&backend, &1).unwrap(), Some(1));
+        assert!(tree.offset_for(&backend, &20).is_err());
+        assert!(tree.insert(&backend, &20, 20).is_err());
+        assert!(tree.iter(&backend).any(|r| r.is_err()));
+        assert!(tree.iter_rev(&backend).any(|r| r.is_err()));
+        // A failed insert leaves the tree as it was.
+        assert_eq!(tree.offset_for(&backend, &2).unwrap(), Some(2));
+        // An empty leaf below the root is wrong, but lookups which reach it just don't find anything.
+        let empty_leaf = backend.store(&disk_node(NodeType::Leaf, vec![], vec![], vec![])).unwrap();
+        let root = backend.store(&disk_node(NodeType::Root, vec![2], vec![good_leaf, empty_leaf], vec![])).unwrap();
+        let tree = OffsetTree::<u64>::open(&backend, root, 7, 2).unwrap();
+        assert_eq!(tree.offset_for(&backend, &5).unwrap(), None);
+        assert_eq!(tree.ceiling(&backend, &5).unwrap(), None);
+        assert_eq!(tree.floor(&backend, &5).unwrap(), Some((2, 2)));
+        assert!(tree.validate(&backend).is_err());
+        // Cursors which go down into it have no entry to stop at, in either direction.
+        let mut cursor = tree.cursor_at(&backend, &2).unwrap();
+        assert!(cursor.next(&backend).is_err());
+        assert_eq!(cursor.key(), None);
+        assert!(cursor.prev(&backend).is_err());
+        // Seeking a cursor into it fails the same way.
+        assert!(tree.cursor_at(&backend, &5).is_err());
+        let mut tree = OffsetTree::<u64>::open(&backend, root, 7, 2).unwrap();
+        let mut cursor = tree.cursor_mut_at(&backend, &2).unwrap();
+        assert!(cursor.next(&backend).is_err());
+        assert_eq!(cursor.key(), None);
+        assert!(cursor.prev(&backend).is_err());
+        drop(cursor);
+        assert!(tree.cursor_mut_at(&backend, &5).is_err());
+        // The failed seek put the tree back together.
+        assert_eq!(tree.offset_for(&backend, &2).unwrap(), Some(2));
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn test_build_sorted() {
+        for &order in &[7, 8, 12] {
+            for count in (0..300).chain(vec![1000, 5000]) {
+                let mut tree = OffsetTree::build_sorted((0..count).map(|i| (i, i)), order).unwrap();
+                assert_eq!(tree.len(), count);
+                check(&tree);
+                tree.insert(&NullBackend, &count, count).unwrap();
+                tree.remove(&NullBackend, &0).unwrap();
+                check(&tree);
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        use storage_backend::MemoryBackend;
+        for &order in &[7, 8, 16] {
+            for count in (0..200).chain(vec![1000, 5000]) {
+                let mut backend = MemoryBackend::new();
+                let header = {
+                    let mut builder = BPTreeBuilder::new(&mut backend, order);
+                    builder.extend((0..count).map(|i| (i*2, i))).unwrap();
+                    builder.finish().unwrap()
+                };
+                let tree = OffsetTree::<u64>::from_header(&backend, order).unwrap().unwrap();
+                assert_eq!(header.len, count);
+                // Validating loads every node, which is what checking the occupancy needs.
+                tree.validate(&backend).unwrap();
+                check(&tree);
+                assert_eq!(tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), (0..count).map(|i| (i*2, i)).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn test_contains_doesnt_clone() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Unclonable(u64);
+        impl Clone for Unclonable {
+            fn clone(&self) -> Unclonable {
+                panic!("Cloned a value.");
+            }
+        }
+        let mut tree = BPTree::empty(7);
+        for i in 0..500u64 {
+            tree.insert(&NullBackend, &(i*2), Unclonable(i)).unwrap();
+        }
+        for i in 0..1000u64 {
+            assert_eq!(tree.contains(&NullBackend, &i).unwrap(), i%2 == 0);
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        for &policy in &[SplitPolicy::Order(7), SplitPolicy::Order(8), SplitPolicy::Order(12), SplitPolicy::Bytes(300)] {
+            let mut tree = OffsetTree::empty(7).with_split_policy(policy);
+            // Interleaved, so that inserts land all over the tree and split at every level.
+            for i in (0..3000u64).map(|i| (i*7919)%3000) {
+                assert_eq!(tree.entry(&NullBackend, &i).unwrap().or_insert(i), i);
+            }
+            check(&tree);
+            assert_eq!(tree.len(), 3000);
+            for i in 0..3000u64 {
+                assert_eq!(tree.entry(&NullBackend, &i).unwrap().and_modify(|v| *v += 1).or_insert(0), i+1);
+            }
+            assert_eq!(tree.iter(&NullBackend).map(|r| r.unwrap()).collect::<Vec<_>>(), (0..3000).map(|i| (i, i+1)).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_retain() {
+        for &order in &[7, 8, 12] {
+            for &keep in &[1, 2, 3, 50, 999] {
+                let mut tree = build(order, 1000);
+                tree.retain(&NullBackend, |&k, _| k%1000 < keep && k%2 == 0).unwrap();
+                assert_eq!(tree.len(), (0..keep).filter(|k| k%2 == 0).count() as u64);
+                check(&tree);
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        fn problem(tree: &OffsetTree<u64>) -> String {
+            match tree.validate(&NullBackend) {
+                Err(DecodingError::Corrupt(p)) => p,
+                _ => panic!("The tree should be invalid."),
+            }
+        }
+        let mut tree = build(7, 8);
+        tree.root_reference.get_mut(&NULL).unwrap().children[1].get_mut(&NULL).unwrap().keys.swap(0, 1);
+        assert_eq!(problem(&tree), "Node /1: keys 0 and 1 are out of order");
+        let mut tree = build(7, 8);
+        tree.root_reference.get_mut(&NULL).unwrap().keys[0] = 0;
+        assert_eq!(problem(&tree), "Node /0: key 1 is above the separator after this node");
+        let mut tree = build(7, 8);
+        tree.root_reference.get_mut(&NULL).unwrap().children[2].get_mut(&NULL).unwrap().keys[0] = 3;
+        assert_eq!(problem(&tree), "Node /2: key 0 isn't above the separator before this node");
+        let mut tree = build(7, 8);
+        {
+            let leaf = tree.root_reference.get_mut(&NULL).unwrap().children[2].get_mut(&NULL).unwrap();
+            leaf.keys.truncate(1);
+            leaf.values.truncate(1);
+        }
+        assert_eq!(problem(&tree), "Node /2: only 1 children");
+        let mut tree = build(7, 8);
+        tree.len = 9;
+        assert_eq!(problem(&tree), "The tree has 8 keys, but its length is 9");
+        let mut tree = build(7, 100);
+        let leaf = Box::new(Node::new(NodeType::Leaf, vec![1000, 1001], vec![], vec![0, 0]));
+        let last = {
+            let root = tree.root_reference.get_mut(&NULL).unwrap();
+            root.keys.push(999);
+            root.children.push(NodeRef::from_boxed_node(leaf));
+            root.keys.len()
+        };
+        tree.len += 2;
+        assert_eq!(problem(&tree), format!("Node /{}: leaf at depth 1, but the first leaf is at {}", last, height(&tree)-1));
+    }
+
+    #[test]
+    fn test_merge_with() {
+        let mut tree = build(7, 8);
+        let root = tree.root_reference.get_mut(&NULL).unwrap();
+        assert_eq!(root.children.len(), 3);
+        let separator = root.keys.remove(0);
+        let right = root.children.remove(1).into_box(&NULL).unwrap();
+        let left = root.children[0].get_mut(&NULL).unwrap();
+        left.merge_with(&NaturalOrder, *right, separator);
+        assert_eq!(left.keys, (0..4).collect::<Vec<_>>());
+        assert_eq!(left.values, (0..4).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_split_off() {
+        for &order in &[7, 8, 12] {
+            for &at in &[0, 1, 2, 500, 999, 1000, 1998, 1999, 2000, 5000] {
+                let mut tree = build(order, 2000);
+                let other = tree.split_off(&NullBackend, &at).unwrap();
+                check(&tree);
+                check(&other);
+                let below = std::cmp::min(at, 2000);
+                assert_eq!(tree.iter(&NullBackend).map(|r| r.unwrap().0).collect::<Vec<_>>(), (0..below).collect::<Vec<_>>());
+                assert_eq!(other.iter(&NullBackend).map(|r| r.unwrap().0).collect::<Vec<_>>(), (below..2000).collect::<Vec<_>>());
+            }
+        }
+        // Byte-budgeted trees, and cutting between two keys rather than at one.
+        let mut tree = OffsetTree::empty(7).with_split_policy(SplitPolicy::Bytes(300));
+        for i in 0..2000u64 {
+            tree.insert(&NullBackend, &(i*2), i).unwrap();
+        }
+        let mut other = tree.split_off(&NullBackend, &1001).unwrap();
+        check(&tree);
+        check(&other);
+        assert_eq!(tree.len(), 501);
+        assert_eq!(other.len(), 1499);
+        // Both halves keep working as trees.
+        other.insert(&NullBackend, &1001, 0).unwrap();
+        tree.remove(&NullBackend, &1000).unwrap();
+        check(&tree);
+        check(&other);
+    }
+
+    #[test]
+    fn test_drain_range() {
+        for &order in &[7, 8, 12] {
+            for &(lower, upper) in &[(0, 2000), (0, 1), (1, 2), (10, 1990), (500, 1500), (1000, 1001), (1999, 5000), (300, 200)] {
+                let mut tree = build(order, 2000);
+                let drained = tree.drain_range(&NullBackend, lower..upper).unwrap();
+                check(&tree);
+                let upper = std::cmp::max(lower, std::cmp::min(upper, 2000));
+                assert_eq!(drained.into_iter().map(|(k, _)| k).collect::<Vec<_>>(), (lower..upper).collect::<Vec<_>>());
+                assert_eq!(tree.len(), 2000-(upper-lower));
+            }
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let range = |order, keys: std::ops::Range<u64>| {
+            let mut tree = OffsetTree::empty(order);
+            for i in keys {
+                tree.insert(&NullBackend, &i, i).unwrap();
+            }
+            tree
+        };
+        for &order in &[7, 8, 12] {
+            // Every combination of heights, including either side being much shorter or empty.
+            for &split in &[0, 1, 3, 10, 100, 1000, 1900, 1999, 2000] {
+                let mut tree = range(order, 0..split);
+                tree.append(&NullBackend, range(order, split..2000)).unwrap();
+                check(&tree);
+                assert_eq!(tree.len(), 2000);
+                assert_eq!(tree.iter(&NullBackend).map(|r| r.unwrap().0).collect::<Vec<_>>(), (0..2000).collect::<Vec<_>>());
+            }
+        }
+        // Splitting and rejoining gives back the same keys.
+        let mut tree = build(7, 2000);
+        for &at in &[1, 700, 1500] {
+            let other = tree.split_off(&NullBackend, &at).unwrap();
+            tree.append(&NullBackend, other).unwrap();
+            check(&tree);
+            assert_eq!(tree.len(), 2000);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "aren't all above")]
+    fn test_append_out_of_order() {
+        let mut tree = build(7, 100);
+        tree.append(&NullBackend, build(7, 100)).unwrap();
     }
 }
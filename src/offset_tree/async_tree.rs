@@ -0,0 +1,175 @@
+/*! Lookups and inserts which await their loads, for trees in an `AsyncStorageBackend`.
+
+Descending without blocking would naturally be a recursive async function, which needs a boxed future per level.
+These futures don't recurse at all: every poll walks down from the root through whatever is already loaded, and awaits the first node which isn't.
+Loaded nodes stay loaded, so each poll only repeats comparisons, and there's only ever one load in flight.
+*/
+use std;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync;
+use std::task::{Context, Poll};
+use serde;
+use storage_backend::{StorageBackend, AsyncStorageBackend, LoadFuture};
+use error::{EncodingError, DecodingError};
+use comparator::Comparator;
+use super::{BPTree, OffsetTree, Node, NodeRef, NodeType, DiskNode, Metered, Metrics, count, from_disk};
+
+// What a descent keeps between polls.
+struct Descent<'a, K, V> {
+    // The load it's waiting on, with the offset it's for.
+    pending: Option<(u64, LoadFuture<'a, DiskNode<K, V>>)>,
+    // How many levels it's counted in the tree's metrics, so that walking down again doesn't count them twice.
+    counted: usize,
+}
+
+impl<'a, K, V> Descent<'a, K, V> {
+    fn new() -> Descent<'a, K, V> {
+        Descent { pending: None, counted: 0 }
+    }
+}
+
+// Load the path to the leaf which `key` belongs in, returning the leaf.
+fn poll_path<'t, 'b, K, V, B, C>(root: &'t NodeRef<K, V>, cmp: &C, key: &K, backend: &'b B, metrics: Option<&Metrics>, descent: &mut Descent<'b, K, V>, cx: &mut Context<'_>) -> Poll<Result<&'t Node<K, V>, DecodingError>>
+    where K: serde::de::DeserializeOwned+Clone+Send+'b, V: serde::de::DeserializeOwned+Clone+Send+'b, B: AsyncStorageBackend, C: Comparator<K>
+{
+    let mut node_ref = root;
+    let mut depth = 0;
+    loop {
+        let node = match node_ref.loaded() {
+            Some(node) => {
+                if depth >= descent.counted {
+                    if let Some(m) = metrics {
+                        count(&m.resident);
+                    }
+                }
+                node
+            },
+            None => {
+                let pending = &mut descent.pending;
+                let offset = node_ref.offset;
+                // Another thread may have loaded the node we were waiting on, in which case the walk gets further than last time.
+                if pending.as_ref().map(|p| p.0) != Some(offset) {
+                    *pending = Some((offset, backend.load_async(offset)));
+                }
+                let loaded = match pending.as_mut().expect("A load was just started.").1.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(loaded) => loaded,
+                };
+                *pending = None;
+                let node = match loaded.and_then(|disk_node| from_disk(disk_node, offset)) {
+                    Ok(node) => node,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                if let Some(m) = metrics {
+                    count(&m.loaded);
+                }
+                &**node_ref.node.get_or_init(|| sync::Arc::new(node))
+            },
+        };
+        descent.counted = std::cmp::max(descent.counted, depth+1);
+        depth += 1;
+        if node.node_type == NodeType::Leaf {
+            return Poll::Ready(Ok(node));
+        }
+        node_ref = &node.children[node.index_of(cmp, key)];
+    }
+}
+
+/// The future returned by `BPTree::get_async`.
+pub struct GetAsync<'a, K: 'a, V: 'a, B: 'a, C: 'a> {
+    tree: &'a BPTree<K, V, C>,
+    backend: &'a B,
+    key: &'a K,
+    descent: Descent<'a, K, V>,
+}
+
+impl<'a, K, V, B, C> Future for GetAsync<'a, K, V, B, C>
+    where K: serde::de::DeserializeOwned+Clone+Send, V: serde::de::DeserializeOwned+Clone+Send, B: AsyncStorageBackend, C: Comparator<K>
+{
+    type Output = Result<Option<V>, DecodingError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let tree = this.tree;
+        let leaf = match poll_path(&tree.root_reference, &tree.comparator, this.key, this.backend, tree.metrics(), &mut this.descent, cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(leaf) => leaf,
+        };
+        Poll::Ready(leaf.map(|leaf| tree.comparator.search(&leaf.keys, this.key).ok().map(|i| leaf.values[i].clone())))
+    }
+}
+
+/// The future returned by `BPTree::insert_async`.
+pub struct InsertAsync<'a, K: 'a, V: 'a, B: 'a, C: 'a> {
+    tree: &'a mut BPTree<K, V, C>,
+    backend: &'a B,
+    key: &'a K,
+    // Taken when the insert happens.
+    value: Option<V>,
+    descent: Descent<'a, K, V>,
+}
+
+// Nothing is ever pinned in place: the load is boxed, and the value is only moved out.
+impl<'a, K, V, B, C> Unpin for InsertAsync<'a, K, V, B, C> {}
+
+impl<'a, K, V, B, C> Future for InsertAsync<'a, K, V, B, C>
+    where K: serde::de::DeserializeOwned+serde::Serialize+Clone+Send, V: serde::de::DeserializeOwned+serde::Serialize+Clone+PartialEq+Send, B: AsyncStorageBackend, C: Comparator<K>
+{
+    type Output = Result<Option<V>, DecodingError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        match poll_path(&this.tree.root_reference, &this.tree.comparator, this.key, this.backend, this.tree.metrics(), &mut this.descent, cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Ready(Ok(_)) => (),
+        }
+        // Inserting only changes the nodes on the path, and splits don't load their siblings, so the synchronous insert never needs the backend from here.
+        let value = this.value.take().expect("InsertAsync polled after it finished");
+        // The path was counted on the way down.
+        Poll::Ready(this.tree.insert_with(&Metered::new(&Preloaded, None), this.key, |old| if old == Some(&value) { None } else { Some(value) }))
+    }
+}
+
+// A backend for operations whose nodes are all loaded already.
+struct Preloaded;
+
+impl StorageBackend for Preloaded {
+    fn load<V: serde::de::DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        Err(DecodingError::Corrupt(format!("The node at {} should have been loaded already", key)))
+    }
+
+    fn store<V: serde::Serialize>(&mut self, _value: &V) -> Result<u64, EncodingError> {
+        unreachable!("Nothing stores through a preloaded backend")
+    }
+
+    fn store_header<V: serde::Serialize>(&mut self, _value: &V) -> Result<(), EncodingError> {
+        unreachable!("Nothing stores through a preloaded backend")
+    }
+
+    fn load_header<V: serde::de::DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        Ok(None)
+    }
+}
+
+impl<K: serde::de::DeserializeOwned+Clone+Send, V: serde::de::DeserializeOwned+Clone+Send, C: Comparator<K>> BPTree<K, V, C> {
+    /// `get`, awaiting each node it has to load instead of blocking on it.
+    pub fn get_async<'a, B: AsyncStorageBackend>(&'a self, backend: &'a B, key: &'a K) -> GetAsync<'a, K, V, B, C> {
+        GetAsync { tree: self, backend, key, descent: Descent::new() }
+    }
+
+    /// `insert`, awaiting each node it has to load instead of blocking on it.
+    ///
+    /// Nothing is stored until the tree is committed, which has to be done through the backend's `StorageBackend` implementation.
+    /// Dropping the future before it finishes leaves the tree as it was, apart from whichever nodes it already loaded.
+    pub fn insert_async<'a, B: AsyncStorageBackend>(&'a mut self, backend: &'a B, key: &'a K, value: V) -> InsertAsync<'a, K, V, B, C> where K: serde::Serialize, V: serde::Serialize+PartialEq {
+        InsertAsync { tree: self, backend, key, value: Some(value), descent: Descent::new() }
+    }
+}
+
+impl<K: serde::de::DeserializeOwned+Clone+Send, C: Comparator<K>> OffsetTree<K, C> {
+    pub fn offset_for_async<'a, B: AsyncStorageBackend>(&'a self, backend: &'a B, key: &'a K) -> GetAsync<'a, K, u64, B, C> {
+        self.get_async(backend, key)
+    }
+}
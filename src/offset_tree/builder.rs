@@ -0,0 +1,163 @@
+/*! Building a tree straight into a backend from pairs which are already sorted.
+
+`BPTree::build_sorted` packs the whole tree in memory before any of it is stored.
+A builder stores each node as soon as it's full instead, keeping only the rightmost unfinished node of each level, so a tree of any size can be built from a sorted dump in one pass.
+*/
+use std;
+use std::fs;
+use serde;
+use storage_backend::{StorageBackend, FileBackend};
+use error::{Error, UnsortedError};
+use super::{BPTree, Node, NodeRef, NodeType, Header, SplitPolicy, FORMAT_VERSION, check_order, even_chunks, split_threshold, min_children};
+
+/// Stores a tree of order `order` in a backend as its pairs arrive in strictly ascending order of key.
+///
+/// Nodes are packed as full as they can be without splitting, as `build_sorted` packs them, except that the last two nodes of each level share what's left between them.
+/// `finish` stores the rest and records the tree in the backend's header, as `commit_with_header` would.
+/// Nothing points at the stored nodes until then, so a builder which is dropped or fails partway leaves the backend's header as it was.
+pub struct BPTreeBuilder<'a, K, V, B: 'a> {
+    backend: &'a mut B,
+    order: u64,
+    // Pairs not yet in a stored leaf.
+    pairs: Vec<(K, V)>,
+    // For each level above the leaves, the stored children not yet in a stored node: the largest key under each, its offset, and the number of keys under it.
+    levels: Vec<Vec<(K, u64, u64)>>,
+    len: u64,
+}
+
+pub type OffsetTreeBuilder<'a, K, B> = BPTreeBuilder<'a, K, u64, B>;
+
+impl<'a, K, V, B> BPTreeBuilder<'a, K, V, B>
+    where K: serde::Serialize+serde::de::DeserializeOwned+Ord+Clone, V: serde::Serialize+serde::de::DeserializeOwned+Clone, B: StorageBackend
+{
+    /// Panics if `order` is less than `MIN_ORDER`.
+    pub fn new(backend: &'a mut B, order: u64) -> BPTreeBuilder<'a, K, V, B> {
+        check_order(order);
+        BPTreeBuilder { backend, order, pairs: vec![], levels: vec![], len: 0 }
+    }
+
+    /// Add a pair, whose key has to be greater than the key of the one before it.
+    pub fn push(&mut self, key: K, value: V) -> Result<(), Error> {
+        // Storing a leaf always leaves some pairs behind, so the last pair pushed is still here.
+        if self.pairs.last().map(|p| p.0 >= key).unwrap_or(false) {
+            return Err(UnsortedError { index: self.len }.into());
+        }
+        self.pairs.push((key, value));
+        self.len += 1;
+        if self.has_full_node(self.pairs.len()) {
+            let leaf = self.pairs.drain(..split_threshold(self.order)).collect();
+            self.store_leaf(leaf)?;
+        }
+        Ok(())
+    }
+
+    /// `push` each of the pairs in turn.
+    pub fn extend<I: IntoIterator<Item=(K, V)>>(&mut self, iter: I) -> Result<(), Error> {
+        for (k, v) in iter {
+            self.push(k, v)?;
+        }
+        Ok(())
+    }
+
+    /// Store what's left of the tree and record it in the backend's header, returning the header.
+    pub fn finish(mut self) -> Result<Header, Error> {
+        let width = split_threshold(self.order);
+        let pairs = std::mem::take(&mut self.pairs);
+        let root = if self.levels.is_empty() && pairs.len() <= width {
+            let (keys, values) = pairs.into_iter().unzip();
+            self.store(&Node::new(NodeType::Leaf, keys, vec![], values))?
+        }
+        else {
+            let mut pairs = pairs.into_iter();
+            for size in even_chunks(pairs.len(), width) {
+                self.store_leaf(pairs.by_ref().take(size).collect())?;
+            }
+            // Each level above holds more than enough children for one node, unless it's only the leftovers of the level below.
+            let mut level = 0;
+            loop {
+                let children = std::mem::take(&mut self.levels[level]);
+                if level+1 == self.levels.len() && children.len() <= width {
+                    break self.store_internal(NodeType::Root, children)?.1;
+                }
+                let mut children = children.into_iter();
+                for size in even_chunks(children.len(), width) {
+                    let node = self.store_internal(NodeType::Internal, children.by_ref().take(size).collect())?;
+                    self.push_child(level+1, node)?;
+                }
+                level += 1;
+            }
+        };
+        self.backend.flush()?;
+        let header = Header {
+            version: FORMAT_VERSION,
+            root,
+            len: self.len,
+            free_list: None,
+            policy: SplitPolicy::Order(self.order),
+        };
+        self.backend.store_header(&header)?;
+        self.backend.flush()?;
+        Ok(header)
+    }
+
+    // Whether a level has enough pairs or children that a full node can be stored from its front and the rest are still enough for at least one more.
+    fn has_full_node(&self, len: usize) -> bool {
+        len > split_threshold(self.order)+min_children(self.order)
+    }
+
+    fn store_leaf(&mut self, pairs: Vec<(K, V)>) -> Result<(), Error> {
+        let (keys, values) = pairs.into_iter().unzip();
+        let leaf = Node::new(NodeType::Leaf, keys, vec![], values);
+        let max = leaf.keys.last().expect("Only the root can be an empty leaf.").clone();
+        let offset = self.store(&leaf)?;
+        self.push_child(0, (max, offset, leaf.total()))
+    }
+
+    fn push_child(&mut self, level: usize, child: (K, u64, u64)) -> Result<(), Error> {
+        if self.levels.len() == level {
+            self.levels.push(vec![]);
+        }
+        self.levels[level].push(child);
+        if self.has_full_node(self.levels[level].len()) {
+            let children = self.levels[level].drain(..split_threshold(self.order)).collect();
+            let node = self.store_internal(NodeType::Internal, children)?;
+            self.push_child(level+1, node)?;
+        }
+        Ok(())
+    }
+
+    // Store an internal node of these children, returning the largest key under it, its offset, and the number of keys under it.
+    fn store_internal(&mut self, node_type: NodeType, children: Vec<(K, u64, u64)>) -> Result<(K, u64, u64), Error> {
+        let mut separators = Vec::with_capacity(children.len());
+        let mut refs = Vec::with_capacity(children.len());
+        for (k, offset, count) in children {
+            separators.push(k);
+            refs.push(NodeRef::from_offset(offset, count));
+        }
+        // The last child's maximum is the maximum of the new node rather than a separator.
+        let max = separators.pop().expect("Internal nodes have children.");
+        let node = Node::new(node_type, separators, refs, vec![]);
+        Ok((max, self.store(&node)?, node.total()))
+    }
+
+    fn store(&mut self, node: &Node<K, V>) -> Result<u64, Error> {
+        let children = node.children.iter().map(|c| c.offset).collect();
+        Ok(self.backend.store(&node.disk_ref(children)?)?)
+    }
+}
+
+impl<K: serde::Serialize+serde::de::DeserializeOwned+Ord+Clone, V: serde::Serialize+serde::de::DeserializeOwned+Clone> BPTree<K, V> {
+    /// Build a tree in a file from pairs whose keys are in strictly ascending order, returning it with the file's backend.
+    ///
+    /// This is a `BPTreeBuilder` writing to a `FileBackend` of the file, so the tree is never all in memory, and it ends up committed and in the file's header for `reopen` to find.
+    /// The file is used as `FileBackend::new` would use it, so anything already in it stays there, but is no longer what the header points at.
+    pub fn from_sorted_file<I: IntoIterator<Item=(K, V)>>(file: fs::File, order: u64, iter: I) -> Result<(BPTree<K, V>, FileBackend), Error> {
+        let mut backend = FileBackend::new(file)?;
+        let header = {
+            let mut builder = BPTreeBuilder::new(&mut backend, order);
+            builder.extend(iter)?;
+            builder.finish()?
+        };
+        Ok((BPTree::from_root_offset(header.root, order, header.len), backend))
+    }
+}
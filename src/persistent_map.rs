@@ -0,0 +1,159 @@
+/*! A map from keys to values, built out of an `OffsetTree` and a backend. */
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use error::{EncodingError, DecodingError, Error};
+use offset_tree::{self, OffsetTree};
+use storage_backend::StorageBackend;
+
+/** A map which stores its values in a backend and keeps a tree of their offsets in the same backend.
+
+Values which are overwritten or removed go to the tree's free list, so backends which support reuse, such as `FileBackend` for values which fit a dead one's slot, get their space back after the next commit.
+Nothing is durable until `commit` is called.
+*/
+pub struct PersistentMap<K, V, B> {
+    tree: OffsetTree<K>,
+    backend: B,
+    _value: PhantomData<V>,
+}
+
+impl<K, V, B> PersistentMap<K, V, B>
+where K: Serialize+DeserializeOwned+Ord+Clone, V: Serialize+DeserializeOwned, B: StorageBackend {
+    /// An empty map.  Any previous contents of the backend's header are ignored, and replaced on commit.
+    pub fn new(backend: B, order: u64) -> PersistentMap<K, V, B> {
+        let mut tree = OffsetTree::empty(order);
+        tree.enable_free_list();
+        PersistentMap { tree, backend, _value: PhantomData }
+    }
+
+    /// Reopen a map from the backend's header, or start an empty one if the backend doesn't have a header.
+    pub fn open(backend: B, order: u64) -> Result<PersistentMap<K, V, B>, DecodingError> {
+        match OffsetTree::from_header(&backend, order)? {
+            Some(mut tree) => {
+                tree.enable_free_list();
+                Ok(PersistentMap { tree, backend, _value: PhantomData })
+            },
+            None => Ok(PersistentMap::new(backend, order)),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool, DecodingError> {
+        self.tree.contains(&self.backend, key)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, DecodingError> {
+        match self.tree.offset_for(&self.backend, key)? {
+            Some(offset) => self.backend.load(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a value, replacing any previous value for the key.
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        let offset = self.tree.free_list_mut().unwrap().store(&mut self.backend, value)?;
+        let old = match self.tree.insert(&self.backend, key, offset) {
+            Ok(old) => old,
+            Err(e) => {
+                // Nothing will ever point at the new record, so it's as dead as an overwritten one.
+                self.tree.free_list_mut().unwrap().release(offset);
+                return Err(e.into());
+            },
+        };
+        if let Some(old) = old {
+            self.tree.free_list_mut().unwrap().release(old);
+        }
+        Ok(())
+    }
+
+    /// Store several values at once with `StorageBackend::store_batch`, replacing any previous values for their keys.
+    ///
+    /// Where a key appears more than once, the last of its values is the one kept.
+    /// The values are all stored as new records, so unlike `insert` this doesn't reuse the space of dead ones.
+    pub fn insert_batch(&mut self, pairs: &[(K, V)]) -> Result<(), Error> {
+        let values = pairs.iter().map(|p| &p.1).collect::<Vec<_>>();
+        let offsets = self.backend.store_batch(&values)?;
+        for (i, ((key, _), &offset)) in pairs.iter().zip(&offsets).enumerate() {
+            let old = match self.tree.insert(&self.backend, key, offset) {
+                Ok(old) => old,
+                Err(e) => {
+                    // As for `insert`, but the records for the pairs after this one are just as dead.
+                    let free_list = self.tree.free_list_mut().unwrap();
+                    for &o in &offsets[i..] {
+                        free_list.release(o);
+                    }
+                    return Err(e.into());
+                },
+            };
+            if let Some(old) = old {
+                self.tree.free_list_mut().unwrap().release(old);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a key, returning its value if it had one.
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>, DecodingError> {
+        // Load first, so that an unreadable value stays in the map.
+        let value = match self.tree.offset_for(&self.backend, key)? {
+            Some(offset) => self.backend.load(offset)?,
+            None => return Ok(None),
+        };
+        if let Some(old) = self.tree.remove(&self.backend, key)? {
+            self.tree.free_list_mut().unwrap().release(old);
+        }
+        Ok(Some(value))
+    }
+
+    /// Iterate over the map in ascending key order.  Values are loaded one at a time, as the iterator reaches them.
+    pub fn iter(&self) -> Iter<'_, K, V, B> {
+        Iter {
+            inner: self.tree.iter(&self.backend),
+            backend: &self.backend,
+            _value: PhantomData,
+        }
+    }
+
+    /// Make everything durable, by committing the tree and writing the backend's header.
+    pub fn commit(&mut self) -> Result<(), EncodingError> {
+        self.tree.commit_with_header(&mut self.backend).map(|_| ())
+    }
+
+    pub fn tree(&self) -> &OffsetTree<K> {
+        &self.tree
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Give back the backend.  Anything which hasn't been committed is lost.
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+}
+
+pub struct Iter<'a, K: 'a, V, B: 'a> {
+    inner: offset_tree::Iter<'a, K, u64, B>,
+    backend: &'a B,
+    _value: PhantomData<V>,
+}
+
+impl<'a, K, V, B> Iterator for Iter<'a, K, V, B>
+where K: DeserializeOwned+Ord+Clone, V: DeserializeOwned, B: StorageBackend {
+    type Item = Result<(K, V), DecodingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|r| r.and_then(|(k, offset)| {
+            let v = self.backend.load(offset)?;
+            Ok((k, v))
+        }))
+    }
+}
@@ -1,8 +1,19 @@
 use serde::{self, Serialize};
 use serde::de::DeserializeOwned;
-use std::error::{self, Error};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+#[cfg(feature = "async")]
+use std::future::{self, Future};
+#[cfg(feature = "async")]
+use std::pin::Pin;
 use std::io::{self, Read, Write, Seek};
 use bincode;
+use crc32fast;
+pub use error::{EncodingError, DecodingError};
+#[cfg(feature = "deflate")]
+use flate2;
 
 /** A storage backend, capable of encoding and decoding values to u64 keys.
 
@@ -12,44 +23,831 @@ Assuming that the data the backend stores is matched with a tree of the same typ
 
 - Any request for the value of a key occurs strictly after a request to store it, either in this run of the program or in a previous run of the program.
 - Any request for a key will ask for a key of the right type.
+
+Backends also hold a single header record, which lives outside the key space and is replaced rather than appended to.
+Trees write it last when committing, so it always points at a complete tree.
+
+Loads take `&self`, and trees can be read from several threads at once when their backend is `Sync`.
+All of the backends here are, and serialize whatever they have to share between loads.
+
+The backends here all encode with bincode's top-level functions, which write integers little-endian and lengths as 8 bytes whatever the host is.
+Bincode 0.8 has no options to get wrong, so files written on one machine read the same on any other.
+Front-coded keys also depend on this, since they rebuild bincode's length prefixes by hand.
 */
 pub trait StorageBackend {
-    type EncodingError: error::Error;
-    type DecodingError: error::Error;
-    fn load<V: DeserializeOwned>(&self, key: u64) -> Result<V, Self::DecodingError>;
-    fn store<V: Serialize>(&mut self, value: &V) -> Result<u64, Self::EncodingError>;
+    fn load<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError>;
+
+    /// `load` something which won't be wanted again soon, so backends which cache what they load shouldn't cache it.
+    /// The default is just `load`.
+    fn load_uncached<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        self.load(key)
+    }
+
+    fn store<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError>;
+
+    /// Store a value in the space used by `key`, an earlier value that nothing needs anymore, returning the new value's key.
+    ///
+    /// Backends which can't reuse space, or for which the value doesn't fit, return `None` and the caller falls back to `store`.
+    /// The default never reuses anything.
+    fn store_reusing<V: Serialize>(&mut self, _key: u64, _value: &V) -> Result<Option<u64>, EncodingError> {
+        Ok(None)
+    }
+
+    /// Store a value whose space is meant to be reused once it's dead, as `FreeList::store` does when nothing dead has room for it.
+    ///
+    /// Backends which only reuse space that a value fits in can leave room to spare, so that more of the values which come after fit.
+    /// The default is just `store`.
+    fn store_reusable<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        self.store(value)
+    }
+
+    /// Store several values, returning their keys in the same order.
+    ///
+    /// The default stores them one at a time; backends for which each store costs a write or a lock can do them all at once.
+    fn store_batch<V: Serialize>(&mut self, values: &[V]) -> Result<Vec<u64>, EncodingError> {
+        values.iter().map(|v| self.store(v)).collect()
+    }
+
+    /// Replace the header record.  This should be atomic: after a crash, loading the header gives either the old one or the new one.
+    fn store_header<V: Serialize>(&mut self, value: &V) -> Result<(), EncodingError>;
+
+    /// Load the header record, or `None` if one was never stored.
+    fn load_header<V: DeserializeOwned>(&self) -> Result<Option<V>, DecodingError>;
+
+    /// Make everything stored so far durable.  The default does nothing, which is right for backends that don't buffer.
+    fn flush(&mut self) -> Result<(), EncodingError> {
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
-pub enum EncodingError {
-    Unknown(String),
-    IoError(io::Error),
+/// The future of an asynchronous load.
+#[cfg(feature = "async")]
+pub type LoadFuture<'a, V> = Pin<Box<dyn Future<Output = Result<V, DecodingError>> + Send + 'a>>;
+
+/** A backend which can load without blocking, for trees read from async code.
+
+Only loads are asynchronous.
+Everything which stores goes through `commit`, which writes a whole batch of nodes at once and then the header, so async code should commit from a blocking task, using the backend's `StorageBackend` implementation.
+Lookups and inserts only ever need to load, and `BPTree::get_async`, `BPTree::insert_async`, and `OffsetTree::offset_for_async` await each node they need instead of blocking on it.
+
+The crate doesn't depend on any async runtime; these futures run on whichever one the caller uses.
+*/
+#[cfg(feature = "async")]
+pub trait AsyncStorageBackend {
+    fn load_async<'a, V: DeserializeOwned+Send+'a>(&'a self, key: u64) -> LoadFuture<'a, V>;
 }
 
-#[derive(Debug)]
-pub enum DecodingError {
-    Corrupt(String),
-    IoError(io::Error),
+// Decoding from a reader believes length prefixes, and allocates for them before it finds out whether there's that much to read, so readers of untrusted records need a limit.
+// That's also why decoding a node doesn't grow its vectors as it goes: each is allocated at the length its prefix gives, up to a megabyte, before any of it is read.
+fn decode<O: serde::de::DeserializeOwned, R: Read+Seek>(reader: &mut R, offset: u64, limit: Option<u64>) -> Result<O, DecodingError> {
+    reader.seek(io::SeekFrom::Start(offset)).map_err(DecodingError::IoError)?;
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return bincode::deserialize_from(reader, bincode::Infinite).map_err(DecodingError::from),
+    };
+    bincode::deserialize_from(reader, bincode::Bounded(limit)).map_err(|e| match *e {
+        bincode::ErrorKind::SizeLimit => DecodingError::Corrupt(format!("The record at {} is larger than the limit of {} bytes", offset, limit)),
+        _ => DecodingError::from(e),
+    })
 }
 
-fn encode_reader<O: serde::Serialize, W: Write+Seek>(writer: &mut W, obj: &O) -> Result<u64, EncodingError> {
-    let offset = writer.seek(io::SeekFrom::End(0)).map_err(|x| EncodingError::IoError(x))?;
+// Every record a file backend stores is in a slot: the size of the slot, then the record, then whatever's left of the slot after it.
+const SLOT_PREFIX_SIZE: u64 = 8;
+
+// Decode the record in the slot at `offset`, which can't run past the end of its slot.
+fn decode_slot<O: serde::de::DeserializeOwned, R: Read+Seek>(reader: &mut R, offset: u64, limit: Option<u64>) -> Result<O, DecodingError> {
+    let slot: u64 = decode(reader, offset, None)?;
+    if let Some(limit) = limit {
+        if slot > limit {
+            return Err(DecodingError::Corrupt(format!("The record at {} is larger than the limit of {} bytes", offset, limit)));
+        }
+    }
+    bincode::deserialize_from(reader, bincode::Bounded(slot)).map_err(|e| match *e {
+        bincode::ErrorKind::SizeLimit => DecodingError::Corrupt(format!("The record at {} runs past the end of its slot of {} bytes", offset, slot)),
+        _ => DecodingError::from(e),
+    })
+}
+
+// Add a record to the end of `scratch`, in a slot just big enough for it, or with `room`, the next power of two bytes up.
+fn push_slot<V: Serialize>(scratch: &mut Vec<u8>, value: &V, room: bool) -> Result<(), EncodingError> {
+    let start = scratch.len();
+    scratch.extend_from_slice(&[0; SLOT_PREFIX_SIZE as usize]);
+    bincode::serialize_into(scratch, value, bincode::Infinite).map_err(EncodingError::from)?;
+    let mut size = (scratch.len()-start) as u64-SLOT_PREFIX_SIZE;
+    if room {
+        size = size.next_power_of_two();
+        scratch.resize(start+(SLOT_PREFIX_SIZE+size) as usize, 0);
+    }
+    scratch[start..start+SLOT_PREFIX_SIZE as usize].copy_from_slice(&size.to_le_bytes());
+    Ok(())
+}
+
+/// A backend which keeps serialized values in memory, keyed by a counter.
+#[derive(Default, Debug)]
+pub struct MemoryBackend {
+    map: HashMap<u64, Vec<u8>>,
+    count: u64,
+    header: Option<Vec<u8>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        Default::default()
+    }
+
+    /// The number of values currently stored, not counting the header.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Total size of the stored values in bytes, not counting the header.
+    pub fn stored_bytes(&self) -> usize {
+        self.map.values().map(Vec::len).sum()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn load<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        let v = self.map.get(&key).ok_or_else(|| DecodingError::Corrupt(format!("No value for key {}", key)))?;
+        bincode::deserialize(v).map_err(DecodingError::from)
+    }
+
+    fn store<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        let key = self.count;
+        // The map keeps the bytes, so there's no buffer to reuse, but bincode sizes them exactly before serializing, so this is the only allocation.
+        let serialized = bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?;
+        // We should never end up inserting a duplicate key.
+        assert!(self.map.insert(key, serialized).is_none());
+        self.count += 1;
+        Ok(key)
+    }
+
+    fn store_reusing<V: Serialize>(&mut self, key: u64, value: &V) -> Result<Option<u64>, EncodingError> {
+        if !self.map.contains_key(&key) {
+            return Ok(None);
+        }
+        let serialized = bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?;
+        self.map.insert(key, serialized);
+        Ok(Some(key))
+    }
+
+    fn store_header<V: Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        self.header = Some(bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?);
+        Ok(())
+    }
+
+    fn load_header<V: DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        match self.header {
+            Some(ref h) => bincode::deserialize(h).map(Some).map_err(DecodingError::from),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncStorageBackend for MemoryBackend {
+    // Nothing here ever waits, so the futures are ready straight away.
+    fn load_async<'a, V: DeserializeOwned+Send+'a>(&'a self, key: u64) -> LoadFuture<'a, V> {
+        Box::pin(future::ready(self.load(key)))
+    }
+}
+
+// The file starts with the offset of the header record, or 0 if there isn't one yet.
+const HEADER_POINTER_SIZE: u64 = 8;
+
+fn write_at<O: serde::Serialize, W: Write+Seek>(writer: &mut W, offset: u64, obj: &O) -> Result<(), EncodingError> {
+    writer.seek(io::SeekFrom::Start(offset)).map_err(EncodingError::IoError)?;
     bincode::serialize_into(writer, obj, bincode::Infinite)
-    .map_err(|x| {
-        match *x {
-            bincode::ErrorKind::IoError(y) => EncodingError::IoError(y),
-            _ => EncodingError::Unknown(x.description().to_string()),
+    .map_err(EncodingError::from)
+}
+
+// The bytes of a file most recently read around, starting at `start`.
+struct ReadWindow {
+    capacity: usize,
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+// A reader over a file which fills a window of it at a time, so that nearby reads don't each go to the file.
+// Reads at least as large as the window go straight to the file.
+struct WindowReader<'a> {
+    file: &'a fs::File,
+    window: &'a mut ReadWindow,
+    position: u64,
+}
+
+impl<'a> Read for WindowReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let window = &mut *self.window;
+        let end = window.start+window.bytes.len() as u64;
+        if self.position < window.start || self.position >= end {
+            self.file.seek(io::SeekFrom::Start(self.position))?;
+            if buf.len() >= window.capacity {
+                let read = self.file.read(buf)?;
+                self.position += read as u64;
+                return Ok(read);
+            }
+            window.bytes.resize(window.capacity, 0);
+            let filled = self.file.read(&mut window.bytes)?;
+            window.bytes.truncate(filled);
+            window.start = self.position;
         }
-    }).map(|_| offset)
+        let from = (self.position-window.start) as usize;
+        let read = std::cmp::min(buf.len(), window.bytes.len()-from);
+        buf[..read].copy_from_slice(&window.bytes[from..from+read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<'a> Seek for WindowReader<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            io::SeekFrom::Start(p) => p,
+            io::SeekFrom::End(_) => self.file.seek(pos)?,
+            io::SeekFrom::Current(d) => self.position.checked_add_signed(d).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the file."))?,
+        };
+        Ok(self.position)
+    }
+}
+
+/** A backend which appends values to a file, using their byte offsets as keys.
+
+Each value goes in a slot, which starts with the slot's size, so that the backend can tell how much room a dead record left.
+`store_reusing` writes a value into a dead record's slot when it fits there, and slots keep their size however small the records they go on to hold.
+`store_reusable` rounds a slot up to a power of two, so that records which are nearly the same size, such as a tree's leaves and internal nodes, fit in each other's slots; with a free list, the file stops growing once the list has enough slots in it.
+The header is appended like any other value, then the pointer to it at the start of the file is overwritten.
+The pointer is only 8 bytes, which is small enough that filesystems write it atomically in practice.
+
+By default every load seeks and reads the file for itself.
+With a read buffer, loads read a window of the file at a time, and loads which land in the window left by the last one don't touch the file at all.
+Commits write a subtree's nodes next to each other, so iterating over a tree which isn't in memory mostly reads nearby records, and this saves many small reads.
+Any write empties the buffer.
+
+Loads from several threads take turns, since each one has to seek the file and read in one go.
+
+Records go after the last one written rather than at the end of the file, so space can be reserved up front with `with_capacity`.
+
+Files from anywhere untrusted should be opened with a record limit.
+Without one, a record with a corrupt length prefix can make a load try to allocate however much the prefix says before it finds out the file isn't that long.
+*/
+pub struct FileBackend {
+    file: fs::File,
+    // Held for the whole of a load, buffered or not.
+    window: Mutex<ReadWindow>,
+    record_limit: Option<u64>,
+    // Reused by every store, so it only allocates when a record is bigger than any before it.
+    scratch: Vec<u8>,
+    // Where the next record goes, which is short of the end of the file while some of a reservation is left.
+    end: u64,
+    // The header before the current one, which nothing points at anymore, so the next header can go in its slot.
+    spare_header: Option<u64>,
 }
 
-fn decode<O: serde::de::DeserializeOwned, R: Read+Seek>(reader: &mut R, offset: u64) -> Result<O, DecodingError> {
-    reader.seek(io::SeekFrom::Start(offset)).map_err(|x| DecodingError::IoError(x))?;
-    bincode::deserialize_from(    reader, bincode::Infinite)
-    .map_err(|x| {
-        match *x {
-            bincode::ErrorKind::IoError(y) => DecodingError::IoError(y),
-            _ => DecodingError::Corrupt(x.description().to_string()),
+// Nothing panics while holding our locks with anything half-changed, so a poisoned lock is as good as any.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl FileBackend {
+    /// Wrap a file which is empty or was previously written by a `FileBackend`.  It must be open for both reading and writing.
+    pub fn new(file: fs::File) -> Result<FileBackend, EncodingError> {
+        FileBackend::with_read_buffer(file, 0)
+    }
+
+    /// Like `new`, but reading the file `capacity` bytes at a time.
+    pub fn with_read_buffer(mut file: fs::File, capacity: usize) -> Result<FileBackend, EncodingError> {
+        let mut len = file.metadata().map_err(EncodingError::IoError)?.len();
+        if len == 0 {
+            write_at(&mut file, 0, &0u64)?;
+            len = HEADER_POINTER_SIZE;
         }
-    })
+        else if len < HEADER_POINTER_SIZE {
+            return Err(EncodingError::IoError(io::Error::new(io::ErrorKind::InvalidData, "File is too short to have been written by a FileBackend.")));
+        }
+        Ok(FileBackend {
+            file,
+            window: Mutex::new(ReadWindow { capacity, start: 0, bytes: vec![] }),
+            record_limit: None,
+            scratch: vec![],
+            end: len,
+            spare_header: None,
+        })
+    }
+
+    /// Make the file at least `bytes` long up front, so that records up to there are written into space which is already allocated.
+    ///
+    /// The reservation isn't recorded anywhere, so a backend opened on the file later appends after all of it.
+    /// `trim` gives back whatever's left of it once the records it was for are written.
+    pub fn with_capacity(self, bytes: u64) -> Result<FileBackend, EncodingError> {
+        if bytes > self.file.metadata().map_err(EncodingError::IoError)?.len() {
+            self.file.set_len(bytes).map_err(EncodingError::IoError)?;
+        }
+        Ok(self)
+    }
+
+    /// Cut the file off after the last record written, giving back any space reserved by `with_capacity` which wasn't used.
+    pub fn trim(&mut self) -> Result<(), EncodingError> {
+        self.invalidate();
+        self.file.set_len(self.end).map_err(EncodingError::IoError)
+    }
+
+    /// Fail loads of records larger than `bytes` with `Corrupt`, without reading or allocating for any more than that.
+    ///
+    /// The limit applies to the header and to every node, so it has to leave room for the largest of them.
+    pub fn with_record_limit(mut self, bytes: u64) -> FileBackend {
+        self.record_limit = Some(bytes);
+        self
+    }
+
+    fn decode<V: DeserializeOwned>(&self, offset: u64) -> Result<V, DecodingError> {
+        let mut window = lock(&self.window);
+        if window.capacity == 0 {
+            return decode_slot(&mut &self.file, offset, self.record_limit);
+        }
+        decode_slot(&mut WindowReader { file: &self.file, window: &mut window, position: 0 }, offset, self.record_limit)
+    }
+
+    fn invalidate(&mut self) {
+        self.window.get_mut().unwrap_or_else(PoisonError::into_inner).bytes.clear();
+    }
+
+    // Append a record, serializing it into the scratch buffer first so that it goes to the file in one write rather than one per field.
+    fn append<V: Serialize>(&mut self, value: &V, room: bool) -> Result<u64, EncodingError> {
+        self.scratch.clear();
+        push_slot(&mut self.scratch, value, room)?;
+        self.write_scratch()
+    }
+
+    // Write out the scratch buffer after the last record, returning where it went.
+    // If the write fails, the next one goes in the same place.
+    fn write_scratch(&mut self) -> Result<u64, EncodingError> {
+        self.invalidate();
+        let offset = self.end;
+        self.file.seek(io::SeekFrom::Start(offset)).map_err(EncodingError::IoError)?;
+        self.file.write_all(&self.scratch).map_err(EncodingError::IoError)?;
+        self.end += self.scratch.len() as u64;
+        Ok(offset)
+    }
+
+    // Read the 8 bytes at `offset`, for the pointer to the header and the sizes of slots.
+    fn read_u64(&mut self, offset: u64) -> Result<u64, EncodingError> {
+        let mut bytes = [0; 8];
+        self.file.seek(io::SeekFrom::Start(offset)).map_err(EncodingError::IoError)?;
+        self.file.read_exact(&mut bytes).map_err(EncodingError::IoError)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn into_inner(self) -> fs::File {
+        self.file
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn load<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        self.decode(key)
+    }
+
+    fn store<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        self.append(value, false)
+    }
+
+    fn store_reusable<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        self.append(value, true)
+    }
+
+    fn store_reusing<V: Serialize>(&mut self, key: u64, value: &V) -> Result<Option<u64>, EncodingError> {
+        let slot = self.read_u64(key)?;
+        self.scratch.clear();
+        bincode::serialize_into(&mut self.scratch, value, bincode::Infinite).map_err(EncodingError::from)?;
+        // A slot which claims to run past the last record can't be trusted with a write.
+        if self.scratch.len() as u64 > slot || key+SLOT_PREFIX_SIZE+slot > self.end {
+            return Ok(None);
+        }
+        self.invalidate();
+        self.file.seek(io::SeekFrom::Start(key+SLOT_PREFIX_SIZE)).map_err(EncodingError::IoError)?;
+        self.file.write_all(&self.scratch).map_err(EncodingError::IoError)?;
+        Ok(Some(key))
+    }
+
+    // All of the records go into the scratch buffer, and then to the file in one write.
+    fn store_batch<V: Serialize>(&mut self, values: &[V]) -> Result<Vec<u64>, EncodingError> {
+        self.scratch.clear();
+        let mut keys = Vec::with_capacity(values.len());
+        for v in values {
+            keys.push(self.end+self.scratch.len() as u64);
+            push_slot(&mut self.scratch, v, false)?;
+        }
+        self.write_scratch()?;
+        Ok(keys)
+    }
+
+    // Headers take turns between two slots, so that they don't pile up: the current one has to stay until the pointer moves, but the one before it is dead.
+    // A header's slot has room for it to grow, since a free list in it grows and shrinks from one commit to the next.
+    fn store_header<V: Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        let reused = match self.spare_header.take() {
+            Some(spare) => self.store_reusing(spare, value)?,
+            None => None,
+        };
+        let offset = match reused {
+            Some(offset) => offset,
+            None => self.append(value, true)?,
+        };
+        // The record has to be on disk before anything points at it.
+        self.flush()?;
+        let previous = self.read_u64(0)?;
+        write_at(&mut self.file, 0, &offset)?;
+        if previous != 0 {
+            self.spare_header = Some(previous);
+        }
+        Ok(())
+    }
+
+    fn load_header<V: DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        // The pointer is the one thing in the file which isn't in a slot.
+        // Taking the lock keeps any other load from moving the file's position meanwhile.
+        let offset: u64 = {
+            let _window = lock(&self.window);
+            decode(&mut &self.file, 0, None)?
+        };
+        if offset == 0 {
+            return Ok(None);
+        }
+        self.decode(offset).map(Some)
+    }
+
+    fn flush(&mut self) -> Result<(), EncodingError> {
+        self.file.sync_data().map_err(EncodingError::IoError)
+    }
+}
+
+// A least-recently-used map from keys to serialized values.
+struct Lru {
+    capacity: usize,
+    // Each entry remembers when it was last used.
+    entries: HashMap<u64, (u64, Vec<u8>)>,
+    // From last use to key, so the oldest entry is first.
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: u64) -> Option<&Vec<u8>> {
+        let clock = self.clock;
+        match self.entries.get_mut(&key) {
+            Some(&mut (ref mut used, ref bytes)) => {
+                self.recency.remove(used);
+                self.recency.insert(clock, key);
+                *used = clock;
+                self.clock += 1;
+                Some(bytes)
+            },
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: u64, bytes: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(key);
+        while self.entries.len() >= self.capacity {
+            let (&used, &oldest) = self.recency.iter().next().unwrap();
+            self.recency.remove(&used);
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(key, (self.clock, bytes));
+        self.recency.insert(self.clock, key);
+        self.clock += 1;
+    }
+
+    fn remove(&mut self, key: u64) {
+        if let Some((used, _)) = self.entries.remove(&key) {
+            self.recency.remove(&used);
+        }
+    }
+}
+
+/** A backend which keeps the most recently used values of another backend in memory.
+
+Values go to the inner backend as serialized blobs, so that the cache can hold them without knowing their types.
+This means that data written through a `CachedBackend` has to be read through one as well.
+The header isn't cached, and is passed straight through.
+*/
+pub struct CachedBackend<B> {
+    inner: B,
+    cache: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<B: StorageBackend> CachedBackend<B> {
+    /// Wrap a backend, caching up to `capacity` values.
+    pub fn new(inner: B, capacity: usize) -> CachedBackend<B> {
+        CachedBackend {
+            inner,
+            cache: Mutex::new(Lru {
+                capacity,
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of loads served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of loads which had to go to the inner backend.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_counters(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for CachedBackend<B> {
+    fn load<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        if let Some(bytes) = lock(&self.cache).get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return bincode::deserialize(bytes).map_err(DecodingError::from);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // The cache isn't locked while the inner backend loads, so other threads can use it meanwhile.
+        let bytes: Vec<u8> = self.inner.load(key)?;
+        let value = bincode::deserialize(&bytes).map_err(DecodingError::from)?;
+        lock(&self.cache).insert(key, bytes);
+        Ok(value)
+    }
+
+    // Something already cached is still a hit, but nothing new goes in.
+    fn load_uncached<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        if let Some(bytes) = lock(&self.cache).get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return bincode::deserialize(bytes).map_err(DecodingError::from);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let bytes: Vec<u8> = self.inner.load_uncached(key)?;
+        bincode::deserialize(&bytes).map_err(DecodingError::from)
+    }
+
+    fn store<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        let bytes = bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?;
+        let key = self.inner.store(&bytes)?;
+        // Whatever was just written is likely to be read again soon.
+        self.cache.get_mut().unwrap_or_else(PoisonError::into_inner).insert(key, bytes);
+        Ok(key)
+    }
+
+    fn store_batch<V: Serialize>(&mut self, values: &[V]) -> Result<Vec<u64>, EncodingError> {
+        let blobs = values.iter().map(|v| bincode::serialize(v, bincode::Infinite).map_err(EncodingError::from)).collect::<Result<Vec<_>, _>>()?;
+        let keys = self.inner.store_batch(&blobs)?;
+        let cache = self.cache.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for (&key, bytes) in keys.iter().zip(blobs) {
+            cache.insert(key, bytes);
+        }
+        Ok(keys)
+    }
+
+    fn store_reusing<V: Serialize>(&mut self, key: u64, value: &V) -> Result<Option<u64>, EncodingError> {
+        let bytes = bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?;
+        let new_key = self.inner.store_reusing(key, &bytes)?;
+        if let Some(k) = new_key {
+            let cache = self.cache.get_mut().unwrap_or_else(PoisonError::into_inner);
+            cache.remove(key);
+            cache.insert(k, bytes);
+        }
+        Ok(new_key)
+    }
+
+    fn store_reusable<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        let bytes = bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?;
+        let key = self.inner.store_reusable(&bytes)?;
+        self.cache.get_mut().unwrap_or_else(PoisonError::into_inner).insert(key, bytes);
+        Ok(key)
+    }
+
+    fn store_header<V: Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        self.inner.store_header(value)
+    }
+
+    fn load_header<V: DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        self.inner.load_header()
+    }
+
+    fn flush(&mut self) -> Result<(), EncodingError> {
+        self.inner.flush()
+    }
+}
+
+/// A compression scheme for `CompressedBackend`.
+pub trait Codec {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, EncodingError>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, DecodingError>;
+}
+
+/// Deflate compression, via `flate2`.
+#[cfg(feature = "deflate")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deflate {
+    level: flate2::Compression,
+}
+
+#[cfg(feature = "deflate")]
+impl Deflate {
+    /// Compress at the given level, from 0 (none) to 9 (best).
+    pub fn new(level: u32) -> Deflate {
+        Deflate { level: flate2::Compression::new(level) }
+    }
+}
+
+#[cfg(feature = "deflate")]
+impl Codec for Deflate {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, EncodingError> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), self.level);
+        encoder.write_all(data).map_err(EncodingError::IoError)?;
+        encoder.finish().map_err(EncodingError::IoError)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, DecodingError> {
+        let mut decoded = Vec::new();
+        // Deflate libraries report a bad stream as an I/O error, but it can only mean the data is bad.
+        flate2::read::DeflateDecoder::new(data).read_to_end(&mut decoded)
+        .map_err(|e| DecodingError::Corrupt(format!("Unable to decompress: {}", e)))?;
+        Ok(decoded)
+    }
+}
+
+/** A backend which compresses values before handing them to another backend.
+
+Like `CachedBackend`, values go to the inner backend as blobs, and keys are the inner backend's keys.
+The header isn't compressed.
+*/
+pub struct CompressedBackend<B, C> {
+    inner: B,
+    codec: C,
+}
+
+impl<B, C> CompressedBackend<B, C>
+where B: StorageBackend, C: Codec {
+    pub fn new(inner: B, codec: C) -> CompressedBackend<B, C> {
+        CompressedBackend { inner, codec }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    fn compress<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, EncodingError> {
+        let bytes = bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?;
+        self.codec.compress(&bytes)
+    }
+}
+
+impl<B, C> StorageBackend for CompressedBackend<B, C>
+where B: StorageBackend, C: Codec {
+    fn load<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        let compressed: Vec<u8> = self.inner.load(key)?;
+        let bytes = self.codec.decompress(&compressed)?;
+        bincode::deserialize(&bytes).map_err(DecodingError::from)
+    }
+
+    fn load_uncached<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        let compressed: Vec<u8> = self.inner.load_uncached(key)?;
+        let bytes = self.codec.decompress(&compressed)?;
+        bincode::deserialize(&bytes).map_err(DecodingError::from)
+    }
+
+    fn store<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        let compressed = self.compress(value)?;
+        self.inner.store(&compressed)
+    }
+
+    fn store_batch<V: Serialize>(&mut self, values: &[V]) -> Result<Vec<u64>, EncodingError> {
+        let compressed = values.iter().map(|v| self.compress(v)).collect::<Result<Vec<_>, _>>()?;
+        self.inner.store_batch(&compressed)
+    }
+
+    fn store_reusing<V: Serialize>(&mut self, key: u64, value: &V) -> Result<Option<u64>, EncodingError> {
+        let compressed = self.compress(value)?;
+        self.inner.store_reusing(key, &compressed)
+    }
+
+    fn store_reusable<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        let compressed = self.compress(value)?;
+        self.inner.store_reusable(&compressed)
+    }
+
+    fn store_header<V: Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        self.inner.store_header(value)
+    }
+
+    fn load_header<V: DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        self.inner.load_header()
+    }
+
+    fn flush(&mut self) -> Result<(), EncodingError> {
+        self.inner.flush()
+    }
+}
+
+/** A backend which stores a CRC32 alongside each value and verifies it on load.
+
+A damaged record can still decode to something plausible, so this turns silent corruption into `DecodingError::Corrupt`.
+Values go to the inner backend as a checksum and a blob, and the header isn't checksummed.
+*/
+pub struct ChecksumBackend<B> {
+    inner: B,
+}
+
+impl<B: StorageBackend> ChecksumBackend<B> {
+    pub fn new(inner: B) -> ChecksumBackend<B> {
+        ChecksumBackend { inner }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+fn checksummed<V: Serialize>(value: &V) -> Result<(u32, Vec<u8>), EncodingError> {
+    let bytes = bincode::serialize(value, bincode::Infinite).map_err(EncodingError::from)?;
+    Ok((crc32fast::hash(&bytes), bytes))
+}
+
+fn verified<V: DeserializeOwned>(key: u64, record: (u32, Vec<u8>)) -> Result<V, DecodingError> {
+    let (checksum, bytes) = record;
+    if crc32fast::hash(&bytes) != checksum {
+        return Err(DecodingError::Corrupt(format!("Checksum mismatch for key {}", key)));
+    }
+    bincode::deserialize(&bytes).map_err(DecodingError::from)
+}
+
+impl<B: StorageBackend> StorageBackend for ChecksumBackend<B> {
+    fn load<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        verified(key, self.inner.load(key)?)
+    }
+
+    fn load_uncached<V: DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        verified(key, self.inner.load_uncached(key)?)
+    }
+
+    fn store<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        let record = checksummed(value)?;
+        self.inner.store(&record)
+    }
+
+    fn store_batch<V: Serialize>(&mut self, values: &[V]) -> Result<Vec<u64>, EncodingError> {
+        let records = values.iter().map(checksummed).collect::<Result<Vec<_>, _>>()?;
+        self.inner.store_batch(&records)
+    }
+
+    fn store_reusing<V: Serialize>(&mut self, key: u64, value: &V) -> Result<Option<u64>, EncodingError> {
+        let record = checksummed(value)?;
+        self.inner.store_reusing(key, &record)
+    }
+
+    fn store_reusable<V: Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        let record = checksummed(value)?;
+        self.inner.store_reusable(&record)
+    }
+
+    fn store_header<V: Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        self.inner.store_header(value)
+    }
+
+    fn load_header<V: DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        self.inner.load_header()
+    }
+
+    fn flush(&mut self) -> Result<(), EncodingError> {
+        self.inner.flush()
+    }
 }
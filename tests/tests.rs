@@ -1,58 +1,2732 @@
 extern crate append_tree;
-use append_tree::StorageBackend;
+use append_tree::{StorageBackend, FileBackend, MemoryBackend, CachedBackend, ChecksumBackend};
+use append_tree::error::{EncodingError, DecodingError};
 extern crate rand;
 extern crate bincode;
 extern crate serde;
 use rand::{XorShiftRng, Rng, SeedableRng};
-use append_tree::offset_tree::OffsetTree;
-use std::collections::HashMap;
+use append_tree::offset_tree::{BPTree, OffsetTree, MultiOffsetTree, Header, SplitPolicy, TreeStats, MIN_ORDER, FORMAT_VERSION};
+use std::cell::RefCell;
+use append_tree::{PersistentMap, Comparator, UnsortedError, Error, OffsetTreeBuilder, NodeType, NodeView};
 
-#[derive(Default)]
-struct MemoryBackend {
-    map: HashMap<u64, Vec<u8>>,
-    count: u64,
-}
-
-impl MemoryBackend {
-    fn new() -> MemoryBackend {
-        Default::default()
+#[test]
+#[allow(clippy::needless_borrowed_reference)]
+fn test_insertion_nocommit() {
+    let mut rng = XorShiftRng::from_seed([1, 1, 1, 1]);
+    let count = 10000;
+    let order = 7;
+    let mut points = (0..count).zip(rng.gen_iter().take(count)).collect::<Vec<_>>();
+    rng.shuffle(&mut points);
+    let mut tree = OffsetTree::empty(order);
+    let backend = MemoryBackend::new();
+    for &(ref k, ref v) in points.iter() {
+        tree.insert(&backend, k, *v).unwrap();
+    }
+    for &(ref k, ref v) in points.iter() {
+        let got = tree.offset_for(&backend, k).unwrap().unwrap();
+        assert_eq!(got,  *v);
     }
 }
 
-impl StorageBackend for MemoryBackend {
-    type DecodingError = Box<bincode::ErrorKind>;
-    type EncodingError = Box<bincode::ErrorKind>;
 
-    fn load<V: serde::de::DeserializeOwned>(&self, key: u64) -> Result<V, Self::DecodingError> {
-        let v = self.map.get(&key).unwrap();
-        bincode::deserialize(&v)
+#[test]
+fn test_removal_nocommit() {
+    let mut rng = XorShiftRng::from_seed([2, 2, 2, 2]);
+    let count = 10000;
+    let order = 7;
+    let mut points = (0..count).zip(rng.gen_iter().take(count)).collect::<Vec<_>>();
+    rng.shuffle(&mut points);
+    let mut tree = OffsetTree::empty(order);
+    let backend = MemoryBackend::new();
+    for (k, v) in points.iter() {
+        tree.insert(&backend, k, *v).unwrap();
     }
-
-    fn store<V: serde::Serialize>(&mut self, value: &V) -> Result<u64, Self::EncodingError> {
-        let key = self.count;
-        let serialized = bincode::serialize(value, bincode::Infinite)?;
-        // We should never end up inserting a duplicate key.
-        assert!(self.map.insert(key, serialized) == None);
-        self.count += 1;
-        Ok(key)
+    rng.shuffle(&mut points);
+    let (removed, kept) = points.split_at(count/2);
+    for (k, v) in removed.iter() {
+        assert_eq!(tree.remove(&backend, k).unwrap(), Some(*v));
+        assert_eq!(tree.remove(&backend, k).unwrap(), None);
+    }
+    for (k, _) in removed.iter() {
+        assert_eq!(tree.offset_for(&backend, k).unwrap(), None);
+    }
+    for (k, v) in kept.iter() {
+        assert_eq!(tree.offset_for(&backend, k).unwrap(), Some(*v));
+    }
+    // Taking it all the way down to nothing collapses the root back to a leaf.
+    for (k, v) in kept.iter() {
+        assert_eq!(tree.remove(&backend, k).unwrap(), Some(*v));
+    }
+    for (k, _) in points.iter() {
+        assert!(!tree.contains(&backend, k).unwrap());
+    }
+    // And the tree is still usable afterward.
+    for (k, v) in points.iter() {
+        tree.insert(&backend, k, *v).unwrap();
+    }
+    for (k, v) in points.iter() {
+        assert_eq!(tree.offset_for(&backend, k).unwrap(), Some(*v));
     }
 }
 
 #[test]
-fn test_insertion_nocommit() {
-    let mut rng = XorShiftRng::from_seed([1, 1, 1, 1]);
+fn test_iter() {
+    let mut rng = XorShiftRng::from_seed([4, 4, 4, 4]);
     let count = 10000;
     let order = 7;
     let mut points = (0..count).zip(rng.gen_iter().take(count)).collect::<Vec<_>>();
     rng.shuffle(&mut points);
     let mut tree = OffsetTree::empty(order);
     let backend = MemoryBackend::new();
-    for &(ref k, ref v) in points.iter() {
+    assert_eq!(tree.iter(&backend).count(), 0);
+    for (k, v) in points.iter() {
         tree.insert(&backend, k, *v).unwrap();
     }
-    for &(ref k, ref v) in points.iter() {
-        let got = tree.offset_for(&backend, k).unwrap().unwrap();
-        assert_eq!(got,  *v);
+    points.sort();
+    let got = tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(got, points);
+}
+
+#[test]
+fn test_range() {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+    let mut rng = XorShiftRng::from_seed([5, 5, 5, 5]);
+    let count = 2000;
+    // Only even keys, so that probes can fall between them.
+    let mut points = (0..count).map(|x| x*2).zip(rng.gen_iter().take(count)).collect::<Vec<_>>();
+    rng.shuffle(&mut points);
+    let mut tree = OffsetTree::empty(7);
+    let backend = MemoryBackend::new();
+    let mut oracle = BTreeMap::new();
+    for (k, v) in points.iter() {
+        tree.insert(&backend, k, *v).unwrap();
+        oracle.insert(*k, *v);
+    }
+    let collect = |lower: Bound<usize>, upper: Bound<usize>| tree.range(&backend, (lower, upper)).collect::<Result<Vec<_>, _>>().unwrap();
+    let expect = |lower: Bound<usize>, upper: Bound<usize>| oracle.range((lower, upper)).map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+    let probes = [0, 1, 2, 3, 100, 101, 1000, 1999, 2000, 3998, 3999, 4000, 5000];
+    for &a in probes.iter() {
+        for &b in probes.iter().filter(|&&b| b >= a) {
+            for &(lower, upper) in [
+                (Bound::Included(a), Bound::Included(b)),
+                (Bound::Included(a), Bound::Excluded(b)),
+                (Bound::Excluded(a), Bound::Included(b)),
+                (Bound::Unbounded, Bound::Excluded(b)),
+                (Bound::Included(a), Bound::Unbounded),
+            ].iter() {
+                if lower == Bound::Excluded(a) && upper == Bound::Excluded(b) && a == b {
+                    continue;
+                }
+                assert_eq!(collect(lower, upper), expect(lower, upper));
+            }
+        }
+    }
+    // Backwards ranges are empty rather than an error.
+    assert!(collect(Bound::Included(10), Bound::Included(5)).is_empty());
+    assert!(collect(Bound::Excluded(10), Bound::Excluded(10)).is_empty());
+    // Entirely past either end.
+    assert!(collect(Bound::Excluded(3998), Bound::Unbounded).is_empty());
+    assert!(tree.range(&backend, ..0).next().is_none());
+    assert_eq!(tree.range(&backend, ..).count(), count);
+}
+
+#[test]
+fn test_first_last() {
+    let mut rng = XorShiftRng::from_seed([6, 6, 6, 6]);
+    let mut tree = OffsetTree::empty(7);
+    let backend = MemoryBackend::new();
+    assert_eq!(tree.first(&backend).unwrap(), None);
+    assert_eq!(tree.last(&backend).unwrap(), None);
+    let count = 5000;
+    let mut points = (0..count).map(|x| x*3+7).zip(rng.gen_iter().take(count)).collect::<Vec<_>>();
+    rng.shuffle(&mut points);
+    for (k, v) in points.iter() {
+        tree.insert(&backend, k, *v).unwrap();
+    }
+    points.sort();
+    assert_eq!(tree.first(&backend).unwrap(), points.first().cloned());
+    assert_eq!(tree.last(&backend).unwrap(), points.last().cloned());
+}
+
+#[test]
+fn test_empty_tree() {
+    fn check_empty(tree: &OffsetTree<u64>, backend: &MemoryBackend) {
+        assert_eq!(tree.offset_for(backend, &5).unwrap(), None);
+        assert!(!tree.contains(backend, &5).unwrap());
+        assert_eq!(tree.first(backend).unwrap(), None);
+        assert_eq!(tree.last(backend).unwrap(), None);
+        assert_eq!(tree.ceiling(backend, &5).unwrap(), None);
+        assert_eq!(tree.floor(backend, &5).unwrap(), None);
+        assert_eq!(tree.rank(backend, &5).unwrap(), 0);
+        assert_eq!(tree.count_range(backend, 0..10).unwrap(), 0);
+        assert_eq!(tree.iter(backend).count(), 0);
+        assert_eq!(tree.iter_rev(backend).count(), 0);
+        tree.validate(backend).unwrap();
+    }
+    let mut backend = MemoryBackend::new();
+    // Never inserted into.
+    let mut tree = OffsetTree::empty(7);
+    check_empty(&tree, &backend);
+    // Committed and opened again, so that the empty root has to be loaded.
+    let root = tree.commit(&mut backend).unwrap();
+    check_empty(&OffsetTree::open(&backend, root, 7, 0).unwrap(), &backend);
+    // Emptied after growing past a single leaf.
+    for i in 0..100u64 {
+        tree.insert(&backend, &i, i).unwrap();
+    }
+    for i in 0..100u64 {
+        tree.remove(&backend, &i).unwrap();
+    }
+    check_empty(&tree, &backend);
+}
+
+#[test]
+fn test_ceiling_floor() {
+    use std::collections::BTreeMap;
+    let mut rng = XorShiftRng::from_seed([7, 7, 7, 7]);
+    let count = 3000;
+    let mut points = (0..count).map(|x| x*4+10).zip(rng.gen_iter().take(count)).collect::<Vec<_>>();
+    rng.shuffle(&mut points);
+    let mut tree = OffsetTree::empty(7);
+    let backend = MemoryBackend::new();
+    assert_eq!(tree.ceiling(&backend, &5).unwrap(), None);
+    assert_eq!(tree.floor(&backend, &5).unwrap(), None);
+    let mut oracle = BTreeMap::new();
+    for (k, v) in points.iter() {
+        tree.insert(&backend, k, *v).unwrap();
+        oracle.insert(*k, *v);
+    }
+    // Removing keys leaves behind separators which no longer exist in the leaves.
+    for (k, _) in points.iter().take(count/3) {
+        tree.remove(&backend, k).unwrap();
+        oracle.remove(k);
+    }
+    // Probes before, on, between, and after the keys.
+    for probe in 0..count*4+20 {
+        let ceiling = oracle.range(probe..).next().map(|(k, v)| (*k, *v));
+        let floor = oracle.range(..=probe).next_back().map(|(k, v)| (*k, *v));
+        assert_eq!(tree.ceiling(&backend, &probe).unwrap(), ceiling);
+        assert_eq!(tree.floor(&backend, &probe).unwrap(), floor);
+    }
+}
+
+#[test]
+fn test_iter_rev() {
+    let mut rng = XorShiftRng::from_seed([8, 8, 8, 8]);
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    assert_eq!(tree.iter_rev(&backend).count(), 0);
+    // A tree which is only a root leaf.
+    for k in 0..3 {
+        tree.insert(&backend, &k, k as u64).unwrap();
+    }
+    let got = tree.iter_rev(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(got, vec![(2, 2), (1, 1), (0, 0)]);
+    // Ascending insertion keeps splitting the rightmost path.
+    let mut tree = OffsetTree::empty(7);
+    let mut points = (0..5000).zip(rng.gen_iter()).collect::<Vec<(usize, u64)>>();
+    for (k, v) in points.iter() {
+        tree.insert(&backend, k, *v).unwrap();
+    }
+    points.reverse();
+    let got = tree.iter_rev(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(got, points);
+}
+
+#[test]
+fn test_len() {
+    let mut rng = XorShiftRng::from_seed([9, 9, 9, 9]);
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    assert!(tree.is_empty());
+    // Plenty of duplicates.
+    let keys = rng.gen_iter::<u16>().map(|x| x%1000).take(5000).collect::<Vec<_>>();
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(&backend, k, i as u64).unwrap();
+    }
+    let mut distinct = keys.clone();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(tree.len(), distinct.len() as u64);
+    assert!(!tree.is_empty());
+    for k in distinct.iter().take(100) {
+        tree.remove(&backend, k).unwrap();
+        // Removing something which isn't there doesn't change anything.
+        tree.remove(&backend, k).unwrap();
+    }
+    assert_eq!(tree.len(), distinct.len() as u64-100);
+    assert_eq!(tree.len(), tree.iter(&backend).count() as u64);
+}
+
+#[test]
+fn test_insert_returns_previous() {
+    let mut rng = XorShiftRng::from_seed([24, 24, 24, 24]);
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    let mut expected = std::collections::HashMap::new();
+    for i in 0..5000u64 {
+        let k = rng.gen::<u16>()%1000;
+        assert_eq!(tree.insert(&backend, &k, i).unwrap(), expected.insert(k, i));
+    }
+}
+
+#[test]
+fn test_overwrite() {
+    let backend = MemoryBackend::new();
+    // A root leaf one key short of splitting.
+    let mut tree = OffsetTree::empty(7);
+    for i in 0..4u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+    }
+    let before = tree.stats(&backend).unwrap();
+    assert_eq!(before.height, 1);
+    for round in 1..10u64 {
+        for i in 0..4u32 {
+            assert_eq!(tree.insert(&backend, &i, round*100+i as u64).unwrap(), Some((round-1)*100+i as u64));
+        }
+    }
+    assert_eq!(tree.stats(&backend).unwrap(), before);
+    assert_eq!(tree.len(), 4);
+    // Now a deeper tree, overwriting every key, the separators in the internal nodes included.
+    for i in 4..1000u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+    }
+    let before = tree.stats(&backend).unwrap();
+    assert!(before.height > 2);
+    for round in 10..15u64 {
+        for i in 0..1000u32 {
+            tree.insert(&backend, &i, round*10000+i as u64).unwrap();
+        }
+        assert_eq!(tree.len(), 1000);
+        assert_eq!(tree.stats(&backend).unwrap(), before);
+        let got = tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, (0..1000u32).map(|i| (i, round*10000+i as u64)).collect::<Vec<_>>());
+    }
+    for i in 0..1000u32 {
+        assert_eq!(tree.offset_for(&backend, &i).unwrap(), Some(140000+i as u64));
+    }
+    tree.validate(&backend).unwrap();
+}
+
+#[test]
+fn test_get_or_insert_with() {
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    for i in 0..1000u32 {
+        tree.insert(&backend, &(i*2), i as u64).unwrap();
+    }
+    let mut calls = 0;
+    for i in 0..2000u32 {
+        let offset = tree.get_or_insert_with(&backend, &i, || {
+            calls += 1;
+            10000+i as u64
+        }).unwrap();
+        let expected = if i%2 == 0 { (i/2) as u64 } else { 10000+i as u64 };
+        assert_eq!(offset, expected);
+    }
+    // Only the odd keys were missing.
+    assert_eq!(calls, 1000);
+    assert_eq!(tree.len(), 2000);
+    for i in 0..2000u32 {
+        assert_eq!(tree.get_or_insert_with(&backend, &i, || panic!("{} should be present", i)).unwrap(), tree.offset_for(&backend, &i).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_commit() {
+    use std::collections::BTreeMap;
+    let mut rng = XorShiftRng::from_seed([10, 10, 10, 10]);
+    let order = 7;
+    let count = 5000;
+    let mut backend = MemoryBackend::new();
+    let mut oracle = BTreeMap::new();
+    let mut tree = OffsetTree::empty(order);
+    for _ in 0..count {
+        let (k, v) = rng.gen::<(u16, u64)>();
+        tree.insert(&backend, &k, v).unwrap();
+        oracle.insert(k, v);
+    }
+    let root = tree.commit(&mut backend).unwrap();
+    // Committing again with nothing changed writes nothing.
+    let stored = backend.len();
+    assert_eq!(tree.commit(&mut backend).unwrap(), root);
+    assert_eq!(backend.len(), stored);
+    let expected = oracle.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+    let mut reopened = OffsetTree::from_root_offset(root, order, tree.len());
+    // Iterate first, so that the iterator is the one doing the loading.
+    assert_eq!(reopened.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), expected);
+    for (k, v) in oracle.iter() {
+        assert_eq!(reopened.offset_for(&backend, k).unwrap(), Some(*v));
+    }
+    // Modify the reopened tree and do it again.
+    for _ in 0..count/2 {
+        let (k, v) = rng.gen::<(u16, u64)>();
+        if v%3 == 0 {
+            assert_eq!(reopened.remove(&backend, &k).unwrap(), oracle.remove(&k));
+        }
+        else {
+            reopened.insert(&backend, &k, v).unwrap();
+            oracle.insert(k, v);
+        }
+    }
+    let root2 = reopened.commit(&mut backend).unwrap();
+    assert!(root2 != root);
+    let expected2 = oracle.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+    let reopened2 = OffsetTree::from_root_offset(root2, order, reopened.len());
+    assert_eq!(reopened2.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), expected2);
+    assert_eq!(reopened2.len(), oracle.len() as u64);
+    // The old root is still intact.
+    let old = OffsetTree::<u16>::from_root_offset(root, order, tree.len());
+    assert_eq!(old.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), expected);
+}
+
+#[test]
+fn test_commit_only_writes_changes() {
+    let order = 7;
+    let count = 20000;
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(order);
+    for i in 0..count {
+        tree.insert(&backend, &(i*2), i as u64).unwrap();
+    }
+    let root = tree.commit(&mut backend).unwrap();
+    let total_nodes = backend.len();
+    let mut tree = OffsetTree::from_root_offset(root, order, tree.len());
+    // None of these change anything, so nothing gets written.
+    tree.offset_for(&backend, &500).unwrap();
+    tree.remove(&backend, &501).unwrap();
+    tree.insert(&backend, &502, 251).unwrap();
+    tree.get_or_insert_with(&backend, &504, || 0).unwrap();
+    assert_eq!(tree.commit(&mut backend).unwrap(), root);
+    assert_eq!(backend.len(), total_nodes);
+    // One new key rewrites one path, which for this tree is at most 14 nodes plus any splits.
+    tree.insert(&backend, &503, 0).unwrap();
+    let before = backend.len();
+    tree.commit(&mut backend).unwrap();
+    let written = backend.len()-before;
+    assert!(written > 0 && written <= 28, "Wrote {} of {} nodes", written, total_nodes);
+}
+
+#[test]
+fn test_open() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    for i in 0..1000u32 {
+        tree.insert(&backend, &i, (i*10) as u64).unwrap();
+    }
+    let root = tree.commit(&mut backend).unwrap();
+    let reopened = OffsetTree::<u32>::open(&backend, root, 7, tree.len()).unwrap();
+    assert_eq!(reopened.len(), 1000);
+    assert_eq!(reopened.first(&backend).unwrap(), Some((0, 0)));
+    assert_eq!(reopened.last(&backend).unwrap(), Some((999, 9990)));
+    // The backend's error comes straight through.
+    match OffsetTree::<u32>::open(&backend, backend.len() as u64+5, 7, 0) {
+        Err(DecodingError::Corrupt(_)) => {},
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Opened a tree from a key which doesn't exist."),
+    }
+}
+
+// Records the order in which a MemoryBackend gets used.
+#[derive(Default)]
+struct LoggingBackend {
+    inner: MemoryBackend,
+    log: RefCell<Vec<&'static str>>,
+}
+
+impl StorageBackend for LoggingBackend {
+    fn load<V: serde::de::DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        self.log.borrow_mut().push("load");
+        self.inner.load(key)
+    }
+
+    fn store<V: serde::Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        self.log.borrow_mut().push("store");
+        self.inner.store(value)
+    }
+
+    fn store_header<V: serde::Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        self.log.borrow_mut().push("store_header");
+        self.inner.store_header(value)
+    }
+
+    fn load_header<V: serde::de::DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        self.log.borrow_mut().push("load_header");
+        self.inner.load_header()
+    }
+
+    fn flush(&mut self) -> Result<(), EncodingError> {
+        self.log.borrow_mut().push("flush");
+        Ok(())
+    }
+}
+
+#[test]
+fn test_commit_with_header() {
+    let mut backend = LoggingBackend::default();
+    assert!(OffsetTree::<u32>::from_header(&backend, 7).unwrap().is_none());
+    let mut tree = OffsetTree::empty(7);
+    for i in 0..500u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+    }
+    backend.log.borrow_mut().clear();
+    let header = tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(header.len, 500);
+    // Every node, then a flush, then the header, then another flush.
+    let log = backend.log.borrow().clone();
+    let n = log.len();
+    assert!(log[..n-3].iter().all(|x| *x == "store"));
+    assert_eq!(&log[n-3..], &["flush", "store_header", "flush"]);
+    let reopened = OffsetTree::<u32>::from_header(&backend, 7).unwrap().unwrap();
+    assert_eq!(reopened.len(), 500);
+    assert_eq!(reopened.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), (0..500).map(|i| (i, i as u64)).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_header_records_policy() {
+    let mut backend = MemoryBackend::new();
+    assert!(OffsetTree::<u32>::reopen(&backend).unwrap().is_none());
+    let mut tree = OffsetTree::empty(11);
+    for i in 0..300u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+    }
+    let header = tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(header.policy, SplitPolicy::Order(11));
+    let reopened = OffsetTree::<u32>::reopen(&backend).unwrap().unwrap();
+    assert_eq!(reopened.split_policy(), SplitPolicy::Order(11));
+    assert_eq!(reopened.len(), 300);
+    assert!(OffsetTree::<u32>::from_header(&backend, 11).unwrap().is_some());
+    assert!(OffsetTree::<u32>::from_header(&backend, 7).is_err());
+    let orders = SplitPolicy::Orders { leaf: 32, internal: 8 };
+    let mut tree = reopened.with_split_policy(orders);
+    tree.insert(&backend, &1000, 1000).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(OffsetTree::<u32>::reopen(&backend).unwrap().unwrap().split_policy(), orders);
+    // Separate orders don't match a single one.
+    assert!(OffsetTree::<u32>::from_header(&backend, 8).is_err());
+    let mut map = PersistentMap::<u16, String, _>::new(MemoryBackend::new(), 9);
+    map.insert(&1, &"one".to_string()).unwrap();
+    map.commit().unwrap();
+    assert!(PersistentMap::<u16, String, _>::open(map.into_backend(), 10).is_err());
+}
+
+#[test]
+fn test_header_version() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    for i in 0..100u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+    }
+    let header = tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(header.version, FORMAT_VERSION);
+    let bumped = Header { version: FORMAT_VERSION+1, ..header.clone() };
+    backend.store_header(&bumped).unwrap();
+    match OffsetTree::<u32>::reopen(&backend) {
+        Err(DecodingError::Corrupt(ref m)) => assert_eq!(*m, format!("unsupported version {}", FORMAT_VERSION+1)),
+        _ => panic!("Reopened a header from a future version."),
+    }
+    assert!(OffsetTree::<u32>::from_header(&backend, 7).is_err());
+    // A later version may lay out the rest of the header differently, and is still rejected by its version.
+    backend.store_header(&(FORMAT_VERSION+1, "something else entirely")).unwrap();
+    assert!(OffsetTree::<u32>::reopen(&backend).is_err());
+    backend.store_header(&header).unwrap();
+    assert_eq!(OffsetTree::<u32>::reopen(&backend).unwrap().unwrap().len(), 100);
+}
+
+#[test]
+fn test_free_list() {
+    use std::collections::BTreeMap;
+    let mut rng = XorShiftRng::from_seed([11, 11, 11, 11]);
+    let order = 7;
+    let mut backend = MemoryBackend::new();
+    let mut oracle = BTreeMap::new();
+    let mut tree = OffsetTree::empty(order);
+    tree.enable_free_list();
+    for i in 0..2000u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+        oracle.insert(i, i as u64);
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let initial_size = backend.len();
+    for round in 0..20 {
+        let previous = backend.load_header::<Header>().unwrap().unwrap();
+        let mut tree = OffsetTree::<u32>::from_header(&backend, order).unwrap().unwrap();
+        for _ in 0..100 {
+            let (k, v) = rng.gen::<(u32, u64)>();
+            let k = k%3000;
+            if v%2 == 0 {
+                assert_eq!(tree.remove(&backend, &k).unwrap(), oracle.remove(&k));
+            }
+            else {
+                tree.insert(&backend, &k, v).unwrap();
+                oracle.insert(k, v);
+            }
+        }
+        // A plain commit doesn't touch anything the last header needs.
+        tree.commit(&mut backend).unwrap();
+        assert!(!tree.free_list().unwrap().pending().is_empty());
+        let old = OffsetTree::<u32>::from_root_offset(previous.root, order, previous.len);
+        assert_eq!(old.iter(&backend).count() as u64, previous.len, "Round {}", round);
+        tree.commit_with_header(&mut backend).unwrap();
+        assert!(tree.free_list().unwrap().pending().is_empty());
+        let expected = oracle.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        let reopened = OffsetTree::<u32>::from_header(&backend, order).unwrap().unwrap();
+        assert_eq!(reopened.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), expected);
+    }
+    // Without reuse, every round would have added hundreds of nodes.
+    assert!(backend.len() < initial_size*2, "{} nodes, started with {}", backend.len(), initial_size);
+}
+
+#[test]
+fn test_free_list_file() {
+    let mut rng = XorShiftRng::from_seed([15, 15, 15, 15]);
+    let path = temp_path("free_list_file");
+    let order = 7;
+    let mut oracle = std::collections::BTreeMap::new();
+    let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+    let mut tree = OffsetTree::empty(order);
+    tree.enable_free_list();
+    for i in 0..2000u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+        oracle.insert(i, i as u64);
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let file_len = || std::fs::metadata(&path).unwrap().len();
+    let initial_len = file_len();
+    let mut lengths = vec![];
+    for _ in 0..60 {
+        for _ in 0..100 {
+            let (k, v) = rng.gen::<(u32, u64)>();
+            let k = k%3000;
+            if v%2 == 0 {
+                assert_eq!(tree.remove(&backend, &k).unwrap(), oracle.remove(&k));
+            }
+            else {
+                tree.insert(&backend, &k, v).unwrap();
+                oracle.insert(k, v);
+            }
+        }
+        tree.commit_with_header(&mut backend).unwrap();
+        lengths.push(file_len());
+    }
+    // Once the free list has enough slots, rewritten nodes and headers go into them rather than onto the end of the file.
+    assert!(lengths[40..].iter().all(|&l| l == lengths[39]), "{:?}", lengths);
+    assert!(lengths[59] < initial_len*3/2, "{} bytes, started with {}", lengths[59], initial_len);
+    drop(backend);
+    let backend = FileBackend::new(open_rw(&path)).unwrap();
+    let tree = OffsetTree::<u32>::from_header(&backend, order).unwrap().unwrap();
+    tree.validate(&backend).unwrap();
+    assert_eq!(tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), oracle.into_iter().collect::<Vec<_>>());
+    std::fs::remove_file(&path).unwrap();
+}
+
+// A MemoryBackend which keeps only the first `survive` writes, as if it crashed after them, but goes on reporting the rest as successful.
+struct CrashingBackend {
+    inner: MemoryBackend,
+    survive: usize,
+    writes: usize,
+    // How many headers were written before the crash.
+    headers: usize,
+}
+
+impl CrashingBackend {
+    fn new(survive: usize) -> CrashingBackend {
+        CrashingBackend { inner: MemoryBackend::new(), survive, writes: 0, headers: 0 }
+    }
+
+    fn survives(&mut self) -> bool {
+        self.writes += 1;
+        self.writes <= self.survive
+    }
+}
+
+impl StorageBackend for CrashingBackend {
+    fn load<V: serde::de::DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        self.inner.load(key)
+    }
+
+    fn store<V: serde::Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        if self.survives() {
+            return self.inner.store(value);
+        }
+        // A key the inner backend never hands out, so that loading it after the crash fails.
+        Ok(u64::MAX-self.writes as u64)
+    }
+
+    fn store_reusing<V: serde::Serialize>(&mut self, key: u64, value: &V) -> Result<Option<u64>, EncodingError> {
+        if self.survives() {
+            return self.inner.store_reusing(key, value);
+        }
+        Ok(Some(key))
+    }
+
+    fn store_header<V: serde::Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        if self.survives() {
+            self.headers += 1;
+            return self.inner.store_header(value);
+        }
+        Ok(())
+    }
+
+    fn load_header<V: serde::de::DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        self.inner.load_header()
+    }
+}
+
+// Returns what the tree held at each header it committed.
+fn crash_workload(backend: &mut CrashingBackend) -> Vec<Vec<(u32, u64)>> {
+    use std::collections::BTreeMap;
+    let mut rng = XorShiftRng::from_seed([82, 82, 82, 82]);
+    let mut oracle = BTreeMap::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.enable_free_list();
+    let mut committed = vec![];
+    for round in 0..12 {
+        for _ in 0..40 {
+            let (k, v) = rng.gen::<(u32, u64)>();
+            let k = k%300;
+            if round > 3 && v%3 == 0 {
+                assert_eq!(tree.remove(backend, &k).unwrap(), oracle.remove(&k));
+            }
+            else {
+                tree.insert(backend, &k, v).unwrap();
+                oracle.insert(k, v);
+            }
+        }
+        // Some commits don't write a header, so the crash can come after nodes nothing durable points at.
+        if round%3 == 1 {
+            tree.commit(backend).unwrap();
+        }
+        else {
+            tree.commit_with_header(backend).unwrap();
+            committed.push(oracle.iter().map(|(k, v)| (*k, *v)).collect());
+        }
+    }
+    committed
+}
+
+#[test]
+fn test_crash_consistency() {
+    let mut backend = CrashingBackend::new(usize::MAX);
+    let committed = crash_workload(&mut backend);
+    let total = backend.writes;
+    assert_eq!(backend.headers, committed.len());
+    // Some of the writes reused freed nodes in place.
+    assert!(backend.inner.len() < total-backend.headers, "{} nodes from {} writes", backend.inner.len(), total);
+    for survive in 0..=total {
+        let mut backend = CrashingBackend::new(survive);
+        assert_eq!(crash_workload(&mut backend), committed);
+        // Whatever was lost, the header is the last one written in full, and the tree it points at is all there.
+        match OffsetTree::<u32>::from_header(&backend.inner, 7).unwrap() {
+            None => assert_eq!(backend.headers, 0, "Lost the header after {} writes", survive),
+            Some(tree) => {
+                tree.validate(&backend.inner).unwrap();
+                let got = tree.iter(&backend.inner).collect::<Result<Vec<_>, _>>().unwrap();
+                assert_eq!(got, committed[backend.headers-1], "After {} writes", survive);
+            },
+        }
+    }
+}
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("append_tree_{}_{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn open_rw(path: &std::path::Path) -> std::fs::File {
+    std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap()
+}
+
+#[test]
+fn test_encoding_is_little_endian() {
+    // The bytes are spelled out, so this fails on a big-endian host if the encoding ever follows the host's byte order.
+    let path = temp_path("encoding_is_little_endian");
+    let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+    let mut tree = OffsetTree::empty(7);
+    for &k in &[1u32, 0x01020304] {
+        tree.insert(&backend, &k, k as u64+0x0a0b0c0d_00000000).unwrap();
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    drop(backend);
+    let mut leaf = vec![FORMAT_VERSION];
+    leaf.extend_from_slice(&2u32.to_le_bytes()); // A leaf.
+    leaf.extend_from_slice(&2u64.to_le_bytes());
+    leaf.extend_from_slice(&1u32.to_le_bytes());
+    leaf.extend_from_slice(&0x01020304u32.to_le_bytes());
+    leaf.extend_from_slice(&0u64.to_le_bytes());
+    leaf.extend_from_slice(&2u64.to_le_bytes());
+    leaf.extend_from_slice(&0x0a0b0c0d_00000001u64.to_le_bytes());
+    leaf.extend_from_slice(&0x0a0b0c0d_01020304u64.to_le_bytes());
+    leaf.extend_from_slice(&0u64.to_le_bytes());
+    leaf.extend_from_slice(&0u64.to_le_bytes());
+    let mut header = vec![FORMAT_VERSION];
+    header.extend_from_slice(&8u64.to_le_bytes());
+    header.extend_from_slice(&2u64.to_le_bytes());
+    header.push(0); // No free list.
+    header.extend_from_slice(&0u32.to_le_bytes()); // SplitPolicy::Order.
+    header.extend_from_slice(&7u64.to_le_bytes());
+    // Each record is in a slot which starts with its size, and the header's slot is rounded up to a power of two.
+    let header_at = 8+8+leaf.len() as u64;
+    let header_slot = header.len().next_power_of_two();
+    let mut expected = header_at.to_le_bytes().to_vec();
+    expected.extend_from_slice(&(leaf.len() as u64).to_le_bytes());
+    expected.extend_from_slice(&leaf);
+    expected.extend_from_slice(&(header_slot as u64).to_le_bytes());
+    expected.extend_from_slice(&header);
+    expected.resize(expected.len()+header_slot-header.len(), 0);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+    // And the same bytes read back.
+    std::fs::write(&path, &expected).unwrap();
+    let backend = FileBackend::new(open_rw(&path)).unwrap();
+    let tree = OffsetTree::<u32>::reopen(&backend).unwrap().unwrap();
+    assert_eq!(tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), vec![(1, 0x0a0b0c0d_00000001), (0x01020304, 0x0a0b0c0d_01020304)]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_file_backend_round_trip() {
+    let path = temp_path("file_backend_round_trip");
+    let values = [
+        (1u32, "one".to_string()),
+        (2, "two".to_string()),
+        (3, String::new()),
+        (4, "a much longer string than the others".to_string()),
+        // Shorter than the one before, so none of that one's bytes can be left over.
+        (5, "five".to_string()),
+    ];
+    let keys = {
+        let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+        assert!(backend.load_header::<u64>().unwrap().is_none());
+        let keys = values.iter().map(|v| backend.store(v).unwrap()).collect::<Vec<_>>();
+        backend.store_header(&12345u64).unwrap();
+        backend.flush().unwrap();
+        keys
+    };
+    let backend = FileBackend::new(open_rw(&path)).unwrap();
+    for (k, v) in keys.iter().zip(values.iter()) {
+        assert_eq!(&backend.load::<(u32, String)>(*k).unwrap(), v);
+    }
+    assert_eq!(backend.load_header::<u64>().unwrap(), Some(12345));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_file_backend_capacity() {
+    let path = temp_path("file_backend_capacity");
+    let values = (0..200u64).map(|i| (i, i.to_string())).collect::<Vec<_>>();
+    let (keys, used) = {
+        let mut backend = FileBackend::with_read_buffer(open_rw(&path), 4096).unwrap().with_capacity(1 << 16).unwrap();
+        let keys = values.iter().map(|v| backend.store(v).unwrap()).collect::<Vec<_>>();
+        backend.store_header(&keys.len()).unwrap();
+        // Everything went at the front of the reservation, one record after another.
+        assert_eq!(keys[0], 8);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let file_len = || std::fs::metadata(&path).unwrap().len();
+        assert_eq!(file_len(), 1 << 16);
+        backend.trim().unwrap();
+        let used = file_len();
+        assert!(used < 1 << 14);
+        // Trimming only gives back what wasn't used, and more can be stored afterward.
+        let key = backend.store(&values[0]).unwrap();
+        assert_eq!(key, used);
+        assert_eq!(backend.load::<(u64, String)>(key).unwrap(), values[0]);
+        (keys, used)
+    };
+    {
+        let backend = FileBackend::new(open_rw(&path)).unwrap();
+        assert_eq!(backend.load_header::<usize>().unwrap(), Some(values.len()));
+        for (&k, v) in keys.iter().zip(values.iter()) {
+            assert_eq!(&backend.load::<(u64, String)>(k).unwrap(), v);
+        }
+    }
+    // A reservation smaller than the file leaves it as it is, and records still go at the end.
+    let mut backend = FileBackend::new(open_rw(&path)).unwrap().with_capacity(100).unwrap();
+    assert!(backend.store(&values[1]).unwrap() > used);
+    assert_eq!(backend.load_header::<usize>().unwrap(), Some(values.len()));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_store_batch() {
+    let path = temp_path("store_batch");
+    let values = (0..100u32).map(|i| (i, "x".repeat(i as usize%7))).collect::<Vec<_>>();
+    let (batched, single) = {
+        let mut backend = CachedBackend::new(ChecksumBackend::new(FileBackend::new(open_rw(&path)).unwrap()), 10);
+        let batched = backend.store_batch(&values).unwrap();
+        // Anything stored afterwards goes after the whole batch.
+        let single = backend.store(&values[0]).unwrap();
+        assert!(batched.iter().all(|&k| k < single));
+        assert!(backend.store_batch::<u8>(&[]).unwrap().is_empty());
+        backend.flush().unwrap();
+        (batched, single)
+    };
+    let backend = CachedBackend::new(ChecksumBackend::new(FileBackend::new(open_rw(&path)).unwrap()), 10);
+    for (&k, v) in batched.iter().zip(values.iter()) {
+        assert_eq!(&backend.load::<(u32, String)>(k).unwrap(), v);
+    }
+    assert_eq!(backend.load::<(u32, String)>(single).unwrap(), values[0]);
+    std::fs::remove_file(&path).unwrap();
+
+    let mut map = PersistentMap::new(MemoryBackend::new(), 7);
+    map.insert(&1u32, &"old".to_string()).unwrap();
+    let pairs = (0..50u32).map(|i| (i%40, format!("value {}", i))).collect::<Vec<_>>();
+    map.insert_batch(&pairs).unwrap();
+    assert_eq!(map.len(), 40);
+    // The later of two values for a key wins.
+    assert_eq!(map.get(&1).unwrap(), Some("value 41".to_string()));
+    assert_eq!(map.get(&39).unwrap(), Some("value 39".to_string()));
+    map.commit().unwrap();
+    // The old value and the ten overwritten in the batch are freed.
+    assert!(map.tree().free_list().unwrap().available().len() >= 11);
+}
+
+#[test]
+fn test_open_or_create() {
+    let path = temp_path("open_or_create");
+    {
+        let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+        let mut tree = OffsetTree::<u64>::open_or_create(&mut backend, 9).unwrap();
+        assert!(tree.is_empty());
+        // The empty tree is recorded straight away.
+        assert_eq!(backend.load_header::<Header>().unwrap().map(|h| h.len), Some(0));
+        for i in 0..500u64 {
+            tree.insert(&backend, &i, i*2).unwrap();
+        }
+        tree.commit_with_header(&mut backend).unwrap();
+    }
+    {
+        let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+        let mut tree = OffsetTree::<u64>::open_or_create(&mut backend, 9).unwrap();
+        assert_eq!(tree.len(), 500);
+        assert_eq!(tree.offset_for(&backend, &250).unwrap(), Some(500));
+        tree.insert(&backend, &1000, 1).unwrap();
+        tree.commit_with_header(&mut backend).unwrap();
+        // A header for another order is an error rather than a reason to start over.
+        assert!(OffsetTree::<u64>::open_or_create(&mut backend, 10).is_err());
+    }
+    let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+    let tree = OffsetTree::<u64>::open_or_create(&mut backend, 9).unwrap();
+    assert_eq!(tree.len(), 501);
+    assert_eq!(tree.offset_for(&backend, &1000).unwrap(), Some(1));
+    std::fs::remove_file(&path).unwrap();
+
+    // Nor is a header which can't be read, which stays as it was.
+    let mut backend = MemoryBackend::new();
+    backend.store_header(&"not a header".to_string()).unwrap();
+    match OffsetTree::<u64>::open_or_create(&mut backend, 9) {
+        Err(Error::Decoding(DecodingError::Corrupt(_))) => (),
+        other => panic!("{:?}", other.map(|t| t.len())),
+    }
+    assert_eq!(backend.load_header::<String>().unwrap(), Some("not a header".to_string()));
+}
+
+#[test]
+fn test_file_backend_tree() {
+    let path = temp_path("file_backend_tree");
+    let order = 7;
+    {
+        let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+        let mut tree = OffsetTree::empty(order);
+        for i in 0..3000u64 {
+            tree.insert(&backend, &i, i*2).unwrap();
+        }
+        tree.commit_with_header(&mut backend).unwrap();
+    }
+    let backend = FileBackend::new(open_rw(&path)).unwrap();
+    let tree = OffsetTree::<u64>::from_header(&backend, order).unwrap().unwrap();
+    assert_eq!(tree.len(), 3000);
+    assert_eq!(tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), (0..3000).map(|i| (i, i*2)).collect::<Vec<_>>());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_reproducible_commits() {
+    let mut rng = XorShiftRng::from_seed([95, 95, 95, 95]);
+    let first = temp_path("reproducible_commits_first");
+    let second = temp_path("reproducible_commits_second");
+    let mut keys = (0..3000u64).collect::<Vec<_>>();
+    rng.shuffle(&mut keys);
+    let mut tree = OffsetTree::empty(9);
+    for &k in &keys {
+        tree.insert(&MemoryBackend::new(), &k, k*2).unwrap();
+    }
+    // The fork shares every node with the tree, and commits its own copies of them.
+    let mut fork = tree.fork();
+    let mut first_backend = FileBackend::new(open_rw(&first)).unwrap();
+    let mut second_backend = FileBackend::new(open_rw(&second)).unwrap();
+    tree.commit_with_header(&mut first_backend).unwrap();
+    fork.commit_with_header(&mut second_backend).unwrap();
+    // Changing both trees the same way afterward rewrites the same nodes.
+    for &k in &keys[..500] {
+        tree.remove(&first_backend, &k).unwrap();
+        fork.remove(&second_backend, &k).unwrap();
+    }
+    for k in 5000..5100u64 {
+        tree.insert(&first_backend, &k, k).unwrap();
+        fork.insert(&second_backend, &k, k).unwrap();
+    }
+    tree.commit_with_header(&mut first_backend).unwrap();
+    fork.commit_with_header(&mut second_backend).unwrap();
+    drop((first_backend, second_backend));
+    let bytes = std::fs::read(&first).unwrap();
+    assert!(bytes.len() > 10000);
+    assert!(bytes == std::fs::read(&second).unwrap());
+    std::fs::remove_file(&first).unwrap();
+    std::fs::remove_file(&second).unwrap();
+}
+
+#[test]
+fn test_from_sorted_file() {
+    let path = temp_path("from_sorted_file");
+    {
+        let (tree, backend) = OffsetTree::from_sorted_file(open_rw(&path), 16, (0..20_000u64).map(|i| (i*3, i))).unwrap();
+        assert_eq!(tree.len(), 20_000);
+        assert_eq!(tree.offset_for(&backend, &300).unwrap(), Some(100));
+        tree.validate(&backend).unwrap();
+    }
+    let backend = FileBackend::new(open_rw(&path)).unwrap();
+    let tree = OffsetTree::<u64>::from_header(&backend, 16).unwrap().unwrap();
+    assert_eq!(tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), (0..20_000).map(|i| (i*3, i)).collect::<Vec<_>>());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_builder_unsorted() {
+    let mut backend = MemoryBackend::new();
+    OffsetTree::<u64>::empty(7).commit_with_header(&mut backend).unwrap();
+    {
+        let mut builder = OffsetTreeBuilder::new(&mut backend, 7);
+        match builder.extend(vec![(1u64, 1), (5, 2), (5, 3)]) {
+            Err(Error::Unsorted(UnsortedError { index: 2 })) => (),
+            other => panic!("Expected the third pair to be out of order, got {:?}", other),
+        }
+    }
+    // The builder never finished, so the header still has the tree from before.
+    assert!(OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap().is_empty());
+}
+
+#[test]
+fn test_file_backend_read_buffer() {
+    let path = temp_path("file_backend_read_buffer");
+    {
+        let mut backend = FileBackend::with_read_buffer(open_rw(&path), 4096).unwrap();
+        let mut tree = OffsetTree::empty(7);
+        for i in 0..3000u64 {
+            tree.insert(&backend, &i, i*2).unwrap();
+            // Reads and writes interleave, and reads have to see everything written before them.
+            if i%500 == 0 {
+                tree.commit_with_header(&mut backend).unwrap();
+                let reopened = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+                assert_eq!(reopened.offset_for(&backend, &i).unwrap(), Some(i*2));
+            }
+        }
+        tree.commit_with_header(&mut backend).unwrap();
     }
+    // Windows smaller than a node mean reads which span several windows, or skip them.
+    for &capacity in &[1, 16, 4096, 1 << 20] {
+        let backend = FileBackend::with_read_buffer(open_rw(&path), capacity).unwrap();
+        let tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+        assert_eq!(tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), (0..3000).map(|i| (i, i*2)).collect::<Vec<_>>());
+        assert_eq!(tree.iter_rev(&backend).count(), 3000);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_file_backend_record_limit() {
+    use std::io::{Seek, Write};
+    let path = temp_path("file_backend_record_limit");
+    let (bogus, overrun) = {
+        let mut backend = FileBackend::new(open_rw(&path)).unwrap();
+        let mut tree = OffsetTree::empty(7);
+        for i in 0..3000u64 {
+            tree.insert(&backend, &i, i).unwrap();
+        }
+        tree.commit_with_header(&mut backend).unwrap();
+        (backend.store(&"a string".to_string()).unwrap(), backend.store(&"another".to_string()).unwrap())
+    };
+    // A terabyte of string, which this is nowhere near.
+    let mut file = open_rw(&path);
+    file.seek(std::io::SeekFrom::Start(bogus)).unwrap();
+    file.write_all(&(1u64 << 40).to_le_bytes()).unwrap();
+    // Past the slot's size, the string's own length prefix.
+    file.seek(std::io::SeekFrom::Start(overrun+8)).unwrap();
+    file.write_all(&(1u64 << 40).to_le_bytes()).unwrap();
+    drop(file);
+    // Even without a limit, a record can't claim more than its slot.
+    match FileBackend::new(open_rw(&path)).unwrap().load::<String>(overrun) {
+        Err(DecodingError::Corrupt(message)) => assert!(message.contains("slot"), "{}", message),
+        other => panic!("Expected a corrupt record, got {:?}", other),
+    }
+    for &capacity in &[0, 4096] {
+        let backend = FileBackend::with_read_buffer(open_rw(&path), capacity).unwrap().with_record_limit(1 << 20);
+        match backend.load::<String>(bogus) {
+            Err(DecodingError::Corrupt(message)) => assert!(message.contains("limit"), "{}", message),
+            other => panic!("Expected a corrupt record, got {:?}", other),
+        }
+        // Everything which is the size it says still loads.
+        let tree = OffsetTree::<u64>::reopen(&backend).unwrap().unwrap();
+        assert_eq!(tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap(), (0..3000).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+    // A limit smaller than the nodes rejects them too.
+    let backend = FileBackend::new(open_rw(&path)).unwrap().with_record_limit(16);
+    match OffsetTree::<u64>::reopen(&backend) {
+        Err(DecodingError::Corrupt(_)) => (),
+        _ => panic!("Expected the header to be too large"),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_prefetch() {
+    let mut backend = CachedBackend::new(MemoryBackend::new(), 0);
+    let mut tree = OffsetTree::empty(9);
+    tree.extend(&backend, (0..5000u64).map(|i| (i, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let tree = OffsetTree::<u64>::from_header(&backend, 9).unwrap().unwrap();
+    for &(start, end) in &[(0, 5000), (1000, 1001), (1234, 3456), (4990, 6000), (6000, 7000)] {
+        backend.reset_counters();
+        let plain = tree.range(&backend, start..end).collect::<Result<Vec<_>, _>>().unwrap();
+        let plain_loads = backend.misses();
+        backend.reset_counters();
+        let prefetched = tree.range(&backend, start..end).prefetch(true).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(plain, prefetched);
+        assert_eq!(prefetched, (start..std::cmp::min(end, 5000)).map(|i| (i, i)).collect::<Vec<_>>());
+        // Nothing well past the range is loaded.
+        assert!(backend.misses() <= plain_loads+5, "{} loads, but {} without prefetching", backend.misses(), plain_loads);
+    }
+    // Prefetching loads the children of a node on entering it, rather than one at a time.
+    backend.reset_counters();
+    tree.range(&backend, 1000..2000).next().unwrap().unwrap();
+    let plain_loads = backend.misses();
+    backend.reset_counters();
+    tree.range(&backend, 1000..2000).prefetch(true).next().unwrap().unwrap();
+    assert!(backend.misses() > plain_loads);
+}
+
+#[test]
+fn test_get_batch() {
+    let mut rng = XorShiftRng::from_seed([59, 59, 59, 59]);
+    let mut backend = CachedBackend::new(MemoryBackend::new(), 0);
+    let mut tree = OffsetTree::empty(9);
+    tree.extend(&backend, (0..5000u64).map(|i| (i*2, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let tree = OffsetTree::<u64>::from_header(&backend, 9).unwrap().unwrap();
+    assert!(tree.get_batch(&backend, &[]).unwrap().is_empty());
+    let expected = |keys: &[u64]| keys.iter().map(|&k| if k%2 == 0 && k < 10000 { Some(k/2) } else { None }).collect::<Vec<_>>();
+    let mut keys = (0..2000).map(|_| rng.gen_range(0, 11000u64)).collect::<Vec<_>>();
+    // Out of order and repeated keys are still right.
+    assert_eq!(tree.get_batch(&backend, &keys).unwrap(), expected(&keys));
+    keys.sort();
+    let stats = OffsetTree::<u64>::from_header(&backend, 9).unwrap().unwrap().stats(&backend).unwrap();
+    backend.reset_counters();
+    assert_eq!(tree.get_batch(&backend, &keys).unwrap(), expected(&keys));
+    // Sorted keys visit each node once, and there are fewer nodes than keys.
+    assert!(backend.misses() <= stats.internal_nodes+stats.leaves);
+    // Keys in the same leaf only go down once.
+    backend.reset_counters();
+    assert_eq!(tree.get_batch(&backend, &[4000, 4001, 4002]).unwrap(), vec![Some(2000), None, Some(2001)]);
+    assert_eq!(backend.misses(), stats.height);
 }
 
+#[test]
+fn test_peek() {
+    let mut backend = CachedBackend::new(MemoryBackend::new(), 0);
+    let mut tree = OffsetTree::empty(9);
+    tree.extend(&backend, (0..5000u64).map(|i| (i*2, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let stats = tree.stats(&backend).unwrap();
+    let backend = CachedBackend::new(backend.into_inner(), 10000);
+    let tree = OffsetTree::<u64>::from_header(&backend, 9).unwrap().unwrap();
+    for k in 0..10000u64 {
+        assert_eq!(tree.peek_offset_for(&backend, &k).unwrap(), if k%2 == 0 { Some(k/2) } else { None });
+    }
+    // Every lookup read its leaf, and none of them went into the cache.
+    assert_eq!(backend.hits(), 0);
+    assert!(backend.misses() >= 10000);
+    // Only the leaf has to be read for a lookup after that, since the internal nodes stayed in the tree.
+    backend.reset_counters();
+    assert_eq!(tree.offset_for(&backend, &4000).unwrap(), Some(2000));
+    assert_eq!((backend.hits(), backend.misses()), (0, 1));
+    // A leaf already loaded into the tree isn't read again.
+    backend.reset_counters();
+    assert_eq!(tree.peek(&backend, &4000).unwrap(), Some(2000));
+    assert_eq!((backend.hits(), backend.misses()), (0, 0));
+    // Peeking at a leaf which is cached is still a hit, though the internal nodes peeked at before weren't cached.
+    // The root is loaded by opening the tree.
+    let tree = OffsetTree::<u64>::from_header(&backend, 9).unwrap().unwrap();
+    backend.reset_counters();
+    assert_eq!(tree.peek_offset_for(&backend, &4000).unwrap(), Some(2000));
+    assert_eq!((backend.hits(), backend.misses()), (1, stats.height-2));
+}
+
+#[test]
+fn test_concurrent_reads() {
+    fn assert_sync<T: Sync+Send>(_: &T) {}
+    let path = temp_path("concurrent_reads");
+    let mut backend = CachedBackend::new(FileBackend::with_read_buffer(open_rw(&path), 4096).unwrap(), 50);
+    let mut tree = OffsetTree::empty(9);
+    tree.extend(&backend, (0..20000u64).map(|i| (i, i*3))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    // Nothing but the root is loaded, so the threads race to load the rest.
+    let tree = OffsetTree::<u64>::from_header(&backend, 9).unwrap().unwrap();
+    assert_sync(&tree);
+    assert_sync(&backend);
+    std::thread::scope(|s| {
+        for t in 0..8u64 {
+            let (tree, backend) = (&tree, &backend);
+            s.spawn(move || {
+                let mut rng = XorShiftRng::from_seed([64, t as u32+1, 64, 64]);
+                for _ in 0..5000 {
+                    let k = rng.gen_range(0, 21000u64);
+                    assert_eq!(tree.offset_for(backend, &k).unwrap(), if k < 20000 { Some(k*3) } else { None });
+                }
+                assert_eq!(tree.range(backend, t*1000..t*1000+500).count(), 500);
+            });
+        }
+    });
+    tree.validate(&backend).unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_cached_backend() {
+    let mut backend = CachedBackend::new(MemoryBackend::new(), 2);
+    let a = backend.store(&"a".to_string()).unwrap();
+    let b = backend.store(&"b".to_string()).unwrap();
+    let c = backend.store(&"c".to_string()).unwrap();
+    backend.reset_counters();
+    // a fell out when c went in.
+    assert_eq!(backend.load::<String>(c).unwrap(), "c");
+    assert_eq!(backend.load::<String>(b).unwrap(), "b");
+    assert_eq!((backend.hits(), backend.misses()), (2, 0));
+    assert_eq!(backend.load::<String>(a).unwrap(), "a");
+    assert_eq!((backend.hits(), backend.misses()), (2, 1));
+    // Loading a evicted c, the least recently used.
+    assert_eq!(backend.load::<String>(b).unwrap(), "b");
+    assert_eq!(backend.load::<String>(c).unwrap(), "c");
+    assert_eq!((backend.hits(), backend.misses()), (3, 2));
+}
+
+#[test]
+fn test_cached_backend_tree() {
+    let order = 7;
+    let mut backend = CachedBackend::new(MemoryBackend::new(), 256);
+    let mut tree = OffsetTree::empty(order);
+    for i in 0..5000u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    backend.reset_counters();
+    for _ in 0..3 {
+        let tree = OffsetTree::<u32>::from_header(&backend, order).unwrap().unwrap();
+        for i in (0..5000u32).step_by(500) {
+            assert_eq!(tree.offset_for(&backend, &i).unwrap(), Some(i as u64));
+        }
+    }
+    // The upper levels of the tree stay cached across reopens.
+    assert!(backend.hits() > backend.misses(), "{} hits, {} misses", backend.hits(), backend.misses());
+}
+
+#[cfg(feature = "deflate")]
+#[test]
+fn test_compressed_backend() {
+    use append_tree::{CompressedBackend, Deflate};
+    let mut rng = XorShiftRng::from_seed([18, 18, 18, 18]);
+    // Node-sized blobs: sorted keys with small gaps, which is what real nodes look like.
+    let blobs: Vec<Vec<u64>> = (0..100).map(|_| {
+        let mut key = rng.gen_range(0u64, 1 << 40);
+        (0..64).map(|_| {
+            key += rng.gen_range(1, 100);
+            key
+        }).collect()
+    }).collect();
+    let mut plain = MemoryBackend::new();
+    let mut backend = CompressedBackend::new(MemoryBackend::new(), Deflate::default());
+    let mut keys = Vec::new();
+    for blob in blobs.iter() {
+        plain.store(blob).unwrap();
+        keys.push(backend.store(blob).unwrap());
+    }
+    assert!(backend.inner().stored_bytes() < plain.stored_bytes(),
+        "{} compressed vs {} plain", backend.inner().stored_bytes(), plain.stored_bytes());
+    for (key, blob) in keys.iter().zip(blobs.iter()) {
+        assert_eq!(&backend.load::<Vec<u64>>(*key).unwrap(), blob);
+    }
+}
+
+#[cfg(feature = "deflate")]
+#[test]
+fn test_compressed_file_backend_tree() {
+    use append_tree::{CompressedBackend, Deflate};
+    let path = temp_path("compressed_tree");
+    let order = 7;
+    {
+        let mut backend = CompressedBackend::new(FileBackend::new(open_rw(&path)).unwrap(), Deflate::new(9));
+        let mut tree = OffsetTree::empty(order);
+        for i in 0..1000u32 {
+            tree.insert(&backend, &i, i as u64*2).unwrap();
+        }
+        tree.commit_with_header(&mut backend).unwrap();
+    }
+    let backend = CompressedBackend::new(FileBackend::new(open_rw(&path)).unwrap(), Deflate::new(9));
+    let tree = OffsetTree::<u32>::from_header(&backend, order).unwrap().unwrap();
+    let collected: Vec<(u32, u64)> = tree.iter(&backend).map(|r| r.unwrap()).collect();
+    assert_eq!(collected, (0..1000u32).map(|i| (i, i as u64*2)).collect::<Vec<_>>());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_checksum_backend() {
+    use std::io::{Seek, SeekFrom, Write};
+    let path = temp_path("checksum_backend");
+    let (good, bad) = {
+        let mut backend = ChecksumBackend::new(FileBackend::new(open_rw(&path)).unwrap());
+        let good = backend.store(&vec![1u64, 2, 3]).unwrap();
+        let bad = backend.store(&vec![4u64, 5, 6]).unwrap();
+        (good, bad)
+    };
+    // Flip a byte in the middle of the second payload, after the slot's size, the checksum, and the length prefix.
+    {
+        let mut file = open_rw(&path);
+        file.seek(SeekFrom::Start(bad+8+4+8+8+3)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+    }
+    let backend = ChecksumBackend::new(FileBackend::new(open_rw(&path)).unwrap());
+    assert_eq!(backend.load::<Vec<u64>>(good).unwrap(), vec![1, 2, 3]);
+    match backend.load::<Vec<u64>>(bad) {
+        Err(DecodingError::Corrupt(msg)) => assert!(msg.contains(&bad.to_string()), "{}", msg),
+        other => panic!("Expected corruption, got {:?}", other),
+    }
+    // Without the checksum the damaged record decodes to the wrong value.
+    let raw: (u32, Vec<u8>) = backend.inner().load(bad).unwrap();
+    let decoded: Vec<u64> = bincode::deserialize(&raw.1).unwrap();
+    assert_ne!(decoded, vec![4, 5, 6]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error;
+    let io = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "short read");
+    let boxed: Box<dyn Error> = Box::new(DecodingError::IoError(io()));
+    assert_eq!(boxed.to_string(), "I/O error while decoding: short read");
+    assert_eq!(boxed.source().unwrap().to_string(), "short read");
+    assert!(DecodingError::Corrupt("bad".to_string()).source().is_none());
+    assert!(EncodingError::IoError(io()).source().is_some());
+    assert!(EncodingError::Unknown("bad".to_string()).source().is_none());
+}
+
+#[test]
+fn test_persistent_map() {
+    use std::collections::BTreeMap;
+    let mut rng = XorShiftRng::from_seed([26, 26, 26, 26]);
+    let mut map = PersistentMap::new(MemoryBackend::new(), 7);
+    let mut oracle = BTreeMap::new();
+    for _ in 0..3000 {
+        let k = rng.gen::<u16>()%500;
+        let v = format!("value {}", rng.gen::<u32>());
+        map.insert(&k, &v).unwrap();
+        oracle.insert(k, v);
+    }
+    for k in 0..250u16 {
+        assert_eq!(map.remove(&k).unwrap(), oracle.remove(&k));
+    }
+    assert_eq!(map.len(), oracle.len() as u64);
+    map.commit().unwrap();
+    let stored = map.backend().len();
+    let mut map: PersistentMap<u16, String, _> = PersistentMap::open(map.into_backend(), 7).unwrap();
+    // Reads only need a shared map.
+    let shared = &map;
+    for k in 0..500u16 {
+        assert_eq!(shared.get(&k).unwrap().as_ref(), oracle.get(&k));
+        assert_eq!(shared.contains_key(&k).unwrap(), oracle.contains_key(&k));
+    }
+    // The first commit freed the overwritten and removed values, so replacing values reuses their space instead of growing the backend.
+    for k in 250..500u16 {
+        map.insert(&k, &"replaced".to_string()).unwrap();
+    }
+    map.commit().unwrap();
+    for k in 250..500u16 {
+        map.insert(&k, &"again".to_string()).unwrap();
+    }
+    map.commit().unwrap();
+    assert_eq!(map.backend().len(), stored);
+    assert_eq!(map.get(&300).unwrap(), Some("again".to_string()));
+}
+
+// A MemoryBackend whose values can't be loaded once `unreadable` is set, though its header still can.
+#[derive(Default)]
+struct UnreadableBackend {
+    inner: MemoryBackend,
+    unreadable: std::cell::Cell<bool>,
+}
+
+impl StorageBackend for UnreadableBackend {
+    fn load<V: serde::de::DeserializeOwned>(&self, key: u64) -> Result<V, DecodingError> {
+        if self.unreadable.get() {
+            return Err(DecodingError::Corrupt(format!("Can't read key {}", key)));
+        }
+        self.inner.load(key)
+    }
+
+    fn store<V: serde::Serialize>(&mut self, value: &V) -> Result<u64, EncodingError> {
+        self.inner.store(value)
+    }
+
+    fn store_header<V: serde::Serialize>(&mut self, value: &V) -> Result<(), EncodingError> {
+        self.inner.store_header(value)
+    }
+
+    fn load_header<V: serde::de::DeserializeOwned>(&self) -> Result<Option<V>, DecodingError> {
+        self.inner.load_header()
+    }
+}
+
+#[test]
+fn test_persistent_map_failed_insert() {
+    let mut map = PersistentMap::new(UnreadableBackend::default(), 7);
+    for k in 0..100u16 {
+        map.insert(&k, &k.to_string()).unwrap();
+    }
+    map.commit().unwrap();
+    let mut map: PersistentMap<u16, String, _> = PersistentMap::open(map.into_backend(), 7).unwrap();
+    let next_key = map.backend().inner.len() as u64;
+    map.backend().unreadable.set(true);
+    // Opening loaded only the root, so the value is stored before the tree finds out that it can't load the leaf.
+    assert!(map.insert(&1000, &"new".to_string()).is_err());
+    assert_eq!(map.tree().free_list().unwrap().pending(), &[next_key]);
+}
+
+#[test]
+fn test_persistent_map_failed_insert_batch() {
+    let mut map = PersistentMap::new(UnreadableBackend::default(), 7);
+    for k in 0..100u16 {
+        map.insert(&k, &k.to_string()).unwrap();
+    }
+    map.commit().unwrap();
+    let mut map: PersistentMap<u16, String, _> = PersistentMap::open(map.into_backend(), 7).unwrap();
+    // Looking 0 up keeps its leaf, so the first pair goes in and the second is the one which fails.
+    let old = map.tree().offset_for(map.backend(), &0).unwrap().unwrap();
+    let next_key = map.backend().inner.len() as u64;
+    map.backend().unreadable.set(true);
+    let pairs = [(0, "zero".to_string()), (1000, "new".to_string()), (1001, "newer".to_string())];
+    assert!(map.insert_batch(&pairs).is_err());
+    assert_eq!(map.tree().free_list().unwrap().pending(), &[old, next_key+1, next_key+2]);
+    assert_eq!(map.len(), 100);
+}
+
+#[test]
+fn test_persistent_map_iter() {
+    let mut rng = XorShiftRng::from_seed([27, 27, 27, 27]);
+    let mut map = PersistentMap::new(MemoryBackend::new(), 7);
+    assert_eq!(map.iter().count(), 0);
+    let mut keys = (0..2000u32).collect::<Vec<_>>();
+    rng.shuffle(&mut keys);
+    for k in keys.iter() {
+        map.insert(k, &(k.to_string(), *k as u64*3)).unwrap();
+    }
+    map.commit().unwrap();
+    let map: PersistentMap<u32, (String, u64), _> = PersistentMap::open(map.into_backend(), 7).unwrap();
+    let got = map.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    let expected = (0..2000u32).map(|k| (k, (k.to_string(), k as u64*3))).collect::<Vec<_>>();
+    assert_eq!(got, expected);
+}
+
+#[test]
+fn test_bptree_values() {
+    use std::collections::BTreeMap;
+    let mut rng = XorShiftRng::from_seed([28, 28, 28, 28]);
+    let mut backend = MemoryBackend::new();
+    let mut tree = BPTree::empty(7);
+    let mut oracle = BTreeMap::new();
+    for _ in 0..3000 {
+        let k = rng.gen::<u16>()%1000;
+        let v = (format!("{}", rng.gen::<u32>()), rng.gen::<bool>());
+        assert_eq!(tree.insert(&backend, &k, v.clone()).unwrap(), oracle.insert(k, v));
+    }
+    for k in 0..300u16 {
+        assert_eq!(tree.remove(&backend, &k).unwrap(), oracle.remove(&k));
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree: BPTree<u16, (String, bool)> = BPTree::from_header(&backend, 7).unwrap().unwrap();
+    assert_eq!(tree.len(), oracle.len() as u64);
+    for k in 0..1000u16 {
+        assert_eq!(tree.get(&backend, &k).unwrap().as_ref(), oracle.get(&k));
+    }
+    let got = tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(got, oracle.iter().map(|(k, v)| (*k, v.clone())).collect::<Vec<_>>());
+    assert_eq!(tree.first(&backend).unwrap(), oracle.iter().next().map(|(k, v)| (*k, v.clone())));
+    assert_eq!(tree.get_or_insert_with(&backend, &5000, || ("new".to_string(), true)).unwrap(), ("new".to_string(), true));
+    assert_eq!(tree.len(), oracle.len() as u64+1);
+}
+
+#[test]
+fn test_multi_offset_tree() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = MultiOffsetTree::empty(7);
+    // Key 50 has enough offsets that a leaf couldn't hold them as separate entries.
+    for i in 0..100u64 {
+        tree.insert_multi(&backend, &(i as u32), i).unwrap();
+    }
+    for i in 1000..3000 {
+        tree.insert_multi(&backend, &50u32, i).unwrap();
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let tree = MultiOffsetTree::<u32>::from_header(&backend, 7).unwrap().unwrap();
+    assert_eq!(tree.len(), 100);
+    let mut expected = vec![50];
+    expected.extend(1000..3000);
+    assert_eq!(tree.offsets_for(&backend, &50).unwrap(), expected);
+    assert_eq!(tree.offsets_for(&backend, &7).unwrap(), vec![7]);
+    assert!(tree.offsets_for(&backend, &500).unwrap().is_empty());
+    let scanned = tree.range(&backend, 49..52).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(scanned, vec![(49, vec![49]), (50, expected), (51, vec![51])]);
+}
+
+struct Descending;
+
+impl Comparator<u32> for Descending {
+    fn compare(&self, a: &u32, b: &u32) -> std::cmp::Ordering {
+        b.cmp(a)
+    }
+}
+
+#[test]
+fn test_comparator() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7).with_comparator(Descending);
+    for i in 0..1000u32 {
+        tree.insert(&backend, &i, i as u64 * 2).unwrap();
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u32>::from_header(&backend, 7).unwrap().unwrap().with_comparator(Descending);
+    for i in 0..1000u32 {
+        assert_eq!(tree.offset_for(&backend, &i).unwrap(), Some(i as u64 * 2));
+    }
+    let keys = tree.iter(&backend).map(|r| r.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(keys, (0..1000).rev().collect::<Vec<_>>());
+    let keys = tree.range(&backend, (std::ops::Bound::Included(500), std::ops::Bound::Excluded(490))).map(|r| r.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(keys, (491..=500).rev().collect::<Vec<_>>());
+    assert_eq!(tree.first(&backend).unwrap(), Some((999, 1998)));
+    assert_eq!(tree.remove(&backend, &999).unwrap(), Some(1998));
+    assert_eq!(tree.first(&backend).unwrap(), Some((998, 1996)));
+    // Closures work too.
+    let mut tree = OffsetTree::empty(7).with_comparator(|a: &u32, b: &u32| (a % 10).cmp(&(b % 10)).then(a.cmp(b)));
+    for i in 0..100u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+    }
+    let keys = tree.iter(&backend).take(3).map(|r| r.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(keys, vec![0, 10, 20]);
+}
+
+#[test]
+fn test_deep_tree() {
+    // The smallest order makes the tallest tree.
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(MIN_ORDER);
+    for i in 0..1_000_000u64 {
+        tree.insert(&backend, &i, i).unwrap();
+    }
+    assert_eq!(tree.len(), 1_000_000);
+    for i in (0..1_000_000u64).step_by(997) {
+        assert_eq!(tree.offset_for(&backend, &i).unwrap(), Some(i));
+    }
+    assert_eq!(tree.iter(&backend).count(), 1_000_000);
+}
+
+#[test]
+fn test_byte_budget() {
+    let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7).with_split_policy(SplitPolicy::Bytes(1024));
+    let mut oracle = std::collections::BTreeMap::new();
+    for i in 0..2000u64 {
+        let len = rng.gen_range(1, 200);
+        let k = format!("{:05}{}", i, "x".repeat(len));
+        tree.insert(&backend, &k, i).unwrap();
+        oracle.insert(k, i);
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    // Nothing but nodes and the header went to the backend.
+    assert!(backend.stored_bytes()/backend.len() <= 1024);
+    let tree = OffsetTree::<String>::reopen(&backend).unwrap().unwrap();
+    assert_eq!(tree.split_policy(), SplitPolicy::Bytes(1024));
+    for (k, v) in oracle.iter() {
+        assert_eq!(tree.offset_for(&backend, k).unwrap(), Some(*v));
+    }
+    let got = tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(got, oracle.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_front_coded_keys() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(64);
+    let keys = (0..1000u64).map(|i| format!("/some/rather/long/directory/of/files/file{:04}", i)).collect::<Vec<_>>();
+    for (i, k) in keys.iter().enumerate() {
+        tree.insert(&backend, k, i as u64).unwrap();
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    // Stored plainly, the keys alone would take half again as much as everything does.
+    let plain = keys.iter().map(|k| k.len()+8).sum::<usize>();
+    assert!(backend.stored_bytes() < plain*2/3);
+    let tree = OffsetTree::<String>::from_header(&backend, 64).unwrap().unwrap();
+    let got = tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(got, keys.into_iter().zip(0..).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_min_order() {
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(MIN_ORDER);
+    for i in 0..1000u64 {
+        tree.insert(&backend, &i, i).unwrap();
+    }
+    assert_eq!(tree.len(), 1000);
+}
+
+#[test]
+#[should_panic(expected = "The order of a tree must be at least 7, not 6.")]
+fn test_order_too_small() {
+    OffsetTree::<u64>::empty(MIN_ORDER-1);
+}
+
+#[test]
+#[should_panic(expected = "The order of a tree must be at least 7, not 2.")]
+fn test_reopen_order_too_small() {
+    OffsetTree::<u64>::from_root_offset(0, 2, 0);
+}
+
+#[test]
+fn test_build_sorted() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::build_sorted((0..100_000u64).map(|i| (i*2, i)), 16).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 16).unwrap().unwrap();
+    assert_eq!(tree.len(), 100_000);
+    for i in (0..100_000u64).step_by(101) {
+        assert_eq!(tree.offset_for(&backend, &(i*2)).unwrap(), Some(i));
+        assert_eq!(tree.offset_for(&backend, &(i*2+1)).unwrap(), None);
+    }
+    assert_eq!(tree.iter(&backend).map(|r| r.unwrap().0).collect::<Vec<_>>(), (0..100_000u64).map(|i| i*2).collect::<Vec<_>>());
+    tree.insert(&backend, &7, 7).unwrap();
+    assert_eq!(tree.ceiling(&backend, &5).unwrap(), Some((6, 3)));
+    assert_eq!(tree.ceiling(&backend, &7).unwrap(), Some((7, 7)));
+    assert!(OffsetTree::<u64>::build_sorted(vec![], 16).unwrap().is_empty());
+    assert_eq!(OffsetTree::build_sorted(vec![(1u32, 0), (3, 0), (2, 0)], 16).err(), Some(UnsortedError { index: 2 }));
+    assert_eq!(OffsetTree::build_sorted(vec![(1u32, 0), (1, 0)], 16).err(), Some(UnsortedError { index: 1 }));
+}
+
+#[test]
+fn test_extend() {
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(16);
+    assert_eq!(tree.extend(&backend, (0..100u64).map(|i| (i, i))).unwrap(), 100);
+    assert_eq!(tree.extend(&backend, (50..150u64).map(|i| (i, i+1))).unwrap(), 50);
+    assert_eq!(tree.len(), 150);
+    assert_eq!(tree.offset_for(&backend, &49).unwrap(), Some(49));
+    assert_eq!(tree.offset_for(&backend, &50).unwrap(), Some(51));
+    assert_eq!(tree.extend(&backend, vec![]).unwrap(), 0);
+}
+
+#[test]
+fn test_to_sorted_vec() {
+    let mut backend = MemoryBackend::new();
+    let mut rng = XorShiftRng::from_seed([4, 3, 2, 1]);
+    let mut tree = OffsetTree::empty(7);
+    let mut expected = std::collections::BTreeMap::new();
+    for _ in 0..5000 {
+        let k = rng.gen_range(0u32, 2000);
+        tree.insert(&backend, &k, k as u64).unwrap();
+        expected.insert(k, k as u64);
+    }
+    tree.commit(&mut backend).unwrap();
+    let pairs = tree.to_sorted_vec(&backend).unwrap();
+    assert_eq!(pairs, expected.into_iter().collect::<Vec<_>>());
+    let rebuilt = OffsetTree::build_sorted(pairs, 7).unwrap();
+    assert_eq!(rebuilt.len(), tree.len());
+    assert!(OffsetTree::<u32>::empty(7).to_sorted_vec(&backend).unwrap().is_empty());
+}
+
+#[test]
+fn test_compact() {
+    let mut backend = MemoryBackend::new();
+    let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+    let mut tree = OffsetTree::empty(7);
+    for round in 0..20 {
+        for _ in 0..500 {
+            let k = rng.gen_range(0u32, 3000);
+            if rng.gen() {
+                tree.insert(&backend, &k, round).unwrap();
+            }
+            else {
+                tree.remove(&backend, &k).unwrap();
+            }
+        }
+        tree.commit(&mut backend).unwrap();
+    }
+    // Leave something uncommitted, which should be compacted too.
+    tree.insert(&backend, &5000, 5000).unwrap();
+    let expected = tree.to_sorted_vec(&backend).unwrap();
+    let stats = tree.stats(&backend).unwrap();
+    let mut target = MemoryBackend::new();
+    tree.compact(&backend, &mut target).unwrap();
+    assert_eq!(target.len() as u64, stats.internal_nodes+stats.leaves);
+    assert!(target.stored_bytes() < backend.stored_bytes());
+    drop(backend);
+    tree.validate(&target).unwrap();
+    assert_eq!(tree.to_sorted_vec(&target).unwrap(), expected);
+    // Nothing changed since, so the header just points at the compacted root.
+    tree.commit_with_header(&mut target).unwrap();
+    assert_eq!(target.len() as u64, stats.internal_nodes+stats.leaves);
+    let tree = OffsetTree::<u32>::from_header(&target, 7).unwrap().unwrap();
+    assert_eq!(tree.offset_for(&target, &5000).unwrap(), Some(5000));
+}
+
+#[test]
+fn test_split_off() {
+    let mut rng = XorShiftRng::from_seed([9, 8, 7, 6]);
+    for &order in &[7, 9, 16] {
+        for _ in 0..10 {
+            let mut backend = MemoryBackend::new();
+            let mut tree = OffsetTree::empty(order);
+            tree.enable_free_list();
+            let mut oracle = std::collections::BTreeMap::new();
+            for _ in 0..rng.gen_range(0, 3000) {
+                let k = rng.gen_range(0u32, 5000);
+                let v = rng.gen();
+                tree.insert(&backend, &k, v).unwrap();
+                oracle.insert(k, v);
+            }
+            tree.commit_with_header(&mut backend).unwrap();
+            // Split a tree which is mostly still on disk.
+            let mut tree = OffsetTree::<u32>::from_header(&backend, order).unwrap().unwrap();
+            let at = rng.gen_range(0u32, 5100);
+            let mut other = tree.split_off(&backend, &at).unwrap();
+            let mut other_oracle = oracle.split_off(&at);
+            assert_matches_oracle(&mut tree, &backend, &oracle);
+            assert_matches_oracle(&mut other, &backend, &other_oracle);
+            let other_root = other.commit(&mut backend).unwrap();
+            tree.commit_with_header(&mut backend).unwrap();
+            // Whatever this tree freed isn't still in use by the other one.
+            for i in 0..500u32 {
+                tree.insert(&backend, &i, i as u64).unwrap();
+                oracle.insert(i, i as u64);
+            }
+            tree.commit_with_header(&mut backend).unwrap();
+            let mut other = OffsetTree::<u32>::from_root_offset(other_root, order, other_oracle.len() as u64);
+            assert_matches_oracle(&mut other, &backend, &other_oracle);
+            assert_matches_oracle(&mut tree, &backend, &oracle);
+            other.insert(&backend, &at, 0).unwrap();
+            other_oracle.insert(at, 0);
+            assert_matches_oracle(&mut other, &backend, &other_oracle);
+        }
+    }
+}
+
+#[test]
+fn test_append() {
+    let mut rng = XorShiftRng::from_seed([6, 7, 8, 9]);
+    let mut backend = MemoryBackend::new();
+    let mut oracle = std::collections::BTreeMap::new();
+    // Partitions of very different sizes, stored, then appended as trees mostly still on disk.
+    let mut roots = vec![];
+    let mut start = 0u32;
+    for &size in &[3000, 5, 200, 0, 4000, 1] {
+        let mut part = OffsetTree::empty(9);
+        for k in start..start+size {
+            let v = rng.gen();
+            part.insert(&backend, &k, v).unwrap();
+            oracle.insert(k, v);
+        }
+        roots.push((part.commit(&mut backend).unwrap(), size as u64));
+        start += size+rng.gen_range(1, 10);
+    }
+    let mut tree = OffsetTree::empty(9);
+    tree.enable_free_list();
+    for (root, len) in roots {
+        tree.append(&backend, OffsetTree::from_root_offset(root, 9, len)).unwrap();
+    }
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u32>::from_header(&backend, 9).unwrap().unwrap();
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+    // Byte-budgeted trees.
+    let mut tree = OffsetTree::empty(7).with_split_policy(SplitPolicy::Bytes(200));
+    let mut other = OffsetTree::empty(7).with_split_policy(SplitPolicy::Bytes(200));
+    for i in 0..1000u32 {
+        tree.insert(&backend, &i, i as u64).unwrap();
+        if i < 30 {
+            other.insert(&backend, &(i+1000), i as u64).unwrap();
+        }
+    }
+    tree.append(&backend, other).unwrap();
+    tree.validate(&backend).unwrap();
+    assert_eq!(tree.iter(&backend).map(|r| r.unwrap().0).collect::<Vec<_>>(), (0..1030).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_cursor() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.extend(&backend, (0..1000u64).map(|i| (i*2, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    // Some of the tree in memory and some not.
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    tree.insert(&backend, &1001, 0).unwrap();
+    tree.remove(&backend, &1001).unwrap();
+    let mut cursor = tree.cursor_at(&backend, &501).unwrap();
+    assert_eq!(cursor.key(), Some(&502));
+    assert_eq!(cursor.offset(), Some(251));
+    // Other reads don't have to wait for the cursor.
+    assert_eq!(tree.first(&backend).unwrap(), Some((0, 0)));
+    for i in 252..1000 {
+        assert!(cursor.next(&backend).unwrap());
+        assert_eq!(cursor.offset(), Some(i));
+    }
+    assert!(!cursor.next(&backend).unwrap());
+    assert_eq!(cursor.key(), None);
+    // Off the end wraps around.
+    assert!(cursor.prev(&backend).unwrap());
+    assert_eq!(cursor.key(), Some(&1998));
+    for i in (0..999).rev() {
+        assert!(cursor.prev(&backend).unwrap());
+        assert_eq!(cursor.key(), Some(&(i*2)));
+    }
+    assert!(!cursor.prev(&backend).unwrap());
+    assert!(cursor.next(&backend).unwrap());
+    assert_eq!(cursor.key(), Some(&0));
+    assert_eq!(tree.cursor_at(&backend, &1000).unwrap().key(), Some(&1000));
+    assert_eq!(tree.cursor_at(&backend, &1999).unwrap().key(), None);
+    let empty = OffsetTree::<u64>::empty(7);
+    let mut cursor = empty.cursor_at(&backend, &0).unwrap();
+    assert_eq!(cursor.key(), None);
+    assert!(!cursor.next(&backend).unwrap());
+    assert!(!cursor.prev(&backend).unwrap());
+}
+
+#[test]
+fn test_cursor_mut() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.extend(&backend, (0..1000u64).map(|i| (i*2, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    {
+        // Sweep a range, adjusting offsets.
+        let mut cursor = tree.cursor_mut_at(&backend, &199).unwrap();
+        while cursor.key().map(|k| *k < 1200).unwrap_or(false) {
+            let offset = cursor.offset().unwrap();
+            assert_eq!(cursor.set_offset(offset+10_000), Some(offset));
+            cursor.next(&backend).unwrap();
+        }
+        assert_eq!(cursor.key(), Some(&1200));
+        assert!(cursor.prev(&backend).unwrap());
+        assert_eq!(cursor.offset(), Some(599+10_000));
+    }
+    tree.validate(&backend).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    for i in 0..1000u64 {
+        let expected = if (100..600).contains(&i) { i+10_000 } else { i };
+        assert_eq!(tree.offset_for(&backend, &(i*2)).unwrap(), Some(expected));
+    }
+    // Off the end, there's nothing to set.
+    let mut cursor = tree.cursor_mut_at(&backend, &5000).unwrap();
+    assert_eq!(cursor.key(), None);
+    assert_eq!(cursor.set_offset(7), None);
+    assert!(cursor.prev(&backend).unwrap());
+    assert_eq!(cursor.key(), Some(&1998));
+    assert!(!cursor.next(&backend).unwrap());
+    assert!(cursor.next(&backend).unwrap());
+    assert_eq!(cursor.key(), Some(&0));
+    drop(cursor);
+    assert_eq!(tree.len(), 1000);
+    tree.validate(&backend).unwrap();
+    // Setting an offset to what it already is leaves the next commit nothing to write.
+    tree.commit_with_header(&mut backend).unwrap();
+    let stored = backend.len();
+    assert_eq!(tree.cursor_mut_at(&backend, &300).unwrap().set_offset(10_150), Some(10_150));
+    tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(backend.len(), stored);
+}
+
+#[test]
+fn test_entry() {
+    use append_tree::offset_tree::Entry;
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.extend(&backend, (0..1000u64).map(|i| (i*2, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    match tree.entry(&backend, &10).unwrap() {
+        Entry::Occupied(mut o) => {
+            assert_eq!((*o.key(), *o.get()), (10, 5));
+            assert_eq!(o.insert(500), 5);
+            assert_eq!(*o.get(), 500);
+        },
+        Entry::Vacant(_) => panic!("10 is in the tree."),
+    }
+    match tree.entry(&backend, &11).unwrap() {
+        Entry::Vacant(v) => {
+            assert_eq!(*v.key(), 11);
+            v.insert(11);
+        },
+        Entry::Occupied(_) => panic!("11 isn't in the tree."),
+    }
+    // Looking and not inserting changes nothing.
+    assert_eq!(*tree.entry(&backend, &13).unwrap().key(), 13);
+    assert_eq!(tree.len(), 1001);
+    assert_eq!(tree.offset_for(&backend, &13).unwrap(), None);
+    let mut oracle = (0..1000u64).map(|i| (i*2, i)).collect::<std::collections::BTreeMap<_, _>>();
+    oracle.insert(10, 500);
+    oracle.insert(11, 11);
+    // Counting occurrences, the usual use.
+    for i in 0..3000u64 {
+        let k = (i*37)%2500;
+        let next = oracle.get(&k).map(|v| v+1).unwrap_or(0);
+        oracle.insert(k, next);
+        tree.entry(&backend, &k).unwrap().and_modify(|v| *v += 1).or_insert(0);
+    }
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+    // Replacing a value with an equal one leaves the next commit nothing to write.
+    let stored = backend.len();
+    match tree.entry(&backend, &10).unwrap() {
+        Entry::Occupied(mut o) => assert_eq!(o.insert(oracle[&10]), oracle[&10]),
+        Entry::Vacant(_) => panic!("10 is in the tree."),
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(backend.len(), stored);
+}
+
+#[test]
+fn test_stats() {
+    let mut backend = MemoryBackend::new();
+    let tree = OffsetTree::<u64>::empty(8);
+    assert_eq!(tree.stats(&backend).unwrap(), TreeStats { height: 1, internal_nodes: 0, leaves: 1, keys: 0, fill_factor: 0.0 });
+    // Nodes of order 8 hold 4 children, and bulk loading fills them.
+    let mut tree = OffsetTree::build_sorted((0..16u64).map(|i| (i, i)), 8).unwrap();
+    let full = TreeStats { height: 2, internal_nodes: 1, leaves: 4, keys: 16, fill_factor: 1.0 };
+    assert_eq!(tree.stats(&backend).unwrap(), full);
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 8).unwrap().unwrap();
+    assert_eq!(tree.stats(&backend).unwrap(), full);
+    tree.remove(&backend, &0).unwrap();
+    let stats = tree.stats(&backend).unwrap();
+    assert_eq!(stats.keys, 15);
+    assert!(stats.fill_factor < 1.0);
+}
+
+#[test]
+fn test_root_collapses() {
+    let mut rng = XorShiftRng::from_seed([85, 85, 85, 85]);
+    let backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    let mut keys = (0..500u64).collect::<Vec<_>>();
+    for k in keys.iter() {
+        tree.insert(&backend, k, *k).unwrap();
+    }
+    assert!(tree.stats(&backend).unwrap().height > 3);
+    rng.shuffle(&mut keys);
+    let mut last_height = tree.stats(&backend).unwrap().height;
+    for (i, k) in keys.iter().enumerate() {
+        tree.remove(&backend, k).unwrap();
+        let left = keys.len()-i-1;
+        let height = tree.stats(&backend).unwrap().height;
+        assert!(height <= last_height);
+        last_height = height;
+        // Every node but the root has at least 2 children, so a root with 2 internal children needs 8 keys under it, and one with 2 leaves needs 4.
+        if left < 8 {
+            assert!(height <= 2, "Height {} with {} keys", height, left);
+        }
+        if left < 4 {
+            assert_eq!(height, 1, "Height {} with {} keys", height, left);
+        }
+    }
+    tree.validate(&backend).unwrap();
+}
+
+#[test]
+fn test_for_each_node() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::build_sorted((0..1000u64).map(|i| (i, i)), 8).unwrap();
+    tree.commit(&mut backend).unwrap();
+    let written = backend.len();
+    let mut visited = vec![];
+    let mut leaf_keys = vec![];
+    tree.for_each_node(&backend, |node_type, keys, children| {
+        visited.push((node_type, children));
+        if node_type == NodeType::Leaf {
+            assert_eq!(keys.len(), children);
+            leaf_keys.extend_from_slice(keys);
+        }
+        else {
+            assert_eq!(keys.len()+1, children);
+        }
+    }).unwrap();
+    assert_eq!(visited[0].0, NodeType::Root);
+    let stats = tree.stats(&backend).unwrap();
+    assert_eq!(visited.iter().filter(|v| v.0 == NodeType::Leaf).count() as u64, stats.leaves);
+    assert_eq!(visited.len() as u64, stats.leaves+stats.internal_nodes);
+    // Left to right, so the leaves come in key order.
+    assert_eq!(leaf_keys, (0..1000).collect::<Vec<_>>());
+    // Visiting didn't change anything for the next commit to write.
+    tree.commit(&mut backend).unwrap();
+    assert_eq!(backend.len(), written);
+}
+
+#[test]
+fn test_node_view() {
+    let mut backend = CachedBackend::new(MemoryBackend::new(), 0);
+    let mut tree = OffsetTree::build_sorted((0..1000u64).map(|i| (i, i*3)), 8).unwrap();
+    let root = tree.commit(&mut backend).unwrap();
+    let height = tree.stats(&backend).unwrap().height;
+    let mut tree = OffsetTree::<u64>::from_root_offset(root, 8, 1000);
+    backend.reset_counters();
+    // Walking down one side only loads the nodes on the way.
+    let mut view: NodeView<u64, u64> = tree.root_view(&backend).unwrap();
+    assert_eq!(view.node_type(), NodeType::Root);
+    assert_eq!(view.offset(), Some(root));
+    let mut depth = 1;
+    while view.node_type() != NodeType::Leaf {
+        assert_eq!(view.children(), view.keys().len()+1);
+        assert!(view.values().is_empty());
+        let last = view.children()-1;
+        let offset = view.child_offset(last);
+        view = view.child(&backend, last).unwrap();
+        assert_eq!(view.offset(), offset);
+        depth += 1;
+    }
+    assert_eq!(depth, height);
+    assert_eq!(backend.misses(), height);
+    assert_eq!(view.children(), 0);
+    assert_eq!(*view.keys().last().unwrap(), 999);
+    assert_eq!(*view.values().last().unwrap(), 999*3);
+    // Nodes which have changed since they were stored aren't stored anywhere as they are.
+    tree.insert(&backend, &1000, 0).unwrap();
+    let view = tree.root_view(&backend).unwrap();
+    assert_eq!(view.offset(), None);
+    assert!(view.child_offset(0).is_some());
+    assert_eq!(view.child_offset(view.children()-1), None);
+}
+
+#[test]
+fn test_validate() {
+    let mut rng = XorShiftRng::from_seed([9, 8, 7, 6]);
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    for round in 0..20 {
+        for _ in 0..200 {
+            let k = rng.gen_range(0u32, 500);
+            if rng.gen() {
+                tree.insert(&backend, &k, k as u64).unwrap();
+            }
+            else {
+                tree.remove(&backend, &k).unwrap();
+            }
+        }
+        tree.validate(&backend).unwrap();
+        if round%5 == 0 {
+            tree.commit_with_header(&mut backend).unwrap();
+            tree = OffsetTree::from_header(&backend, 7).unwrap().unwrap();
+            tree.validate(&backend).unwrap();
+        }
+    }
+}
+
+// Check everything the tree can be asked against a map which should have the same contents.
+fn assert_matches_oracle<K, B>(tree: &mut OffsetTree<K>, backend: &B, oracle: &std::collections::BTreeMap<K, u64>)
+    where K: serde::Serialize+serde::de::DeserializeOwned+Ord+Clone+std::fmt::Debug, B: StorageBackend
+{
+    tree.validate(backend).unwrap();
+    assert_eq!(tree.len(), oracle.len() as u64);
+    for (k, v) in oracle.iter() {
+        assert_eq!(tree.offset_for(backend, k).unwrap(), Some(*v), "Wrong offset for {:?}", k);
+    }
+    let got = tree.iter(backend).collect::<Result<Vec<_>, _>>().unwrap();
+    assert!(got.iter().map(|(k, v)| (k, v)).eq(oracle.iter()), "Iteration doesn't match the oracle.");
+}
+
+#[test]
+fn test_against_oracle() {
+    let mut rng = XorShiftRng::from_seed([10, 20, 30, 40]);
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(9);
+    tree.enable_free_list();
+    let mut oracle = std::collections::BTreeMap::new();
+    for step in 0..20_000 {
+        let k = rng.gen_range(0u32, 2000);
+        match rng.gen_range(0, 10) {
+            0..=4 => {
+                let v = rng.gen();
+                assert_eq!(tree.insert(&backend, &k, v).unwrap(), oracle.insert(k, v));
+            },
+            5..=7 => assert_eq!(tree.remove(&backend, &k).unwrap(), oracle.remove(&k)),
+            _ => assert_eq!(tree.offset_for(&backend, &k).unwrap(), oracle.get(&k).cloned()),
+        }
+        if step%2000 == 0 {
+            assert_matches_oracle(&mut tree, &backend, &oracle);
+            tree.commit_with_header(&mut backend).unwrap();
+            tree = OffsetTree::from_header(&backend, 9).unwrap().unwrap();
+        }
+    }
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+}
+
+#[test]
+fn test_clear() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.clear(&backend).unwrap();
+    tree.enable_free_list();
+    tree.extend(&backend, (0..1000u64).map(|i| (i, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let stats = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap().stats(&backend).unwrap();
+    let nodes = (stats.internal_nodes+stats.leaves) as usize;
+    // Only some of the tree is in memory, and some of that has changed.
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    tree.insert(&backend, &500, 0).unwrap();
+    tree.clear(&backend).unwrap();
+    assert_eq!(tree.len(), 0);
+    assert_eq!(tree.offset_for(&backend, &500).unwrap(), None);
+    assert_eq!(tree.iter(&backend).count(), 0);
+    assert_eq!(tree.free_list().unwrap().pending().len(), nodes);
+    tree.commit_with_header(&mut backend).unwrap();
+    let size = backend.len();
+    tree.extend(&backend, (0..1000u64).map(|i| (i, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(backend.len(), size);
+    assert_eq!(tree.offset_for(&backend, &500).unwrap(), Some(500));
+}
+
+#[test]
+fn test_retain() {
+    let mut rng = XorShiftRng::from_seed([57, 57, 57, 57]);
+    for &policy in &[SplitPolicy::Order(7), SplitPolicy::Order(12), SplitPolicy::Bytes(300)] {
+        let mut backend = MemoryBackend::new();
+        let mut tree = OffsetTree::empty(7).with_split_policy(policy);
+        tree.enable_free_list();
+        let mut oracle = std::collections::BTreeMap::new();
+        for i in 0..2000u32 {
+            let o = rng.gen::<u64>()%1000;
+            tree.insert(&backend, &i, o).unwrap();
+            oracle.insert(i, o);
+        }
+        tree.commit_with_header(&mut backend).unwrap();
+        let mut tree = OffsetTree::<u32>::reopen(&backend).unwrap().unwrap();
+        tree.enable_free_list();
+        // Nothing to remove.
+        assert_eq!(tree.retain(&backend, |_, _| true).unwrap(), 0);
+        assert!(tree.free_list().unwrap().pending().is_empty());
+        // Expire a few, then most, then everything.
+        for &cutoff in &[10u64, 900, 1000] {
+            let before = oracle.len() as u64;
+            let mut seen = 0;
+            let removed = tree.retain(&backend, |_, &o| {
+                seen += 1;
+                o >= cutoff
+            }).unwrap();
+            assert_eq!(seen, before);
+            oracle.retain(|_, o| *o >= cutoff);
+            assert_eq!(removed, before-oracle.len() as u64);
+            assert_matches_oracle(&mut tree, &backend, &oracle);
+            tree.commit_with_header(&mut backend).unwrap();
+            let mut reopened = OffsetTree::<u32>::reopen(&backend).unwrap().unwrap();
+            assert_matches_oracle(&mut reopened, &backend, &oracle);
+        }
+        assert!(tree.is_empty());
+        // The old nodes all went to the free list, so refilling the tree doesn't grow the backend.
+        let size = backend.len();
+        tree.extend(&backend, (0..200u32).map(|i| (i, i as u64))).unwrap();
+        tree.commit_with_header(&mut backend).unwrap();
+        assert!(backend.len() <= size);
+    }
+}
+
+#[test]
+fn test_drain_range() {
+    use std::ops::Bound::{self, Included, Excluded, Unbounded};
+    use std::ops::RangeBounds;
+    let mut rng = XorShiftRng::from_seed([58, 58, 58, 58]);
+    let bound = |rng: &mut XorShiftRng| match rng.gen_range(0, 5) {
+        0 => Unbounded,
+        1 | 2 => Included(rng.gen_range(0, 2200u32)),
+        _ => Excluded(rng.gen_range(0, 2200u32)),
+    };
+    for &policy in &[SplitPolicy::Order(7), SplitPolicy::Order(10), SplitPolicy::Bytes(300)] {
+        let mut backend = MemoryBackend::new();
+        let mut tree = OffsetTree::empty(7).with_split_policy(policy);
+        tree.enable_free_list();
+        let mut oracle = std::collections::BTreeMap::new();
+        for i in 0..2000u32 {
+            tree.insert(&backend, &i, i as u64).unwrap();
+            oracle.insert(i, i as u64);
+        }
+        tree.commit_with_header(&mut backend).unwrap();
+        for round in 0..40 {
+            let mut tree = OffsetTree::<u32>::reopen(&backend).unwrap().unwrap();
+            tree.enable_free_list();
+            let (lower, upper): (Bound<u32>, Bound<u32>) = (bound(&mut rng), bound(&mut rng));
+            let expected = oracle.iter().filter(|&(k, _)| (lower, upper).contains(k)).map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+            assert_eq!(tree.drain_range(&backend, (lower, upper)).unwrap(), expected, "{:?}..{:?}", lower, upper);
+            for (k, _) in expected.iter() {
+                oracle.remove(k);
+            }
+            assert_matches_oracle(&mut tree, &backend, &oracle);
+            // Only refill every so often, so that some drains hit ranges which are already partly empty.
+            if round%3 == 2 {
+                tree.extend(&backend, expected.iter().cloned()).unwrap();
+                oracle.extend(expected);
+            }
+            let size = backend.len();
+            tree.commit_with_header(&mut backend).unwrap();
+            if round%3 != 2 {
+                // Whatever the drain rewrote takes the space it freed, more or less.
+                assert!(backend.len() <= size+2*tree.stats(&backend).unwrap().height as usize);
+            }
+        }
+        // Everything at once.
+        let mut tree = OffsetTree::<u32>::reopen(&backend).unwrap().unwrap();
+        assert_eq!(tree.drain_range(&backend, ..).unwrap().len(), oracle.len());
+        oracle.clear();
+        assert_matches_oracle(&mut tree, &backend, &oracle);
+        assert!(tree.drain_range(&backend, ..).unwrap().is_empty());
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.extend(&backend, (0..100u64).map(|i| (i, i))).unwrap();
+    assert!(tree.snapshot().is_none());
+    tree.commit(&mut backend).unwrap();
+    let mut snapshot = tree.snapshot().unwrap();
+    tree.insert(&backend, &1000, 1000).unwrap();
+    tree.remove(&backend, &50).unwrap();
+    tree.insert(&backend, &0, 7).unwrap();
+    let check = |snapshot: &mut OffsetTree<u64>, backend: &MemoryBackend| {
+        assert_eq!(snapshot.len(), 100);
+        assert_eq!(snapshot.iter(backend).map(|x| x.unwrap()).collect::<Vec<_>>(), (0..100u64).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(snapshot.offset_for(backend, &1000).unwrap(), None);
+    };
+    check(&mut snapshot, &backend);
+    tree.commit(&mut backend).unwrap();
+    check(&mut snapshot, &backend);
+    // Snapshots of a reopened tree start from what was opened.
+    let tree = OffsetTree::<u64>::from_root_offset(tree.commit(&mut backend).unwrap(), 7, tree.len());
+    assert_eq!(tree.snapshot().unwrap().offset_for(&backend, &0).unwrap(), Some(7));
+}
+
+#[test]
+fn test_metrics() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    assert!(tree.metrics().is_none());
+    tree.extend(&backend, (0..3000u64).map(|i| (i, i))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let stats = tree.stats(&backend).unwrap();
+    // Opening loads the root without counting it.
+    let mut tree = OffsetTree::<u64>::reopen(&backend).unwrap().unwrap();
+    tree.enable_metrics();
+    assert_eq!(tree.offset_for(&backend, &1234).unwrap(), Some(1234));
+    {
+        let m = tree.metrics().unwrap();
+        assert_eq!((m.loaded(), m.resident(), m.written()), (stats.height-1, 1, 0));
+        m.reset();
+    }
+    // The second time, the whole path is in memory.
+    assert_eq!(tree.offset_for(&backend, &1234).unwrap(), Some(1234));
+    assert_eq!((tree.metrics().unwrap().loaded(), tree.metrics().unwrap().resident()), (0, stats.height));
+    tree.metrics().unwrap().reset();
+    // Changing a key rewrites its path.
+    tree.insert(&backend, &1234, 1).unwrap();
+    tree.commit(&mut backend).unwrap();
+    assert_eq!(tree.metrics().unwrap().written(), stats.height);
+    // Iterators count the nodes they load for themselves.
+    let mut cold = OffsetTree::<u64>::reopen(&backend).unwrap().unwrap();
+    cold.enable_metrics();
+    assert_eq!(cold.iter(&backend).count(), 3000);
+    assert_eq!(cold.metrics().unwrap().loaded(), stats.internal_nodes+stats.leaves-1);
+    assert_eq!(cold.metrics().unwrap().resident(), 1);
+    // Forks count in the same place.
+    let fork = cold.fork();
+    fork.offset_for(&backend, &0).unwrap();
+    assert_eq!(cold.metrics().unwrap().loaded(), stats.internal_nodes+stats.leaves-1+stats.height-1);
+    let mut cursor = fork.cursor_at(&backend, &0).unwrap();
+    cursor.next(&backend).unwrap();
+    assert!(cold.metrics().unwrap().resident() > 1);
+}
+
+#[test]
+fn test_to_dot() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    for i in 0..500u64 {
+        tree.insert(&backend, &format!("key \"{}\"", i), i).unwrap();
+    }
+    let header = tree.commit_with_header(&mut backend).unwrap();
+    let stats = tree.stats(&backend).unwrap();
+    // Nothing is loaded, so the graph has to load it all.
+    let cold = OffsetTree::<String>::from_root_offset(header.root, 7, header.len);
+    let mut out = vec![];
+    cold.to_dot(&backend, &mut out).unwrap();
+    let dot = String::from_utf8(out).unwrap();
+    assert!(dot.starts_with("digraph BPTree {\n"));
+    assert!(dot.ends_with("}\n"));
+    let nodes = stats.internal_nodes+stats.leaves;
+    assert_eq!(dot.matches("[label=").count() as u64, nodes);
+    assert_eq!(dot.matches(" -> ").count() as u64, nodes-1);
+    assert!(dot.contains(&format!("n0 [label=\"Root at {}\\n", header.root)));
+    // Quotes in keys can't end the label.
+    assert!(dot.contains(r#"[\"key \\\"0\\\"\", "#), "{}", dot);
+    // Leaves come out left to right.
+    let first = dot.find(r#"key \\\"0\\"#).unwrap();
+    let last = dot.find(r#"key \\\"99\\"#).unwrap();
+    assert!(first < last);
+}
+
+#[test]
+fn test_debug() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    // A new root has never been stored, so it counts as modified.
+    assert_eq!(format!("{:?}", tree), "BPTree { policy: Order(7), len: 0, root_loaded: true, root_offset: None, modified: true }");
+    for i in 0..100u64 {
+        tree.insert(&backend, &i, i).unwrap();
+    }
+    assert_eq!(format!("{:?}", tree), "BPTree { policy: Order(7), len: 100, root_loaded: true, root_offset: None, modified: true }");
+    let header = tree.commit_with_header(&mut backend).unwrap();
+    assert_eq!(format!("{:?}", tree), format!("BPTree {{ policy: Order(7), len: 100, root_loaded: true, root_offset: Some({}), modified: false }}", header.root));
+    // A tree which hasn't loaded anything yet has nothing to show but its offset.
+    let cold = OffsetTree::<u64>::from_root_offset(header.root, 7, 100);
+    assert_eq!(format!("{:?}", cold), format!("BPTree {{ policy: Order(7), len: 100, root_loaded: false, root_offset: Some({}), modified: false }}", header.root));
+}
+
+#[test]
+fn test_remove_by_offset() {
+    let mut rng = XorShiftRng::from_seed([71, 71, 71, 71]);
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    let mut oracle = std::collections::BTreeMap::new();
+    for _ in 0..3000 {
+        let k = rng.gen::<u32>();
+        let o = rng.gen_range(0u64, 100000);
+        tree.insert(&backend, &k, o).unwrap();
+        oracle.insert(k, o);
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u32>::reopen(&backend).unwrap().unwrap();
+    assert_eq!(tree.remove_by_offset(&backend, |o| o >= 100000).unwrap(), 0);
+    // Everything pointing below a reclaimed offset goes, whatever its key.
+    let removed = tree.remove_by_offset(&backend, |o| o < 30000).unwrap();
+    let before = oracle.len();
+    oracle.retain(|_, o| *o >= 30000);
+    assert_eq!(removed, (before-oracle.len()) as u64);
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+    assert_eq!(tree.remove_by_offset(&backend, |_| true).unwrap(), oracle.len() as u64);
+    assert!(tree.is_empty());
+    tree.validate(&backend).unwrap();
+}
+
+#[test]
+fn test_fork() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.extend(&backend, (0..1000u64).map(|i| (i, i))).unwrap();
+    tree.commit(&mut backend).unwrap();
+    // Some of this is committed and some isn't.
+    tree.extend(&backend, (1000..1500u64).map(|i| (i, i))).unwrap();
+    let mut fork = tree.fork();
+    let mut oracle = (0..1500u64).map(|i| (i, i)).collect::<std::collections::BTreeMap<_, _>>();
+    let mut fork_oracle = oracle.clone();
+    for i in 0..700u64 {
+        tree.remove(&backend, &(i*2)).unwrap();
+        oracle.remove(&(i*2));
+        tree.insert(&backend, &(i+5000), 0).unwrap();
+        oracle.insert(i+5000, 0);
+    }
+    assert_matches_oracle(&mut fork, &backend, &fork_oracle);
+    for i in 0..300u64 {
+        fork.insert(&backend, &(i*5), 77).unwrap();
+        fork_oracle.insert(i*5, 77);
+    }
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+    assert_matches_oracle(&mut fork, &backend, &fork_oracle);
+    let (root, fork_root) = (tree.commit(&mut backend).unwrap(), fork.commit(&mut backend).unwrap());
+    let mut tree = OffsetTree::<u64>::from_root_offset(root, 7, tree.len());
+    assert_matches_oracle(&mut tree, &backend, &oracle);
+    let mut fork = OffsetTree::<u64>::from_root_offset(fork_root, 7, fork.len());
+    assert_matches_oracle(&mut fork, &backend, &fork_oracle);
+}
+
+static LIVE_VALUES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// A value which counts how many of its kind exist.
+#[derive(Debug, PartialEq, Clone)]
+struct Counted(u64);
+
+impl Counted {
+    fn new(v: u64) -> Counted {
+        LIVE_VALUES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Counted(v)
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        LIVE_VALUES.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl serde::Serialize for Counted {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(s)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Counted {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Counted, D::Error> {
+        u64::deserialize(d).map(Counted::new)
+    }
+}
+
+#[test]
+fn test_into_iter() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.extend(&backend, (0..1000u64).map(|i| (i, i*3))).unwrap();
+    tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    // Part of the tree is in memory, and part of that is modified.
+    tree.insert(&backend, &400, 1).unwrap();
+    let got = tree.into_iter(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    let mut expected = (0..1000u64).map(|i| (i, i*3)).collect::<Vec<_>>();
+    expected[400].1 = 1;
+    assert_eq!(got, expected);
+
+    let mut tree = BPTree::empty(7);
+    for i in 0..1000u64 {
+        tree.insert(&backend, &i, Counted::new(i)).unwrap();
+    }
+    assert_eq!(LIVE_VALUES.load(std::sync::atomic::Ordering::SeqCst), 1000);
+    {
+        let mut iter = tree.into_iter(&backend);
+        let (k, v) = iter.nth(10).unwrap().unwrap();
+        assert_eq!((k, v.0), (10, 10));
+    }
+    assert_eq!(LIVE_VALUES.load(std::sync::atomic::Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_pin_root() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    for i in 0..1000u64 {
+        tree.insert(&backend, &i, i).unwrap();
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let height = tree.stats(&backend).unwrap().height as usize;
+    assert!(height > 3);
+    let mut tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    tree.pin_root(&backend, 2).unwrap();
+    tree.enable_metrics();
+    // The pinned levels are never loaded again, however far apart the lookups are.
+    for (i, k) in [0u64, 999, 500, 250].iter().enumerate() {
+        assert_eq!(tree.offset_for(&backend, k).unwrap(), Some(*k));
+        assert_eq!(tree.metrics().unwrap().loaded(), ((height-2)*(i+1)) as u64);
+    }
+    // Pinning the whole tree leaves nothing for the backend to do.
+    tree.pin_root(&backend, height).unwrap();
+    let empty = MemoryBackend::new();
+    assert_eq!(tree.iter(&empty).count(), 1000);
+    // Asking for more levels than there are is fine.
+    tree.pin_root(&backend, height+5).unwrap();
+}
+
+#[test]
+fn test_fold_range() {
+    use std::ops::Bound;
+    let mut rng = XorShiftRng::from_seed([86, 86, 86, 86]);
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    for _ in 0..3000 {
+        let k = rng.gen_range(0u32, 5000);
+        tree.insert(&backend, &k, rng.gen_range(0, 1000)).unwrap();
+    }
+    let header = tree.commit_with_header(&mut backend).unwrap();
+    let mut tree = OffsetTree::<u32>::from_header(&backend, 7).unwrap().unwrap();
+    let bound = |rng: &mut XorShiftRng| match rng.gen_range(0, 3) {
+        0 => Bound::Unbounded,
+        1 => Bound::Included(rng.gen_range(0u32, 5100)),
+        _ => Bound::Excluded(rng.gen_range(0u32, 5100)),
+    };
+    let sum = |acc: (u64, u64), _: &u32, v: &u64| (acc.0+1, acc.1+v);
+    for _ in 0..500 {
+        let bounds = (bound(&mut rng), bound(&mut rng));
+        if let (Bound::Excluded(l), Bound::Excluded(u)) = bounds {
+            if l == u {
+                continue;
+            }
+        }
+        let expected = tree.range(&backend, bounds).map(|r| r.unwrap()).fold((0, 0), |acc, (k, v)| sum(acc, &k, &v));
+        assert_eq!(tree.fold_range(&backend, bounds, (0, 0), sum).unwrap(), expected, "{:?}", bounds);
+    }
+    // The fold visits pairs in order, and doesn't keep the nodes it had to load.
+    let keys = tree.fold_range(&backend, .., vec![], |mut keys, k, _| {
+        keys.push(*k);
+        keys
+    }).unwrap();
+    assert_eq!(keys, tree.iter(&backend).map(|r| r.unwrap().0).collect::<Vec<_>>());
+    tree.enable_metrics();
+    tree.fold_range(&backend, .., (), |_, _, _| ()).unwrap();
+    let once = tree.metrics().unwrap().loaded();
+    assert!(once > 0);
+    tree.fold_range(&backend, .., (), |_, _, _| ()).unwrap();
+    assert_eq!(tree.metrics().unwrap().loaded(), once*2);
+    // Errors come back instead of the accumulator.
+    let garbage = backend.store(&"not a node".to_string()).unwrap();
+    let broken = OffsetTree::<u32>::from_root_offset(garbage, 7, header.len);
+    assert!(broken.fold_range(&backend, .., 0, |n, _, _| n+1).is_err());
+}
+
+#[test]
+fn test_count_range() {
+    use std::ops::Bound;
+    let mut rng = XorShiftRng::from_seed([11, 12, 13, 14]);
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    let mut oracle = std::collections::BTreeMap::new();
+    for _ in 0..3000 {
+        let k = rng.gen_range(0u32, 5000);
+        tree.insert(&backend, &k, 0).unwrap();
+        oracle.insert(k, 0);
+    }
+    for _ in 0..1000 {
+        let k = rng.gen_range(0u32, 5000);
+        tree.remove(&backend, &k).unwrap();
+        oracle.remove(&k);
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let tree = OffsetTree::<u32>::from_header(&backend, 7).unwrap().unwrap();
+    let bound = |rng: &mut XorShiftRng| match rng.gen_range(0, 3) {
+        0 => Bound::Unbounded,
+        1 => Bound::Included(rng.gen_range(0u32, 5100)),
+        _ => Bound::Excluded(rng.gen_range(0u32, 5100)),
+    };
+    for _ in 0..2000 {
+        let (lower, upper) = (bound(&mut rng), bound(&mut rng));
+        let empty = match (lower, upper) {
+            (Bound::Included(l), Bound::Included(u)) => l > u,
+            (Bound::Included(l), Bound::Excluded(u)) | (Bound::Excluded(l), Bound::Included(u)) => l >= u,
+            (Bound::Excluded(l), Bound::Excluded(u)) => l >= u,
+            _ => false,
+        };
+        let expected = if empty { 0 } else { oracle.range((lower, upper)).count() as u64 };
+        assert_eq!(tree.count_range(&backend, (lower, upper)).unwrap(), expected, "{:?} {:?}", lower, upper);
+    }
+    assert_eq!(tree.count_range(&backend, ..).unwrap(), oracle.len() as u64);
+}
+
+#[test]
+fn test_range_is_empty() {
+    use std::ops::Bound;
+    let mut rng = XorShiftRng::from_seed([98, 98, 98, 98]);
+    let mut backend = CachedBackend::new(MemoryBackend::new(), 0);
+    let mut tree = OffsetTree::empty(7);
+    assert!(tree.range_is_empty(&backend, ..).unwrap());
+    let mut oracle = std::collections::BTreeSet::new();
+    for _ in 0..2000 {
+        let k = rng.gen_range(0u32, 10000);
+        tree.insert(&backend, &k, 0).unwrap();
+        oracle.insert(k);
+    }
+    // Removing keys leaves separators behind which aren't keys anymore.
+    for _ in 0..1000 {
+        let k = rng.gen_range(0u32, 10000);
+        tree.remove(&backend, &k).unwrap();
+        oracle.remove(&k);
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let tree = OffsetTree::<u32>::from_header(&backend, 7).unwrap().unwrap();
+    let height = tree.stats(&backend).unwrap().height;
+    let tree = OffsetTree::<u32>::from_header(&backend, 7).unwrap().unwrap();
+    let bound = |rng: &mut XorShiftRng, k: u32| match rng.gen_range(0, 5) {
+        0 => Bound::Unbounded,
+        1 | 2 => Bound::Included(k),
+        _ => Bound::Excluded(k),
+    };
+    let mut empties = 0;
+    for _ in 0..3000 {
+        let start = rng.gen_range(0u32, 10100);
+        let end = start+rng.gen_range(0, 20);
+        let (lower, upper) = (bound(&mut rng, start), bound(&mut rng, end));
+        let backwards = match (lower, upper) {
+            (Bound::Included(l), Bound::Included(u)) => l > u,
+            (Bound::Included(l), Bound::Excluded(u)) | (Bound::Excluded(l), Bound::Included(u)) | (Bound::Excluded(l), Bound::Excluded(u)) => l >= u,
+            _ => false,
+        };
+        let expected = backwards || oracle.range((lower, upper)).next().is_none();
+        empties += expected as u32;
+        // Nothing is kept between the checks, so each one loads everything it looks at.
+        let tree = tree.snapshot().unwrap();
+        backend.reset_counters();
+        assert_eq!(tree.range_is_empty(&backend, (lower, upper)).unwrap(), expected, "{:?} {:?}", lower, upper);
+        // At most the path to the start of the range, and the left edge of the subtree after it.
+        assert!(backend.misses() < 2*height);
+    }
+    assert!(empties > 100 && empties < 2900);
+}
+
+#[test]
+fn test_rank_select() {
+    let mut backend = MemoryBackend::new();
+    let mut tree = OffsetTree::empty(7);
+    tree.extend(&backend, (0..2000u64).map(|i| (i*3, i))).unwrap();
+    for i in 0..200u64 {
+        tree.remove(&backend, &(i*30)).unwrap();
+    }
+    tree.commit_with_header(&mut backend).unwrap();
+    let tree = OffsetTree::<u64>::from_header(&backend, 7).unwrap().unwrap();
+    let keys = tree.iter(&backend).collect::<Result<Vec<_>, _>>().unwrap();
+    for (i, &(k, v)) in keys.iter().enumerate() {
+        assert_eq!(tree.select(&backend, i as u64).unwrap(), Some((k, v)));
+        assert_eq!(tree.rank(&backend, &k).unwrap(), i as u64);
+        assert_eq!(tree.rank(&backend, &(k+1)).unwrap(), i as u64+1);
+    }
+    assert_eq!(tree.select(&backend, 1800).unwrap(), None);
+    assert_eq!(tree.rank(&backend, &0).unwrap(), 0);
+    assert_eq!(tree.rank(&backend, &100_000).unwrap(), 1800);
+    assert_eq!(OffsetTree::<u64>::empty(7).select(&backend, 0).unwrap(), None);
+}
+
+// Spells out the signatures of the core of the API using only what the crate root exports, so that changing any of them is a compile error here.
+#[test]
+fn test_public_api() {
+    use append_tree as at;
+    type Tree = at::OffsetTree<String>;
+    type Found = Result<Option<u64>, at::DecodingError>;
+    let empty: fn(u64) -> Tree = Tree::empty;
+    let _: fn(u64, u64, u64) -> Tree = Tree::from_root_offset;
+    let _: fn(&at::MemoryBackend, u64, u64, u64) -> Result<Tree, at::DecodingError> = Tree::open::<at::MemoryBackend>;
+    let _: fn(&at::FileBackend, u64) -> Result<Option<Tree>, at::DecodingError> = Tree::from_header::<at::FileBackend>;
+    let reopen: fn(&at::MemoryBackend) -> Result<Option<Tree>, at::DecodingError> = Tree::reopen::<at::MemoryBackend>;
+    let _: fn(Tree, at::SplitPolicy) -> Tree = Tree::with_split_policy;
+    let len: fn(&Tree) -> u64 = Tree::len;
+    let insert: fn(&mut Tree, &at::MemoryBackend, &String, u64) -> Found = Tree::insert::<at::MemoryBackend>;
+    let offset_for: fn(&Tree, &at::MemoryBackend, &String) -> Found = Tree::offset_for::<at::MemoryBackend>;
+    let remove: fn(&mut Tree, &at::MemoryBackend, &String) -> Found = Tree::remove::<at::MemoryBackend>;
+    let _: fn(&mut Tree, &mut at::MemoryBackend) -> Result<u64, at::EncodingError> = Tree::commit::<at::MemoryBackend>;
+    let commit_with_header: fn(&mut Tree, &mut at::MemoryBackend) -> Result<at::Header, at::EncodingError> = Tree::commit_with_header::<at::MemoryBackend>;
+    let stats: fn(&Tree, &at::MemoryBackend) -> Result<at::TreeStats, at::DecodingError> = Tree::stats::<at::MemoryBackend>;
+    let mut backend = at::MemoryBackend::new();
+    let mut tree = empty(7);
+    assert_eq!(insert(&mut tree, &backend, &"a".to_string(), 1).unwrap(), None);
+    assert_eq!(insert(&mut tree, &backend, &"b".to_string(), 2).unwrap(), None);
+    assert_eq!(remove(&mut tree, &backend, &"a".to_string()).unwrap(), Some(1));
+    commit_with_header(&mut tree, &mut backend).unwrap();
+    let tree = reopen(&backend).unwrap().unwrap();
+    assert_eq!(len(&tree), 1);
+    assert_eq!(offset_for(&tree, &backend, &"b".to_string()).unwrap(), Some(2));
+    assert_eq!(stats(&tree, &backend).unwrap().height, 1);
+}
+
+// Every load waits once before it's ready, the way one on a real async backend would.
+#[cfg(feature = "async")]
+struct YieldingBackend {
+    inner: MemoryBackend,
+    loads: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(feature = "async")]
+impl append_tree::AsyncStorageBackend for YieldingBackend {
+    fn load_async<'a, V: serde::de::DeserializeOwned+Send+'a>(&'a self, key: u64) -> append_tree::LoadFuture<'a, V> {
+        self.loads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let mut waited = false;
+        Box::pin(std::future::poll_fn(move |cx| {
+            if !waited {
+                waited = true;
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            std::task::Poll::Ready(self.inner.load(key))
+        }))
+    }
+}
+
+// Run a future to completion, returning its output and how many times it wasn't ready.
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> (F::Output, usize) {
+    let mut future = Box::pin(future);
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    let mut waits = 0;
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(output) => return (output, waits),
+            std::task::Poll::Pending => waits += 1,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async() {
+    let mut rng = XorShiftRng::from_seed([66, 6, 66, 6]);
+    let mut backend = YieldingBackend { inner: MemoryBackend::new(), loads: std::sync::atomic::AtomicUsize::new(0) };
+    let mut oracle = std::collections::BTreeMap::new();
+    let mut tree = OffsetTree::empty(7);
+    for round in 0..4 {
+        for _ in 0..500 {
+            let k = rng.gen_range(0u64, 3000);
+            let v = rng.gen::<u64>();
+            let (previous, _) = block_on(tree.insert_async(&backend, &k, v));
+            assert_eq!(previous.unwrap(), oracle.insert(k, v), "Wrong previous value for {} in round {}", k, round);
+        }
+        tree.commit_with_header(&mut backend.inner).unwrap();
+        tree = OffsetTree::reopen(&backend.inner).unwrap().unwrap();
+        assert_matches_oracle(&mut OffsetTree::reopen(&backend.inner).unwrap().unwrap(), &backend.inner, &oracle);
+    }
+    // A tree with nothing loaded has to load, and so wait, once for each level on the way down.
+    let height = tree.stats(&backend.inner).unwrap().height;
+    let header = backend.inner.load_header::<Header>().unwrap().unwrap();
+    let tree = OffsetTree::<u64>::from_root_offset(header.root, 7, header.len);
+    backend.loads.store(0, std::sync::atomic::Ordering::SeqCst);
+    let k = *oracle.keys().next().unwrap();
+    let (found, waits) = block_on(tree.offset_for_async(&backend, &k));
+    assert_eq!(found.unwrap(), oracle.get(&k).cloned());
+    assert_eq!(waits as u64, height);
+    assert_eq!(backend.loads.load(std::sync::atomic::Ordering::SeqCst) as u64, height);
+    // Each level is counted once, however many times the descent was polled.
+    let mut tree = OffsetTree::<u64>::from_root_offset(header.root, 7, header.len);
+    tree.enable_metrics();
+    block_on(tree.offset_for_async(&backend, &k)).0.unwrap();
+    block_on(tree.offset_for_async(&backend, &k)).0.unwrap();
+    assert_eq!((tree.metrics().unwrap().loaded(), tree.metrics().unwrap().resident()), (height, height));
+    for k in 0..3000 {
+        assert_eq!(block_on(tree.offset_for_async(&backend, &k)).0.unwrap(), oracle.get(&k).cloned(), "Wrong offset for {}", k);
+    }
+    assert_matches_oracle(&mut OffsetTree::reopen(&backend.inner).unwrap().unwrap(), &backend.inner, &oracle);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_corrupt_node() {
+    use append_tree::AsyncStorageBackend;
+    let mut backend = YieldingBackend { inner: MemoryBackend::new(), loads: std::sync::atomic::AtomicUsize::new(0) };
+    let mut tree = OffsetTree::empty(7);
+    for k in 0..100u64 {
+        tree.insert(&backend.inner, &k, k).unwrap();
+    }
+    tree.commit(&mut backend.inner).unwrap();
+    let garbage = backend.inner.store(&"not a node".to_string()).unwrap();
+    let tree = OffsetTree::<u64>::from_root_offset(garbage, 7, 100);
+    assert!(block_on(tree.offset_for_async(&backend, &5)).0.is_err());
+    let loaded: Result<String, _> = block_on(backend.load_async(garbage)).0;
+    assert_eq!(loaded.unwrap(), "not a node");
+}